@@ -0,0 +1,198 @@
+#![allow(dead_code)]
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str;
+
+use errors::{CDMachError::*, Result};
+
+use codedir::CodeSignature;
+use rules::is_macho_magic;
+use verify::VerificationReport;
+
+/// Global header of an `ar` archive
+pub const AR_MAGIC: &[u8] = b"!<arch>\n";
+/// Size of a member header
+const AR_HEADER_SIZE: usize = 60;
+/// Terminator of a member header
+const AR_FMAG: &[u8] = b"`\n";
+/// BSD long names: `#1/<length>`, the name preceding the member's data
+const BSD_LONG_NAME_PREFIX: &str = "#1/";
+/// GNU long name table, referenced by `/<offset>` names
+const GNU_LONG_NAMES: &str = "//";
+
+/// One file of an `ar` archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveMember<'a> {
+    pub name: String,
+    /// offset of the member's data in the archive
+    pub offset: usize,
+    pub data: &'a [u8],
+}
+
+pub fn is_archive(data: &[u8]) -> bool {
+    data.starts_with(AR_MAGIC)
+}
+
+/// Whether a member is the archive's symbol table (`ranlib` or GNU style)
+pub fn is_symbol_table(name: &str) -> bool {
+    match name {
+        "/" | "/SYM64/" => true,
+        name => name.starts_with("__.SYMDEF"),
+    }
+}
+
+/// The members of an `ar` archive, with BSD (`#1/NN`) and GNU (`//` table)
+/// long names resolved; symbol tables and the name table are left out
+pub fn archive_members<'a>(data: &'a [u8]) -> Result<Vec<ArchiveMember<'a>>> {
+    if !is_archive(data) {
+        return Err(BadArchive("no archive magic".to_string()).into());
+    }
+    let mut members = vec![];
+    let mut long_names: &[u8] = &[];
+    let mut pos = AR_MAGIC.len();
+    while pos + AR_HEADER_SIZE <= data.len() {
+        let header = &data[pos..pos + AR_HEADER_SIZE];
+        if &header[58..] != AR_FMAG {
+            return Err(BadArchive(format!("bad member header at {}", pos)).into());
+        }
+        let name = header_field(&header[..16])?;
+        let size: usize = match header_field(&header[48..58])?.parse() {
+            Ok(size) => size,
+            Err(_) => return Err(BadArchive(format!("bad member size at {}", pos)).into()),
+        };
+        let mut offset = pos + AR_HEADER_SIZE;
+        let end = offset + size;
+        if end > data.len() {
+            return Err(BadArchive(format!("member at {} past the end", pos)).into());
+        }
+        // members start on even offsets
+        pos = end + (end & 1);
+
+        let name = if name.starts_with(BSD_LONG_NAME_PREFIX) {
+            let length: usize = match name[BSD_LONG_NAME_PREFIX.len()..].parse() {
+                Ok(length) if length <= size => length,
+                _ => return Err(BadArchive(format!("bad member name {}", name)).into()),
+            };
+            let raw = &data[offset..offset + length];
+            offset += length;
+            let raw = match raw.iter().position(|&b| b == 0) {
+                Some(nul) => &raw[..nul],
+                None => raw,
+            };
+            String::from_utf8_lossy(raw).to_string()
+        } else if name == GNU_LONG_NAMES {
+            long_names = &data[offset..end];
+            continue;
+        } else if name.len() > 1
+            && name.starts_with('/')
+            && name[1..].bytes().all(|b| b.is_ascii_digit())
+        {
+            let start: usize = name[1..].parse().unwrap_or(usize::max_value());
+            if start >= long_names.len() {
+                return Err(BadArchive(format!("bad long name reference {}", name)).into());
+            }
+            let raw = &long_names[start..];
+            let raw = match raw.iter().position(|&b| b == b'\n') {
+                Some(newline) => &raw[..newline],
+                None => raw,
+            };
+            String::from_utf8_lossy(raw)
+                .trim_end_matches('/')
+                .to_string()
+        } else if is_symbol_table(&name) {
+            continue;
+        } else {
+            // GNU terminates short names with a slash
+            name.trim_end_matches('/').to_string()
+        };
+        if is_symbol_table(&name) {
+            continue;
+        }
+        members.push(ArchiveMember {
+            name,
+            offset,
+            data: &data[offset..end],
+        });
+    }
+    Ok(members)
+}
+
+/// A space padded header field
+fn header_field(field: &[u8]) -> Result<String> {
+    Ok(str::from_utf8(field)?.trim_end_matches(' ').to_string())
+}
+
+/// Check the signature of every Mach-O member of the archive at `path`
+pub fn verify_archive(path: &Path) -> Result<Vec<VerificationReport>> {
+    let mut data = vec![];
+    File::open(path)?.read_to_end(&mut data)?;
+    verify_members(path, &data)
+}
+
+/// Check the signature of every Mach-O member of an archive (or archive
+/// slice) read from `path`; members are reported as `path(member)`
+pub fn verify_members(path: &Path, data: &[u8]) -> Result<Vec<VerificationReport>> {
+    let mut reports = vec![];
+    for member in archive_members(data)? {
+        if !is_macho_magic(member.data) {
+            continue;
+        }
+        let mut report = VerificationReport::new(&PathBuf::from(format!(
+            "{}({})",
+            path.display(),
+            member.name
+        )));
+        report.check_signatures(&CodeSignature::parse_image(None, member.data)?)?;
+        reports.push(report);
+    }
+    Ok(reports)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use archive::{archive_members, AR_MAGIC};
+
+    fn member(name: &str, data: &[u8]) -> Vec<u8> {
+        let mut member = format!(
+            "{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+            name,
+            0,
+            0,
+            0,
+            644,
+            data.len()
+        )
+        .into_bytes();
+        member.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            member.push(b'\n');
+        }
+        member
+    }
+
+    #[test]
+    fn test_member_names() {
+        let mut bsd = AR_MAGIC.to_vec();
+        bsd.extend(member("#1/20", b"__.SYMDEF SORTED\0\0\0\0"));
+        bsd.extend(member("#1/20", b"a_long_file_name.o\0\0abc"));
+        bsd.extend(member("short.o", b"xyz"));
+        let members = archive_members(&bsd).unwrap();
+        assert_eq!(members.len(), 2);
+        assert_eq!(members[0].name, "a_long_file_name.o");
+        assert_eq!(members[0].data, b"abc");
+        assert_eq!(members[1].name, "short.o");
+        assert_eq!(members[1].data, b"xyz");
+
+        let mut gnu = AR_MAGIC.to_vec();
+        gnu.extend(member("/", b"\0\0\0\0"));
+        gnu.extend(member("//", b"a_long_file_name.o/\nanother_long_name.o/\n"));
+        gnu.extend(member("/20", b"one"));
+        gnu.extend(member("short.o/", b"two"));
+        let members = archive_members(&gnu).unwrap();
+        let names: Vec<_> = members.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, vec!["another_long_name.o", "short.o"]);
+        assert_eq!(members[0].data, b"one");
+    }
+}
@@ -0,0 +1,199 @@
+#![allow(dead_code)]
+
+use plist::{Dictionary, Value};
+
+use std::fs::File;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+
+use errors::{CDMachError::*, Result};
+
+/// Info.plist of a versioned (macOS) framework
+const FRAMEWORK_INFO_PLIST: &str = "Versions/Current/Resources/Info.plist";
+/// `CFBundlePackageType` of kernel extensions
+pub const KEXT_PACKAGE_TYPE: &str = "KEXT";
+/// Prefix of the Info.plist keys only kernel extensions use
+const OS_BUNDLE_KEY_PREFIX: &str = "OSBundle";
+
+/// A bundle on disk (`Foo.app`, `Foo.framework`, ...) and its main executable.
+///
+/// The macOS layout (`Contents/Info.plist`, `Contents/MacOS/<exe>`), the
+/// versioned framework layout (`Versions/Current/Resources/Info.plist`,
+/// `Versions/Current/<exe>`) and the shallow iOS layout (`Info.plist` and the
+/// executable at the root) are understood.
+#[derive(Debug, Clone)]
+pub struct Bundle {
+    /// Root directory of the bundle
+    pub path: PathBuf,
+    /// Directory holding Info.plist and the bundle's resources
+    pub contents: PathBuf,
+    /// Raw Info.plist, as sealed by special slot 1
+    pub info_plist: Vec<u8>,
+    /// Parsed Info.plist (XML or binary)
+    pub info: Dictionary,
+    /// Path to the main executable, from `CFBundleExecutable`
+    pub executable: PathBuf,
+}
+
+impl Bundle {
+    /// Whether `path` looks like a bundle directory
+    pub fn is_bundle<P: AsRef<Path>>(path: P) -> bool {
+        let path = path.as_ref();
+        path.join("Contents/Info.plist").is_file()
+            || path.join(FRAMEWORK_INFO_PLIST).is_file()
+            || path.join("Info.plist").is_file()
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Bundle> {
+        let path = path.as_ref().to_path_buf();
+        let (contents, info_path) = if path.join("Contents/Info.plist").is_file() {
+            (path.join("Contents"), path.join("Contents/Info.plist"))
+        } else if path.join(FRAMEWORK_INFO_PLIST).is_file() {
+            (
+                path.join("Versions/Current"),
+                path.join(FRAMEWORK_INFO_PLIST),
+            )
+        } else if path.join("Info.plist").is_file() {
+            (path.clone(), path.join("Info.plist"))
+        } else {
+            return Err(NotABundle(path.display().to_string()).into());
+        };
+
+        let mut info_plist = vec![];
+        File::open(info_path)?.read_to_end(&mut info_plist)?;
+        let info = parse_dictionary(&info_plist)?;
+
+        let name = match info.get("CFBundleExecutable").and_then(Value::as_string) {
+            Some(name) => name.to_string(),
+            None => return Err(MissingInfoKey("CFBundleExecutable".to_string()).into()),
+        };
+        let executable = if contents == path || contents.ends_with("Versions/Current") {
+            contents.join(&name)
+        } else {
+            contents.join("MacOS").join(&name)
+        };
+
+        Ok(Bundle {
+            path,
+            contents,
+            info_plist,
+            info,
+            executable,
+        })
+    }
+
+    /// `CFBundleIdentifier`, if present
+    pub fn identifier(&self) -> Option<&str> {
+        self.info
+            .get("CFBundleIdentifier")
+            .and_then(Value::as_string)
+    }
+
+    /// Whether this is a kernel extension: a `.kext`, a `KEXT` package type
+    /// or `OSBundle*` Info.plist keys
+    pub fn is_kext(&self) -> bool {
+        self.path.extension().map_or(false, |ext| ext == "kext")
+            || self
+                .info
+                .get("CFBundlePackageType")
+                .and_then(Value::as_string)
+                == Some(KEXT_PACKAGE_TYPE)
+            || self
+                .info
+                .keys()
+                .any(|key| key.starts_with(OS_BUNDLE_KEY_PREFIX))
+    }
+
+    /// Whether this is a shallow (iOS style) bundle, without `Contents`
+    pub fn is_shallow(&self) -> bool {
+        self.contents == self.path
+    }
+
+    /// Path of the main executable relative to the bundle contents
+    pub fn relative_executable(&self) -> Option<String> {
+        self.executable
+            .strip_prefix(&self.contents)
+            .ok()
+            .map(|p| p.to_string_lossy().replace('\\', "/"))
+    }
+
+    /// Read the main executable
+    pub fn read_executable(&self) -> Result<Vec<u8>> {
+        let mut buf = vec![];
+        File::open(&self.executable)?.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// Parse an XML or binary property list whose root is a dictionary
+pub fn parse_dictionary(data: &[u8]) -> Result<Dictionary> {
+    match Value::from_reader(Cursor::new(data))? {
+        Value::Dictionary(dict) => Ok(dict),
+        _ => Err(UnexpectedPlist("root is not a dictionary".to_string()).into()),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use bundle::Bundle;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    /// An XML Info.plist naming the executable and identifier
+    pub fn info_plist(executable: &str, identifier: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0"><dict>
+<key>CFBundleExecutable</key><string>{}</string>
+<key>CFBundleIdentifier</key><string>{}</string>
+</dict></plist>"#,
+            executable, identifier
+        )
+    }
+
+    #[test]
+    fn test_open() {
+        let dir = TempDir::new().unwrap();
+
+        let app = dir.path().join("Foo.app");
+        fs::create_dir_all(app.join("Contents/MacOS")).unwrap();
+        fs::write(
+            app.join("Contents/Info.plist"),
+            info_plist("Foo", "com.example.foo"),
+        )
+        .unwrap();
+        let bundle = Bundle::open(&app).unwrap();
+        assert_eq!(bundle.contents, app.join("Contents"));
+        assert_eq!(bundle.executable, app.join("Contents/MacOS/Foo"));
+        assert_eq!(bundle.identifier(), Some("com.example.foo"));
+        assert!(!bundle.is_shallow());
+
+        // Versions/Current is a symlink to the current version
+        let framework = dir.path().join("Bar.framework");
+        fs::create_dir_all(framework.join("Versions/A/Resources")).unwrap();
+        symlink("A", framework.join("Versions/Current")).unwrap();
+        fs::write(
+            framework.join("Versions/A/Resources/Info.plist"),
+            info_plist("Bar", "com.example.bar"),
+        )
+        .unwrap();
+        let bundle = Bundle::open(&framework).unwrap();
+        assert_eq!(bundle.contents, framework.join("Versions/Current"));
+        assert_eq!(bundle.executable, framework.join("Versions/Current/Bar"));
+        assert_eq!(bundle.relative_executable(), Some("Bar".to_string()));
+        assert!(!bundle.is_shallow());
+
+        let ios = dir.path().join("Baz.app");
+        fs::create_dir_all(&ios).unwrap();
+        fs::write(ios.join("Info.plist"), info_plist("Baz", "com.example.baz")).unwrap();
+        let bundle = Bundle::open(&ios).unwrap();
+        assert_eq!(bundle.contents, ios);
+        assert_eq!(bundle.executable, ios.join("Baz"));
+        assert!(bundle.is_shallow());
+        assert!(!bundle.is_kext());
+
+        assert!(!Bundle::is_bundle(dir.path()));
+        assert!(Bundle::open(dir.path()).is_err());
+    }
+}
@@ -0,0 +1,572 @@
+#![allow(dead_code)]
+
+use ring::digest::{self, digest, SHA256};
+use ring::rand::SystemRandom;
+use ring::signature::{
+    self, RSAKeyPair, RSAParameters, RSASigningState, RSA_PKCS1_2048_8192_SHA1,
+    RSA_PKCS1_2048_8192_SHA256, RSA_PKCS1_2048_8192_SHA384, RSA_PKCS1_2048_8192_SHA512,
+    RSA_PKCS1_SHA256,
+};
+use untrusted::Input;
+
+use std::fmt;
+use std::sync::Arc;
+
+use errors::{CDMachError::*, Result};
+
+use der::{
+    encode, encode_algorithm, encode_constructed, encode_set, Tlv, CONTEXT_0, CONTEXT_3,
+    OID_COMMON_NAME, OID_CONTENT_TYPE, OID_DATA, OID_MESSAGE_DIGEST, OID_ORGANIZATIONAL_UNIT,
+    OID_RSA_ENCRYPTION, OID_SHA1, OID_SHA1_WITH_RSA, OID_SHA256, OID_SHA256_WITH_RSA, OID_SHA384,
+    OID_SHA384_WITH_RSA, OID_SHA512, OID_SHA512_WITH_RSA, OID_SIGNED_DATA, TAG_BIT_STRING,
+    TAG_INTEGER, TAG_OCTET_STRING, TAG_OID, TAG_SEQUENCE, TAG_SET,
+};
+
+/// The PKCS#1 v1.5 RSA verification algorithm of a signature algorithm OID
+pub fn rsa_algorithm(oid: &[u8]) -> Option<&'static RSAParameters> {
+    match oid {
+        OID_SHA1_WITH_RSA => Some(&RSA_PKCS1_2048_8192_SHA1),
+        OID_SHA256_WITH_RSA => Some(&RSA_PKCS1_2048_8192_SHA256),
+        OID_SHA384_WITH_RSA => Some(&RSA_PKCS1_2048_8192_SHA384),
+        OID_SHA512_WITH_RSA => Some(&RSA_PKCS1_2048_8192_SHA512),
+        _ => None,
+    }
+}
+
+/// The digest and PKCS#1 v1.5 RSA verification algorithms of a digest
+/// algorithm OID
+fn digest_algorithm(oid: &[u8]) -> Option<(&'static digest::Algorithm, &'static RSAParameters)> {
+    match oid {
+        OID_SHA1 => Some((&digest::SHA1, &RSA_PKCS1_2048_8192_SHA1)),
+        OID_SHA256 => Some((&digest::SHA256, &RSA_PKCS1_2048_8192_SHA256)),
+        OID_SHA384 => Some((&digest::SHA384, &RSA_PKCS1_2048_8192_SHA384)),
+        OID_SHA512 => Some((&digest::SHA512, &RSA_PKCS1_2048_8192_SHA512)),
+        _ => None,
+    }
+}
+
+/// OID of an AlgorithmIdentifier
+fn algorithm_oid(tlv: &Tlv) -> Result<Vec<u8>> {
+    match tlv.expect(TAG_SEQUENCE)?.first() {
+        Some(oid) if oid.tag == TAG_OID => Ok(oid.contents.to_vec()),
+        _ => Err(BadDer("bad algorithm identifier".to_string()).into()),
+    }
+}
+
+/// Contents of a BIT STRING without unused bits
+fn bit_string(tlv: &Tlv) -> Result<Vec<u8>> {
+    match tlv.contents.split_first() {
+        Some((&0, bits)) if tlv.tag == TAG_BIT_STRING => Ok(bits.to_vec()),
+        _ => Err(BadDer("expected a byte aligned bit string".to_string()).into()),
+    }
+}
+
+/// An X.509 certificate, with the fields needed to match it against signer
+/// identifiers and provisioning profiles
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Certificate {
+    /// DER encoding of the whole certificate
+    pub der: Vec<u8>,
+    /// serialNumber contents
+    pub serial: Vec<u8>,
+    /// DER encoded issuer Name
+    pub issuer: Vec<u8>,
+    /// DER encoded subject Name
+    pub subject: Vec<u8>,
+    /// DER encoded SubjectPublicKeyInfo
+    pub public_key_info: Vec<u8>,
+    /// DER encoded TBSCertificate, what the issuer signed
+    pub tbs: Vec<u8>,
+    /// OID of the issuer's signature algorithm
+    pub signature_algorithm: Vec<u8>,
+    pub signature: Vec<u8>,
+    /// OID and extnValue contents of each extension
+    pub extensions: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Certificate {
+    pub fn parse(der: &[u8]) -> Result<Certificate> {
+        let cert = Tlv::from_bytes(der)?;
+        let fields = cert.expect(TAG_SEQUENCE)?;
+        if fields.len() != 3 {
+            return Err(BadDer("bad certificate".to_string()).into());
+        }
+        let algorithm = fields[1].expect(TAG_SEQUENCE)?;
+        let signature_algorithm = match algorithm.first() {
+            Some(oid) if oid.tag == TAG_OID => oid.contents.to_vec(),
+            _ => return Err(BadDer("bad signature algorithm".to_string()).into()),
+        };
+        let tbs = fields[0].expect(TAG_SEQUENCE)?;
+        // skip the optional explicit version
+        let tbs = match tbs.first() {
+            Some(version) if version.tag == CONTEXT_0 => &tbs[1..],
+            _ => &tbs[..],
+        };
+        if tbs.len() < 6 || tbs[0].tag != TAG_INTEGER {
+            return Err(BadDer("bad TBSCertificate".to_string()).into());
+        }
+        Ok(Certificate {
+            der: cert.raw.to_vec(),
+            serial: tbs[0].contents.to_vec(),
+            issuer: tbs[2].raw.to_vec(),
+            subject: tbs[4].raw.to_vec(),
+            public_key_info: tbs[5].raw.to_vec(),
+            tbs: fields[0].raw.to_vec(),
+            signature_algorithm,
+            signature: bit_string(&fields[2])?,
+            extensions: parse_extensions(&tbs[6..])?,
+        })
+    }
+
+    /// Whether the certificate carries extension `oid`, e.g. an Apple
+    /// certificate policy marker
+    pub fn has_extension(&self, oid: &[u8]) -> bool {
+        self.extensions.iter().any(|&(ref ext, _)| ext == oid)
+    }
+
+    /// The RSAPublicKey of an RSA certificate
+    pub fn rsa_public_key(&self) -> Result<Vec<u8>> {
+        let info = Tlv::from_bytes(&self.public_key_info)?.expect(TAG_SEQUENCE)?;
+        let algorithm = match info.first() {
+            Some(algorithm) => algorithm.expect(TAG_SEQUENCE)?,
+            None => return Err(BadDer("empty SubjectPublicKeyInfo".to_string()).into()),
+        };
+        match (algorithm.first(), info.get(1)) {
+            (Some(oid), Some(key)) if oid.contents == OID_RSA_ENCRYPTION => bit_string(key),
+            _ => Err(BadDer("not an RSA public key".to_string()).into()),
+        }
+    }
+
+    /// Check a PKCS#1 v1.5 `signature` of `message` by this certificate's key
+    pub fn verify(
+        &self,
+        algorithm: &'static RSAParameters,
+        message: &[u8],
+        signature: &[u8],
+    ) -> Result<bool> {
+        let key = self.rsa_public_key()?;
+        Ok(signature::verify(
+            algorithm,
+            Input::from(&key),
+            Input::from(message),
+            Input::from(signature),
+        )
+        .is_ok())
+    }
+
+    /// Whether `issuer` issued and signed this certificate
+    pub fn is_issued_by(&self, issuer: &Certificate) -> Result<bool> {
+        if self.issuer != issuer.subject {
+            return Ok(false);
+        }
+        match rsa_algorithm(&self.signature_algorithm) {
+            Some(algorithm) => issuer.verify(algorithm, &self.tbs, &self.signature),
+            None => Err(BadDer("unsupported certificate signature algorithm".to_string()).into()),
+        }
+    }
+
+    /// First value of attribute `oid` in the subject
+    pub fn subject_attribute(&self, oid: &[u8]) -> Option<String> {
+        let subject = Tlv::from_bytes(&self.subject).ok()?;
+        for rdn in subject.children().ok()? {
+            for attribute in rdn.children().ok()? {
+                let pair = attribute.children().ok()?;
+                if pair.len() == 2 && pair[0].tag == TAG_OID && pair[0].contents == oid {
+                    return Some(pair[1].string());
+                }
+            }
+        }
+        None
+    }
+
+    /// Subject common name, e.g. `Apple Distribution: Example (ABCDE12345)`
+    pub fn common_name(&self) -> Option<String> {
+        self.subject_attribute(OID_COMMON_NAME)
+    }
+
+    /// Subject organizational unit, the team identifier of Apple issued
+    /// certificates
+    pub fn organizational_unit(&self) -> Option<String> {
+        self.subject_attribute(OID_ORGANIZATIONAL_UNIT)
+    }
+}
+
+/// The `[3] Extensions` among the TBSCertificate fields following the
+/// SubjectPublicKeyInfo
+fn parse_extensions(fields: &[Tlv]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut extensions = vec![];
+    for field in fields.iter().filter(|field| field.tag == CONTEXT_3) {
+        for extensions_seq in field.children()? {
+            for extension in extensions_seq.expect(TAG_SEQUENCE)? {
+                // extnID, critical (optional), extnValue
+                let parts = extension.expect(TAG_SEQUENCE)?;
+                match (parts.first(), parts.last()) {
+                    (Some(oid), Some(value)) if oid.tag == TAG_OID && parts.len() > 1 => {
+                        extensions.push((oid.contents.to_vec(), value.octets()?))
+                    }
+                    _ => return Err(BadDer("bad certificate extension".to_string()).into()),
+                }
+            }
+        }
+    }
+    Ok(extensions)
+}
+
+/// Identifies the certificate of a signer: issuer Name and serial number
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignerIdentifier {
+    pub issuer: Vec<u8>,
+    pub serial: Vec<u8>,
+}
+
+/// A `SignerInfo`: who signed, the attributes they signed and the signature
+#[derive(Debug, Clone)]
+pub struct SignerInfo {
+    /// `None` for subject key identifiers
+    pub sid: Option<SignerIdentifier>,
+    /// OID of the digest algorithm
+    pub digest_algorithm: Vec<u8>,
+    /// signed attributes, DER encoded as the SET OF that is signed
+    pub signed_attributes: Option<Vec<u8>>,
+    /// OID of the signature algorithm
+    pub signature_algorithm: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl SignerInfo {
+    fn parse(tlv: &Tlv) -> Result<SignerInfo> {
+        // version, sid, digestAlgorithm, [0] signedAttrs, signatureAlgorithm,
+        // signature, [1] unsignedAttrs
+        let fields = tlv.expect(TAG_SEQUENCE)?;
+        if fields.len() < 5 {
+            return Err(BadDer("truncated SignerInfo".to_string()).into());
+        }
+        let sid = match fields[1] {
+            ref sid if sid.tag == TAG_SEQUENCE => {
+                let sid = sid.children()?;
+                if sid.len() != 2 {
+                    return Err(BadDer("bad signer identifier".to_string()).into());
+                }
+                Some(SignerIdentifier {
+                    issuer: sid[0].raw.to_vec(),
+                    serial: sid[1].contents.to_vec(),
+                })
+            }
+            _ => None,
+        };
+        // the signature covers the attributes with a SET OF tag instead of
+        // the implicit [0], and with definite lengths
+        let (signed_attributes, rest) = if fields[3].tag == CONTEXT_0 {
+            let attributes: Vec<Vec<u8>> = fields[3]
+                .children()?
+                .iter()
+                .map(|attribute| attribute.raw.to_vec())
+                .collect();
+            (Some(encode_constructed(TAG_SET, &attributes)), &fields[4..])
+        } else {
+            (None, &fields[3..])
+        };
+        if rest.len() < 2 {
+            return Err(BadDer("truncated SignerInfo".to_string()).into());
+        }
+        Ok(SignerInfo {
+            sid,
+            digest_algorithm: algorithm_oid(&fields[2])?,
+            signed_attributes,
+            signature_algorithm: algorithm_oid(&rest[0])?,
+            signature: rest[1].octets()?,
+        })
+    }
+
+    /// Value of the messageDigest attribute
+    fn message_digest(&self) -> Result<Option<Vec<u8>>> {
+        let attributes = match self.signed_attributes {
+            Some(ref attributes) => attributes,
+            None => return Ok(None),
+        };
+        for attribute in Tlv::from_bytes(attributes)?.expect(TAG_SET)? {
+            let attribute = attribute.expect(TAG_SEQUENCE)?;
+            if attribute.len() == 2
+                && attribute[0].tag == TAG_OID
+                && attribute[0].contents == OID_MESSAGE_DIGEST
+            {
+                return match attribute[1].expect(TAG_SET)?.first() {
+                    Some(value) => Ok(Some(value.octets()?)),
+                    None => Ok(None),
+                };
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// A CMS `SignedData` (RFC 5652), as used for code signatures and
+/// provisioning profiles
+#[derive(Debug, Clone)]
+pub struct SignedData {
+    /// encapsulated content; `None` for detached signatures
+    pub content: Option<Vec<u8>>,
+    pub certificates: Vec<Certificate>,
+    pub signers: Vec<SignerInfo>,
+}
+
+impl SignedData {
+    /// Parse a `ContentInfo` holding a `SignedData`
+    pub fn parse(data: &[u8]) -> Result<SignedData> {
+        let content_info = Tlv::from_bytes(data)?.expect(TAG_SEQUENCE)?;
+        if content_info.len() < 2
+            || content_info[0].tag != TAG_OID
+            || content_info[0].contents != OID_SIGNED_DATA
+        {
+            return Err(BadDer("not a SignedData".to_string()).into());
+        }
+        let explicit = content_info[1].expect(CONTEXT_0)?;
+        let signed_data = match explicit.first() {
+            Some(signed_data) => signed_data.expect(TAG_SEQUENCE)?,
+            None => return Err(BadDer("empty SignedData".to_string()).into()),
+        };
+        if signed_data.len() < 4 {
+            return Err(BadDer("truncated SignedData".to_string()).into());
+        }
+
+        // version, digestAlgorithms, encapContentInfo, [0] certificates,
+        // [1] crls, signerInfos
+        let encap = signed_data[2].expect(TAG_SEQUENCE)?;
+        let content = match encap.get(1) {
+            Some(explicit) => match explicit.expect(CONTEXT_0)?.first() {
+                Some(octets) => Some(octets.octets()?),
+                None => None,
+            },
+            None => None,
+        };
+
+        let mut certificates = vec![];
+        let mut signers = vec![];
+        for field in &signed_data[3..] {
+            match field.tag {
+                CONTEXT_0 => {
+                    for cert in field.children()? {
+                        certificates.push(Certificate::parse(cert.raw)?);
+                    }
+                }
+                TAG_SET => {
+                    for signer_info in field.children()? {
+                        signers.push(SignerInfo::parse(&signer_info)?);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(SignedData {
+            content,
+            certificates,
+            signers,
+        })
+    }
+
+    /// Certificate of the first signer
+    pub fn leaf_certificate(&self) -> Option<&Certificate> {
+        let sid = match self.signers.first() {
+            Some(&SignerInfo {
+                sid: Some(ref sid), ..
+            }) => sid,
+            _ => return None,
+        };
+        self.certificates
+            .iter()
+            .find(|cert| cert.serial == sid.serial && cert.issuer == sid.issuer)
+    }
+
+    /// Check the first signer's signature of the detached `content`: the
+    /// messageDigest it signed must match the content, and its RSA signature
+    /// of the signed attributes must verify with its certificate.
+    ///
+    /// Errors for what cannot be checked, such as a missing certificate or a
+    /// non-RSA key. The certificate chain is not checked.
+    pub fn verify_detached(&self, content: &[u8]) -> Result<bool> {
+        let signer = match self.signers.first() {
+            Some(signer) => signer,
+            None => return Err(BadDer("no signer".to_string()).into()),
+        };
+        let leaf = match self.leaf_certificate() {
+            Some(leaf) => leaf,
+            None => return Err(BadDer("signer certificate not embedded".to_string()).into()),
+        };
+        let attributes = match signer.signed_attributes {
+            Some(ref attributes) => attributes,
+            None => return Err(BadDer("no signed attributes".to_string()).into()),
+        };
+        let (hash, rsa_parameters) = match digest_algorithm(&signer.digest_algorithm) {
+            Some(algorithms) => algorithms,
+            None => return Err(BadDer("unsupported digest algorithm".to_string()).into()),
+        };
+        match signer.message_digest()? {
+            Some(ref value) if &value[..] == digest(hash, content).as_ref() => {}
+            _ => return Ok(false),
+        }
+        let rsa_parameters = match &signer.signature_algorithm[..] {
+            OID_RSA_ENCRYPTION => rsa_parameters,
+            oid => match rsa_algorithm(oid) {
+                Some(rsa_parameters) => rsa_parameters,
+                None => return Err(BadDer("unsupported signature algorithm".to_string()).into()),
+            },
+        };
+        leaf.verify(rsa_parameters, attributes, &signer.signature)
+    }
+}
+
+/// A certificate and its RSA private key, to produce CMS signatures with
+#[derive(Clone)]
+pub struct SigningIdentity {
+    pub certificate: Certificate,
+    /// intermediate certificates, embedded after the leaf
+    pub chain: Vec<Certificate>,
+    key_pair: Arc<RSAKeyPair>,
+}
+
+impl fmt::Debug for SigningIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SigningIdentity")
+            .field("certificate", &self.certificate.common_name())
+            .field("chain", &self.chain.len())
+            .finish()
+    }
+}
+
+impl SigningIdentity {
+    /// `key` is a DER PKCS#8 RSA private key, the certificates are DER
+    pub fn from_der(key: &[u8], certificate: &[u8], chain: &[&[u8]]) -> Result<SigningIdentity> {
+        let key_pair = match RSAKeyPair::from_pkcs8(Input::from(key)) {
+            Ok(key_pair) => key_pair,
+            Err(_) => return Err(BadSigningKey("not a PKCS#8 RSA key".to_string()).into()),
+        };
+        let mut certificates = vec![];
+        for cert in chain {
+            certificates.push(Certificate::parse(cert)?);
+        }
+        Ok(SigningIdentity {
+            certificate: Certificate::parse(certificate)?,
+            chain: certificates,
+            key_pair: Arc::new(key_pair),
+        })
+    }
+
+    /// Team identifier of Apple issued certificates
+    pub fn team_id(&self) -> Option<String> {
+        self.certificate.organizational_unit()
+    }
+
+    /// Sign `content` (SHA-256, PKCS#1 v1.5), returning a `ContentInfo`
+    /// holding a detached `SignedData`.
+    ///
+    /// `attributes` are encoded `Attribute`s signed next to the content type
+    /// and message digest. No signing time is added, so the output only
+    /// depends on the input.
+    pub fn sign_detached(&self, content: &[u8], attributes: &[Vec<u8>]) -> Result<Vec<u8>> {
+        let attribute = |oid: &[u8], value: Vec<u8>| {
+            encode_constructed(
+                TAG_SEQUENCE,
+                &[encode(TAG_OID, oid), encode_set(vec![value])],
+            )
+        };
+        let mut signed_attributes = vec![
+            attribute(OID_CONTENT_TYPE, encode(TAG_OID, OID_DATA)),
+            attribute(
+                OID_MESSAGE_DIGEST,
+                encode(TAG_OCTET_STRING, digest(&SHA256, content).as_ref()),
+            ),
+        ];
+        signed_attributes.extend_from_slice(attributes);
+        // signed as a SET OF, embedded as [0] IMPLICIT
+        let signed_attributes = encode_set(signed_attributes);
+
+        let mut signature = vec![0; self.key_pair.public_modulus_len()];
+        let signed = RSASigningState::new(self.key_pair.clone()).and_then(|mut state| {
+            state.sign(
+                &RSA_PKCS1_SHA256,
+                &SystemRandom::new(),
+                &signed_attributes,
+                &mut signature,
+            )
+        });
+        if signed.is_err() {
+            return Err(SigningFailed("RSA signature".to_string()).into());
+        }
+
+        let mut implicit_attributes = signed_attributes;
+        implicit_attributes[0] = CONTEXT_0;
+        let signer_info = encode_constructed(
+            TAG_SEQUENCE,
+            &[
+                encode(TAG_INTEGER, &[1]),
+                encode_constructed(
+                    TAG_SEQUENCE,
+                    &[
+                        self.certificate.issuer.clone(),
+                        encode(TAG_INTEGER, &self.certificate.serial),
+                    ],
+                ),
+                encode_algorithm(OID_SHA256),
+                implicit_attributes,
+                encode_algorithm(OID_RSA_ENCRYPTION),
+                encode(TAG_OCTET_STRING, &signature),
+            ],
+        );
+
+        let mut certificates = vec![self.certificate.der.clone()];
+        certificates.extend(self.chain.iter().map(|cert| cert.der.clone()));
+        let signed_data = encode_constructed(
+            TAG_SEQUENCE,
+            &[
+                encode(TAG_INTEGER, &[1]),
+                encode_set(vec![encode_algorithm(OID_SHA256)]),
+                encode_constructed(TAG_SEQUENCE, &[encode(TAG_OID, OID_DATA)]),
+                encode_constructed(CONTEXT_0, &certificates),
+                encode_constructed(TAG_SET, &[signer_info]),
+            ],
+        );
+        Ok(encode_constructed(
+            TAG_SEQUENCE,
+            &[
+                encode(TAG_OID, OID_SIGNED_DATA),
+                encode_constructed(CONTEXT_0, &[signed_data]),
+            ],
+        ))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use cms::{SignedData, SigningIdentity};
+
+    /// Self-signed Developer ID identity of team ABCDE12345
+    pub fn identity() -> SigningIdentity {
+        SigningIdentity::from_der(
+            include_bytes!("../testdata/developer_id.p8"),
+            include_bytes!("../testdata/developer_id.der"),
+            &[],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_verify_detached() {
+        let identity = identity();
+        let cms = identity.sign_detached(b"code directory", &[]).unwrap();
+        let signed = SignedData::parse(&cms).unwrap();
+        assert_eq!(signed.leaf_certificate(), Some(&identity.certificate));
+        assert!(signed.verify_detached(b"code directory").unwrap());
+        assert!(!signed.verify_detached(b"other directory").unwrap());
+
+        // a signature over other attributes
+        let mut forged = signed.clone();
+        forged.signers[0].signature =
+            SignedData::parse(&identity.sign_detached(b"other directory", &[]).unwrap())
+                .unwrap()
+                .signers[0]
+                .signature
+                .clone();
+        assert!(!forged.verify_detached(b"code directory").unwrap());
+    }
+}
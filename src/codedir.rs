@@ -5,22 +5,31 @@
 
 use hexdump;
 
-use byteorder::{ByteOrder, NetworkEndian, ReadBytesExt};
+use byteorder::{ByteOrder, LittleEndian, NetworkEndian, ReadBytesExt, WriteBytesExt};
 use hex;
 use ring::digest;
 
 use slog::{Drain, Logger};
 use slog_stdlog;
 
-use std::io::{BufRead, Cursor, Read, Seek, SeekFrom};
+use std::fs::File;
+use std::io::{BufRead, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
 use std::str;
 
-use errors::{CDMachError::*, Result};
+use errors::{CDMachError, CDMachError::*, Result};
 
 use consts::{
-    CS_HASHTYPE_SHA1, CS_HASHTYPE_SHA256, CSMAGIC_BLOBWRAPPER, CSMAGIC_CODEDIRECTORY,
-    CSMAGIC_EMBEDDED_ENTITLEMENTS, CSMAGIC_EMBEDDED_SIGNATURE, CSMAGIC_REQUIREMENTS,
-    CSSLOT_CODEDIRECTORY,
+    CS_ADHOC, CS_FLAG_NAMES, CS_HASHTYPE_SHA1, CS_LINKER_SIGNED, CS_HASHTYPE_SHA256,
+    CS_HASHTYPE_SHA256_TRUNCATED, CS_HASHTYPE_SHA384, CS_HASH_SIZE_SHA1, CS_HASH_SIZE_SHA256,
+    CS_HASH_SIZE_SHA256_TRUNCATED, CS_HASH_SIZE_SHA384, CSMAGIC_BLOBWRAPPER, CSMAGIC_CODEDIRECTORY,
+    CSMAGIC_EMBEDDED_DER_ENTITLEMENTS, CSMAGIC_EMBEDDED_ENTITLEMENTS, CSMAGIC_EMBEDDED_SIGNATURE,
+    CSMAGIC_DETACHED_SIGNATURE, CSMAGIC_REQUIREMENTS, CSSLOT_CODEDIRECTORY, CSSLOT_TICKETSLOT,
+    FAT_MAGIC_64,
+};
+use mach_object::{
+    FAT_MAGIC, LC_CODE_SIGNATURE, LC_SEGMENT, LC_SEGMENT_64, MH_CIGAM, MH_CIGAM_64, MH_MAGIC,
+    MH_MAGIC_64,
 };
 
 #[derive(Debug, Default, Clone)]
@@ -35,7 +44,7 @@ pub struct SuperBlob {
     pub index: Vec<Option<BlobIndex>>,
 }
 
-trait CDVersion {
+pub trait CDVersion {
     const currentVersion: u32;
     const compatibilityLimit: u32;
 }
@@ -112,6 +121,10 @@ pub struct CodeDirectory {
     pub execSegLimit: u64,
     /// exec segment flags
     pub execSegFlags: u64,
+    /// Runtime version encoded as an unsigned int
+    pub runtime: u32,
+    /// offset of pre-encrypt hash slots
+    pub preEncryptOffset: u32,
 }
 
 /// earliest supported version
@@ -124,6 +137,8 @@ pub const supportsTeamID: u32 = 0x20200;
 pub const supportsCodeLimit64: u32 = 0x20300;
 /// first version to support exec base and limit
 pub const supportsExecSegment: u32 = 0x20400;
+/// first version to support runtime version and pre-encrypt hashes
+pub const supportsRuntime: u32 = 0x20500;
 
 #[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct BlobIndex {
@@ -181,6 +196,28 @@ impl CodeDirectory {
             0
         };
 
+        let (spare3, codeLimit64) = if version >= supportsCodeLimit64 {
+            (buf.read_u32::<O>()?, buf.read_u64::<O>()?)
+        } else {
+            (0, 0)
+        };
+
+        let (execSegBase, execSegLimit, execSegFlags) = if version >= supportsExecSegment {
+            (
+                buf.read_u64::<O>()?,
+                buf.read_u64::<O>()?,
+                buf.read_u64::<O>()?,
+            )
+        } else {
+            (0, 0, 0)
+        };
+
+        let (runtime, preEncryptOffset) = if version >= supportsRuntime {
+            (buf.read_u32::<O>()?, buf.read_u32::<O>()?)
+        } else {
+            (0, 0)
+        };
+
         Ok(CodeDirectory {
             magic,
             length,
@@ -198,18 +235,105 @@ impl CodeDirectory {
             spare2,
             scatterOffset,
             teamIDOffset,
-            ..Default::default()
+            spare3,
+            codeLimit64,
+            execSegBase,
+            execSegLimit,
+            execSegFlags,
+            runtime,
+            preEncryptOffset,
         })
     }
 
+    /// Size in bytes of the fixed header for the given CodeDirectory version
+    pub fn header_size(version: u32) -> u32 {
+        if version >= supportsRuntime {
+            96
+        } else if version >= supportsExecSegment {
+            88
+        } else if version >= supportsCodeLimit64 {
+            64
+        } else if version >= supportsTeamID {
+            52
+        } else if version >= supportsScatter {
+            48
+        } else {
+            44
+        }
+    }
+
+    /// Serialize the fixed header, honouring the fields present in `self.version`
+    pub fn write_header<O: ByteOrder, W: Write>(&self, buf: &mut W) -> Result<()> {
+        buf.write_u32::<O>(self.magic)?;
+        buf.write_u32::<O>(self.length)?;
+        buf.write_u32::<O>(self.version)?;
+        buf.write_u32::<O>(self.flags)?;
+        buf.write_u32::<O>(self.hashOffset)?;
+        buf.write_u32::<O>(self.identOffset)?;
+        buf.write_u32::<O>(self.nSpecialSlots)?;
+        buf.write_u32::<O>(self.nCodeSlots)?;
+        buf.write_u32::<O>(self.codeLimit)?;
+        buf.write_u8(self.hashSize)?;
+        buf.write_u8(self.hashType)?;
+        buf.write_u8(self.platform)?;
+        buf.write_u8(self.pageSize)?;
+        buf.write_u32::<O>(self.spare2)?;
+        if self.version >= supportsScatter {
+            buf.write_u32::<O>(self.scatterOffset)?;
+        }
+        if self.version >= supportsTeamID {
+            buf.write_u32::<O>(self.teamIDOffset)?;
+        }
+        if self.version >= supportsCodeLimit64 {
+            buf.write_u32::<O>(self.spare3)?;
+            buf.write_u64::<O>(self.codeLimit64)?;
+        }
+        if self.version >= supportsExecSegment {
+            buf.write_u64::<O>(self.execSegBase)?;
+            buf.write_u64::<O>(self.execSegLimit)?;
+            buf.write_u64::<O>(self.execSegFlags)?;
+        }
+        if self.version >= supportsRuntime {
+            buf.write_u32::<O>(self.runtime)?;
+            buf.write_u32::<O>(self.preEncryptOffset)?;
+        }
+        Ok(())
+    }
+
     pub fn hash_type_str<'a>(&self) -> Result<&'a str> {
         match self.hashType as u32 {
             CS_HASHTYPE_SHA1 => Ok("SHA-1"),
             CS_HASHTYPE_SHA256 => Ok("SHA-256"),
-            _ => unimplemented!(),
+            CS_HASHTYPE_SHA256_TRUNCATED => Ok("SHA-256 (truncated)"),
+            CS_HASHTYPE_SHA384 => Ok("SHA-384"),
+            typ => Err(UnsupportedHashType(typ).into()),
         }
     }
 
+    /// Comma separated flag names, as printed by `codesign -d`
+    pub fn flags_str(&self) -> String {
+        let names: Vec<&str> = CS_FLAG_NAMES
+            .iter()
+            .filter(|&&(flag, _)| self.flags & flag != 0)
+            .map(|&(_, name)| name)
+            .collect();
+        if names.is_empty() {
+            "none".to_string()
+        } else {
+            names.join(",")
+        }
+    }
+
+    pub fn is_adhoc(&self) -> bool {
+        self.flags & CS_ADHOC != 0
+    }
+
+    /// Signed by ld64 while linking: replaceable at will, and never carrying
+    /// requirements or entitlements
+    pub fn is_linker_signed(&self) -> bool {
+        self.flags & CS_LINKER_SIGNED != 0
+    }
+
     fn team_id<T: AsRef<[u8]>>(&self, buf: &mut Cursor<T>) -> Result<String> {
         if self.version >= supportsTeamID {
             Ok(read_string_to_nul(buf)?)
@@ -237,7 +361,11 @@ impl CodeDirectory {
         buf: &mut Cursor<T>,
     ) -> Result<Vec<(i32, String)>> {
         buf.set_position(0);
-        let page_size = 1 << self.pageSize;
+        // a zero page size hashes the whole code as a single page
+        let page_size = match self.pageSize {
+            0 => self.codeLimit as u64,
+            page_size => 1 << page_size,
+        };
         let hashes: Result<Vec<(i32, String)>> = (0..self.nCodeSlots as i32)
             .map(|i| {
                 let sz = if (buf.position() + page_size) > self.codeLimit as u64 {
@@ -247,12 +375,7 @@ impl CodeDirectory {
                 };
                 let mut hash_buf = vec![0u8; sz as usize];
                 buf.read_exact(&mut hash_buf)?;
-                let digest_type = match self.hashType as u32 {
-                    CS_HASHTYPE_SHA1 => &digest::SHA1,
-                    CS_HASHTYPE_SHA256 => &digest::SHA256,
-                    _ => unimplemented!(),
-                };
-                let digest = digest::digest(digest_type, &hash_buf);
+                let digest = hash_digest(self.hashType as u32, &hash_buf)?;
                 Ok((i, hex::encode(digest)))
             })
             .collect();
@@ -264,12 +387,7 @@ impl CodeDirectory {
         let mut hash_buf = vec![0u8; self.length as usize];
         buf.read_exact(&mut hash_buf)?;
         // hexdump::hexdump(&hash_buf);
-        let digest_type = match self.hashType as u32 {
-            CS_HASHTYPE_SHA1 => &digest::SHA1,
-            CS_HASHTYPE_SHA256 => &digest::SHA256,
-            _ => unimplemented!(),
-        };
-        let digest = digest::digest(digest_type, &hash_buf);
+        let digest = hash_digest(self.hashType as u32, &hash_buf)?;
         let cd_hash = hex::encode(digest);
         Ok(Some(cd_hash))
     }
@@ -285,6 +403,8 @@ pub enum Blob {
         index: BlobIndex,
         /// `CodeDirectory`
         code_directory: CodeDirectory,
+        /// Raw CodeDirectory blob, as hashed and signed
+        data: Vec<u8>,
         /// Identifier string
         identifier: Result<String>,
         /// Team Identifier
@@ -300,16 +420,32 @@ pub enum Blob {
     },
     Requirements {
         index: BlobIndex,
+        /// Raw requirements blob, including its header
+        data: Vec<u8>,
     },
     Entitlements {
         index: BlobIndex,
+        /// SHA-256 of the whole entitlements blob
         entitlements_hash: Vec<u8>,
+        /// XML plist payload
         entitlements: Vec<u8>,
     },
+    DerEntitlements {
+        index: BlobIndex,
+        /// DER encoded payload
+        der_entitlements: Vec<u8>,
+    },
     SignedData {
         index: BlobIndex,
         data: Option<String>,
         sha256_digest: Option<String>,
+        /// CMS SignedData payload (BER/DER); empty for ad-hoc signatures
+        cms: Vec<u8>,
+    },
+    /// Stapled notarization ticket (`CSSLOT_TICKETSLOT`), kept as is
+    Ticket {
+        index: BlobIndex,
+        data: Vec<u8>,
     },
     Unknown {
         index: BlobIndex,
@@ -331,18 +467,335 @@ pub struct CodeSignature {
     pub blobs: Option<Vec<Blob>>,
 }
 
+/// Digest `data` with the algorithm selected by a `CS_HASHTYPE_*` value
+pub fn hash_digest(hash_type: u32, data: &[u8]) -> Result<Vec<u8>> {
+    let digest_type = match hash_type {
+        CS_HASHTYPE_SHA1 => &digest::SHA1,
+        CS_HASHTYPE_SHA256 | CS_HASHTYPE_SHA256_TRUNCATED => &digest::SHA256,
+        CS_HASHTYPE_SHA384 => &digest::SHA384,
+        _ => return Err(UnsupportedHashType(hash_type).into()),
+    };
+    let digest = digest::digest(digest_type, data);
+    Ok(digest.as_ref()[..hash_size(hash_type)? as usize].to_vec())
+}
+
+/// Size in bytes of the digests produced for a `CS_HASHTYPE_*` value
+pub fn hash_size(hash_type: u32) -> Result<u8> {
+    match hash_type {
+        CS_HASHTYPE_SHA1 => Ok(CS_HASH_SIZE_SHA1 as u8),
+        CS_HASHTYPE_SHA256 => Ok(CS_HASH_SIZE_SHA256 as u8),
+        CS_HASHTYPE_SHA256_TRUNCATED => Ok(CS_HASH_SIZE_SHA256_TRUNCATED as u8),
+        CS_HASHTYPE_SHA384 => Ok(CS_HASH_SIZE_SHA384 as u8),
+        _ => Err(UnsupportedHashType(hash_type).into()),
+    }
+}
+
 fn read_string_to_nul<T: AsRef<[u8]>>(buf: &mut Cursor<T>) -> Result<String> {
     let mut ident = vec![];
     let sz = buf.read_until(0x00, &mut ident)?;
     Ok(str::from_utf8(&ident[0..sz - 1])?.to_string())
 }
 
+/// File offsets of the load commands that describe the signature
+#[derive(Debug)]
+pub struct LinkEditLayout {
+    /// offset of the `LC_CODE_SIGNATURE` command
+    pub code_signature_cmd: usize,
+    /// offset of the signature
+    pub dataoff: u32,
+    /// size of the signature
+    pub datasize: u32,
+    /// offset of the `__LINKEDIT` segment command, and whether it is 64 bit
+    pub linkedit_cmd: Option<(usize, bool)>,
+    /// file offset and size of the `__TEXT` segment
+    pub text_segment: Option<(u64, u64)>,
+    /// type of file
+    pub filetype: u32,
+}
+
+/// Smallest `cmdsize` of a load command holding the fields we read
+pub fn min_cmdsize(cmd: u32) -> usize {
+    match cmd {
+        // linkedit_data_command
+        LC_CODE_SIGNATURE => 16,
+        // segment_command, segment_command_64
+        LC_SEGMENT => 56,
+        LC_SEGMENT_64 => 72,
+        _ => 8,
+    }
+}
+
+/// Walk the load commands of a little endian thin image (only the header and
+/// load commands need to be present in `macho`)
+pub fn find_link_edit(macho: &[u8]) -> Result<LinkEditLayout> {
+    if macho.len() < 28 {
+        return Err(UnsupportedMachO("truncated header".to_string()).into());
+    }
+    let header_size = match LittleEndian::read_u32(macho) {
+        MH_MAGIC_64 => 32,
+        MH_MAGIC => 28,
+        magic => return Err(UnsupportedMachO(format!("magic 0x{:x}", magic)).into()),
+    };
+    let ncmds = LittleEndian::read_u32(&macho[16..]);
+
+    let mut code_signature = None;
+    let mut linkedit_cmd = None;
+    let mut text_segment = None;
+    let mut offset = header_size;
+    for _ in 0..ncmds {
+        if offset + 8 > macho.len() {
+            return Err(UnsupportedMachO("truncated load commands".to_string()).into());
+        }
+        let cmd = LittleEndian::read_u32(&macho[offset..]);
+        let cmdsize = LittleEndian::read_u32(&macho[offset + 4..]) as usize;
+        if cmdsize < min_cmdsize(cmd) || offset + cmdsize > macho.len() {
+            return Err(UnsupportedMachO(format!("bad load command size {}", cmdsize)).into());
+        }
+        match cmd {
+            LC_CODE_SIGNATURE => {
+                code_signature = Some((
+                    offset,
+                    LittleEndian::read_u32(&macho[offset + 8..]),
+                    LittleEndian::read_u32(&macho[offset + 12..]),
+                ))
+            }
+            LC_SEGMENT | LC_SEGMENT_64 if &macho[offset + 8..offset + 19] == b"__LINKEDIT\0" => {
+                linkedit_cmd = Some((offset, cmd == LC_SEGMENT_64))
+            }
+            LC_SEGMENT_64 if &macho[offset + 8..offset + 15] == b"__TEXT\0" => {
+                text_segment = Some((
+                    LittleEndian::read_u64(&macho[offset + 40..]),
+                    LittleEndian::read_u64(&macho[offset + 48..]),
+                ))
+            }
+            LC_SEGMENT if &macho[offset + 8..offset + 15] == b"__TEXT\0" => {
+                text_segment = Some((
+                    LittleEndian::read_u32(&macho[offset + 32..]) as u64,
+                    LittleEndian::read_u32(&macho[offset + 36..]) as u64,
+                ))
+            }
+            _ => {}
+        }
+        offset += cmdsize;
+    }
+
+    match code_signature {
+        Some((code_signature_cmd, dataoff, datasize)) => Ok(LinkEditLayout {
+            code_signature_cmd,
+            dataoff,
+            datasize,
+            linkedit_cmd,
+            text_segment,
+            filetype: LittleEndian::read_u32(&macho[12..]),
+        }),
+        None => Err(NoCodeSignature.into()),
+    }
+}
+
+/// The Mach-O images in `data`: the slices of a fat file, or `data` itself
+pub fn image_slices(data: &[u8]) -> Result<Vec<&[u8]>> {
+    // fat_arch: cputype, cpusubtype, offset, size, align; fat_arch_64 widens
+    // offset and size to 64 bits and adds a reserved field
+    if data.len() < 8 {
+        return Ok(vec![data]);
+    }
+    let fat64 = match NetworkEndian::read_u32(data) {
+        FAT_MAGIC => false,
+        FAT_MAGIC_64 => true,
+        _ => return Ok(vec![data]),
+    };
+    let arch_size = if fat64 { 32 } else { 20 };
+    let nfat_arch = NetworkEndian::read_u32(&data[4..]) as usize;
+    let mut slices = vec![];
+    for i in 0..nfat_arch {
+        let arch = 8 + i * arch_size;
+        let entry = match data.get(arch..arch + arch_size) {
+            Some(entry) => entry,
+            None => return Err(UnsupportedMachO("truncated fat header".to_string()).into()),
+        };
+        let (offset, size) = if fat64 {
+            (
+                NetworkEndian::read_u64(&entry[8..]),
+                NetworkEndian::read_u64(&entry[16..]),
+            )
+        } else {
+            (
+                NetworkEndian::read_u32(&entry[8..]) as u64,
+                NetworkEndian::read_u32(&entry[12..]) as u64,
+            )
+        };
+        let slice = offset
+            .checked_add(size)
+            .and_then(|end| data.get(offset as usize..end as usize));
+        match slice {
+            Some(slice) => slices.push(slice),
+            None => {
+                return Err(
+                    UnsupportedMachO("fat slice past the end of the file".to_string()).into(),
+                )
+            }
+        }
+    }
+    Ok(slices)
+}
+
+/// CPU type of a thin Mach-O image; `None` for anything else
+pub fn image_cpu_type(slice: &[u8]) -> Option<u32> {
+    if slice.len() < 8 {
+        return None;
+    }
+    match LittleEndian::read_u32(slice) {
+        MH_MAGIC | MH_MAGIC_64 => Some(LittleEndian::read_u32(&slice[4..])),
+        MH_CIGAM | MH_CIGAM_64 => Some(NetworkEndian::read_u32(&slice[4..])),
+        _ => None,
+    }
+}
+
+/// File type (`MH_EXECUTE`, `MH_KEXT_BUNDLE`, ...) of a thin Mach-O image;
+/// `None` for anything else
+pub fn image_file_type(slice: &[u8]) -> Option<u32> {
+    if slice.len() < 16 {
+        return None;
+    }
+    match LittleEndian::read_u32(slice) {
+        MH_MAGIC | MH_MAGIC_64 => Some(LittleEndian::read_u32(&slice[12..])),
+        MH_CIGAM | MH_CIGAM_64 => Some(NetworkEndian::read_u32(&slice[12..])),
+        _ => None,
+    }
+}
+
+/// Location of a bundle's detached signature, relative to its contents
+pub const DETACHED_SIGNATURE_PATH: &str = "_CodeSignature/CodeSignature";
+
+/// A signature kept outside the code it signs, e.g. in
+/// `_CodeSignature/CodeSignature`: one embedded signature per architecture.
+#[derive(Debug, Default, Clone)]
+pub struct DetachedSignature {
+    /// CPU type and embedded signature (`CSMAGIC_EMBEDDED_SIGNATURE` blob) of
+    /// each architecture; CPU type 0 signs whatever it is applied to
+    pub signatures: Vec<(u32, Vec<u8>)>,
+}
+
+impl DetachedSignature {
+    /// Parse a `CSMAGIC_DETACHED_SIGNATURE` collection, or a lone embedded
+    /// signature as written for code that is not Mach-O
+    pub fn parse(data: &[u8]) -> Result<DetachedSignature> {
+        let mut cur = Cursor::new(data);
+        let super_blob = SuperBlob::parse::<NetworkEndian, _>(&mut cur)?;
+        if super_blob.length as usize > data.len() {
+            return Err(BadBlobLength(super_blob.length).into());
+        }
+        let mut signatures = vec![];
+        match super_blob.magic {
+            CSMAGIC_EMBEDDED_SIGNATURE => {
+                signatures.push((0, data[..super_blob.length as usize].to_vec()))
+            }
+            CSMAGIC_DETACHED_SIGNATURE => {
+                for bi in super_blob.index.iter().flat_map(|bi| bi.iter()) {
+                    cur.set_position(bi.offset as u64);
+                    let magic = cur.read_u32::<NetworkEndian>()?;
+                    let length = cur.read_u32::<NetworkEndian>()?;
+                    cur.seek(SeekFrom::Current(-8))?;
+                    if magic != CSMAGIC_EMBEDDED_SIGNATURE {
+                        return Err(BadMagic(magic).into());
+                    }
+                    signatures.push((bi.typ, read_blob(&mut cur, length)?));
+                }
+            }
+            magic => return Err(BadMagic(magic).into()),
+        }
+        Ok(DetachedSignature { signatures })
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<DetachedSignature> {
+        let mut data = vec![];
+        File::open(path)?.read_to_end(&mut data)?;
+        DetachedSignature::parse(&data)
+    }
+
+    /// The embedded signature for a slice of CPU type `cpu_type`
+    pub fn signature_for(&self, cpu_type: Option<u32>) -> Option<&[u8]> {
+        self.signatures
+            .iter()
+            .find(|&&(typ, _)| Some(typ) == cpu_type)
+            .or_else(|| self.signatures.iter().find(|&&(typ, _)| typ == 0))
+            .map(|&(_, ref signature)| &signature[..])
+    }
+
+    /// Parse the signature of every slice of `target`, like
+    /// `CodeSignature::parse_image` does for embedded signatures.
+    ///
+    /// Each embedded signature is parsed past a copy of its slice, so page
+    /// hashes are computed over the slice; slices without a signature for
+    /// their CPU type are skipped, `uncovered_slices` lists them.
+    pub fn parse_image<L: Into<Option<Logger>>>(
+        &self,
+        logger: L,
+        target: &[u8],
+    ) -> Result<Vec<CodeSignature>> {
+        let log = logger.into();
+        let mut signatures = vec![];
+        for slice in image_slices(target)? {
+            let signature = match self.signature_for(image_cpu_type(slice)) {
+                Some(signature) => signature,
+                None => continue,
+            };
+            let mut buf = Vec::with_capacity(slice.len() + signature.len());
+            buf.extend_from_slice(slice);
+            buf.extend_from_slice(signature);
+            let mut cur = Cursor::new(buf);
+            cur.set_position(slice.len() as u64);
+            if let Some(cs) = CodeSignature::parse(
+                log.clone(),
+                slice.len() as u32,
+                signature.len() as u32,
+                &mut cur,
+            )? {
+                signatures.push(cs);
+            }
+        }
+        Ok(signatures)
+    }
+
+    /// CPU types of the slices of `target` the collection has no signature
+    /// for
+    pub fn uncovered_slices(&self, target: &[u8]) -> Result<Vec<Option<u32>>> {
+        Ok(image_slices(target)?
+            .into_iter()
+            .map(image_cpu_type)
+            .filter(|&cpu_type| self.signature_for(cpu_type).is_none())
+            .collect())
+    }
+}
+
+/// Read a complete blob (header included) of `length` bytes
+fn read_blob<T: AsRef<[u8]>>(buf: &mut Cursor<T>, length: u32) -> Result<Vec<u8>> {
+    if length < 8 {
+        return Err(BadBlobLength(length).into());
+    }
+    let mut data = vec![0u8; length as usize];
+    buf.read_exact(&mut data)?;
+    Ok(data)
+}
+
 impl CodeSignature {
     /// Load code signatures
     pub fn load_code_signatures<T: AsRef<[u8]>>(_path: &str) -> Result<Vec<CodeSignature>> {
         unimplemented!()
     }
 
+    /// Parse the signature at `offset` in `data` that hashes everything
+    /// before it, as in disk images and dyld shared caches
+    pub fn parse_appended(data: &[u8], offset: u64, size: u64) -> Result<Option<CodeSignature>> {
+        match offset.checked_add(size) {
+            Some(end) if end <= data.len() as u64 && end <= u64::from(u32::MAX) => {}
+            _ => return Err(BadBlobLength(size as u32).into()),
+        }
+        let mut cur = Cursor::new(data);
+        cur.set_position(offset);
+        CodeSignature::parse(None, offset as u32, size as u32, &mut cur)
+    }
+
     /// Parse a code signature
     pub fn parse<T: AsRef<[u8]>, L: Into<Option<Logger>>>(
         logger: L,
@@ -392,7 +845,11 @@ impl CodeSignature {
                                     log,
                                     "> CSMAGIC_REQUIREMENTS {:?} {:x?} len: {}", bi, magic, length
                                 );
-                                blobs.push(Blob::Requirements { index: bi.clone() });
+                                let data = read_blob(buf, length)?;
+                                blobs.push(Blob::Requirements {
+                                    index: bi.clone(),
+                                    data,
+                                });
                             }
                             CSMAGIC_CODEDIRECTORY => {
                                 debug!(
@@ -413,6 +870,8 @@ impl CodeSignature {
                                 // calculate cd_hash
                                 buf.set_position(pos);
                                 let cd_hash = cd.compute_cd_hash(buf)?;
+                                buf.set_position(pos);
+                                let data = read_blob(buf, cd.length)?;
 
                                 buf.set_position((offset + bi.offset + cd.identOffset) as u64);
                                 let identifier = read_string_to_nul(buf);
@@ -438,6 +897,7 @@ impl CodeSignature {
                                 blobs.push(Blob::CodeDirectory {
                                     index: bi.clone(),
                                     code_directory: cd,
+                                    data,
                                     identifier,
                                     team_id,
                                     hash_type,
@@ -452,14 +912,14 @@ impl CodeSignature {
                                     "> CSMAGIC_BLOBWRAPPER {:?} {:x?} len: {}", bi, magic, length
                                 );
                                 buf.set_position(offset as u64 + bi.offset as u64);
-                                let mut hash_buf = vec![0u8; length as usize];
-                                buf.read_exact(&mut hash_buf)?;
+                                let hash_buf = read_blob(buf, length)?;
                                 // hexdump::hexdump(&hash_buf);
                                 let digest = digest::digest(&digest::SHA256, &hash_buf);
                                 blobs.push(Blob::SignedData {
                                     index: bi.clone(),
                                     data: None,
                                     sha256_digest: Some(hex::encode(digest)),
+                                    cms: hash_buf[8..].to_vec(),
                                 });
                             }
                             CSMAGIC_EMBEDDED_ENTITLEMENTS => {
@@ -470,10 +930,48 @@ impl CodeSignature {
                                     magic,
                                     length
                                 );
+                                let data = read_blob(buf, length)?;
+                                let digest = digest::digest(&digest::SHA256, &data);
                                 blobs.push(Blob::Entitlements {
                                     index: bi.clone(),
-                                    entitlements_hash: vec![],
-                                    entitlements: vec![],
+                                    entitlements_hash: digest.as_ref().to_vec(),
+                                    entitlements: data[8..].to_vec(),
+                                });
+                            }
+                            CSMAGIC_EMBEDDED_DER_ENTITLEMENTS => {
+                                debug!(
+                                    log,
+                                    "> CSMAGIC_EMBEDDED_DER_ENTITLEMENTS {:?} {:x?} len: {}",
+                                    bi,
+                                    magic,
+                                    length
+                                );
+                                let data = read_blob(buf, length)?;
+                                blobs.push(Blob::DerEntitlements {
+                                    index: bi.clone(),
+                                    der_entitlements: data[8..].to_vec(),
+                                });
+                            }
+                            _ if bi.typ == CSSLOT_TICKETSLOT => {
+                                // raw ticket, no blob header: it extends to the
+                                // next blob or the end of the SuperBlob
+                                let end = super_blob
+                                    .index
+                                    .iter()
+                                    .flat_map(|other| other.iter())
+                                    .map(|other| other.offset)
+                                    .filter(|&other| other > bi.offset)
+                                    .min()
+                                    .unwrap_or(super_blob.length);
+                                if end < bi.offset {
+                                    return Err(BadBlobLength(end).into());
+                                }
+                                let mut data = vec![0u8; (end - bi.offset) as usize];
+                                buf.read_exact(&mut data)?;
+                                debug!(log, "> TICKET {:?} len: {}", bi, data.len());
+                                blobs.push(Blob::Ticket {
+                                    index: bi.clone(),
+                                    data,
                                 });
                             }
                             _ => {
@@ -492,6 +990,13 @@ impl CodeSignature {
                     blobs: Some(blobs),
                 }))
             }
+            CSMAGIC_DETACHED_SIGNATURE => {
+                info!(
+                    log,
+                    "detached signature, parse it with DetachedSignature: {:x?}", magic
+                );
+                Ok(None)
+            }
             _ => {
                 info!(log, "Unhandled CS Magic: {:x?}", magic);
                 Ok(None)
@@ -499,6 +1004,156 @@ impl CodeSignature {
         }
     }
 
+    /// Parse the embedded signature of every slice of a thin or fat image.
+    ///
+    /// Each signature is parsed from its own slice, so its offsets are
+    /// relative to the start of the slice; unsigned slices are skipped.
+    pub fn parse_image<L: Into<Option<Logger>>>(
+        logger: L,
+        data: &[u8],
+    ) -> Result<Vec<CodeSignature>> {
+        let log = logger.into();
+        let mut signatures = vec![];
+        for slice in image_slices(data)? {
+            let layout = match find_link_edit(slice) {
+                Ok(layout) => layout,
+                Err(err) => match err.downcast_ref::<CDMachError>() {
+                    Some(&NoCodeSignature) => continue,
+                    _ => return Err(err),
+                },
+            };
+            if layout.dataoff as usize + layout.datasize as usize > slice.len() {
+                return Err(UnsupportedMachO(
+                    "code signature past the end of the image".to_string(),
+                )
+                .into());
+            }
+            let mut cur = Cursor::new(slice);
+            cur.set_position(layout.dataoff as u64);
+            if let Some(cs) =
+                CodeSignature::parse(log.clone(), layout.dataoff, layout.datasize, &mut cur)?
+            {
+                signatures.push(cs);
+            }
+        }
+        Ok(signatures)
+    }
+
+    /// The primary CodeDirectory and its hash
+    pub fn code_directory(&self) -> Option<(&CodeDirectory, &str)> {
+        self.blobs
+            .iter()
+            .flat_map(|blobs| blobs.iter())
+            .find_map(|blob| match blob {
+                Blob::CodeDirectory {
+                    ref index,
+                    ref code_directory,
+                    cd_hash: Some(ref cd_hash),
+                    ..
+                } if index.typ == CSSLOT_CODEDIRECTORY => Some((code_directory, cd_hash.as_str())),
+                _ => None,
+            })
+    }
+
+    /// The primary CodeDirectory blob, which the CMS signature signs
+    pub fn code_directory_data(&self) -> Option<&[u8]> {
+        self.blobs
+            .iter()
+            .flat_map(|blobs| blobs.iter())
+            .find_map(|blob| match blob {
+                Blob::CodeDirectory {
+                    ref index,
+                    ref data,
+                    ..
+                } if index.typ == CSSLOT_CODEDIRECTORY => Some(&data[..]),
+                _ => None,
+            })
+    }
+
+    /// Identifier and team ID of the primary CodeDirectory
+    pub fn identity(&self) -> Option<(&str, Option<&str>)> {
+        self.blobs
+            .iter()
+            .flat_map(|blobs| blobs.iter())
+            .find_map(|blob| match blob {
+                Blob::CodeDirectory {
+                    ref index,
+                    ref code_directory,
+                    identifier: Ok(ref identifier),
+                    ref team_id,
+                    ..
+                } if index.typ == CSSLOT_CODEDIRECTORY => Some((
+                    identifier.as_str(),
+                    team_id
+                        .as_ref()
+                        .ok()
+                        .filter(|_| code_directory.teamIDOffset != 0)
+                        .map(|t| t.as_str()),
+                )),
+                _ => None,
+            })
+    }
+
+    /// CMS payload of the signature blob wrapper, if it holds one
+    pub fn cms(&self) -> Option<&[u8]> {
+        self.blobs
+            .iter()
+            .flat_map(|blobs| blobs.iter())
+            .find_map(|blob| match blob {
+                Blob::SignedData { ref cms, .. } if !cms.is_empty() => Some(&cms[..]),
+                _ => None,
+            })
+    }
+
+    /// Requirement set blob, header included, if the signature carries one
+    pub fn requirements(&self) -> Option<&[u8]> {
+        self.blobs
+            .iter()
+            .flat_map(|blobs| blobs.iter())
+            .find_map(|blob| match blob {
+                Blob::Requirements { ref data, .. } => Some(&data[..]),
+                _ => None,
+            })
+    }
+
+    /// XML entitlements, if the signature carries them
+    pub fn entitlements(&self) -> Option<&[u8]> {
+        self.blobs
+            .iter()
+            .flat_map(|blobs| blobs.iter())
+            .find_map(|blob| match blob {
+                Blob::Entitlements {
+                    ref entitlements, ..
+                } => Some(&entitlements[..]),
+                _ => None,
+            })
+    }
+
+    /// Check `data` against special slot `slot` of every CodeDirectory.
+    ///
+    /// Returns `None` when no CodeDirectory binds the slot.
+    pub fn verify_special_slot(&self, slot: u32, data: &[u8]) -> Result<Option<bool>> {
+        let mut verdict = None;
+        for blob in self.blobs.iter().flat_map(|blobs| blobs.iter()) {
+            if let Blob::CodeDirectory {
+                ref code_directory,
+                cd_hashes: Ok(ref hashes),
+                ..
+            } = blob
+            {
+                let expected = hashes.iter().find(|&&(i, _)| i == -(slot as i32));
+                if let Some(&(_, ref hash)) = expected {
+                    if hash.bytes().all(|b| b == b'0') {
+                        continue;
+                    }
+                    let computed = hash_digest(code_directory.hashType as u32, data)?;
+                    verdict = Some(verdict.unwrap_or(true) && hex::encode(computed) == *hash);
+                }
+            }
+        }
+        Ok(verdict)
+    }
+
     /// Sample code to locate the CodeDirectory from an embedded signature blob
     pub fn find_code_directory(blob: &SuperBlob) -> Result<Option<BlobIndex>> {
         match blob.magic {
@@ -519,7 +1174,114 @@ impl CodeSignature {
 
 #[cfg(test)]
 pub mod tests {
-    use codedir::CodeDirectory;
+    use byteorder::{ByteOrder, LittleEndian, NetworkEndian, WriteBytesExt};
+    use codedir::{find_link_edit, image_slices, CodeDirectory, DetachedSignature};
+    use consts::{CSMAGIC_DETACHED_SIGNATURE, FAT_MAGIC_64};
+    use mach_object::{FAT_MAGIC, LC_CODE_SIGNATURE, LC_SEGMENT, LC_SEGMENT_64};
+    use signer::tests::{assert_hashes_match, fake_macho};
+    use signer::{sign_macho, SigningSettings};
+
+    /// An unsigned image of CPU type `cpu_type` and a detached signature
+    /// collection holding its signature, filed under that CPU type
+    pub fn detached_signature(cpu_type: u32) -> (Vec<u8>, Vec<u8>) {
+        let mut macho = fake_macho(0x1800);
+        LittleEndian::write_u32(&mut macho[4..], cpu_type);
+        let settings = SigningSettings {
+            identifier: "detached".to_string(),
+            ..Default::default()
+        };
+        sign_macho(&settings, &mut macho).unwrap();
+        let dataoff = LittleEndian::read_u32(&macho[112..]) as usize;
+        let length = NetworkEndian::read_u32(&macho[dataoff + 4..]) as usize;
+
+        let mut detached = vec![];
+        detached
+            .write_u32::<NetworkEndian>(CSMAGIC_DETACHED_SIGNATURE)
+            .unwrap();
+        detached
+            .write_u32::<NetworkEndian>(20 + length as u32)
+            .unwrap();
+        detached.write_u32::<NetworkEndian>(1).unwrap();
+        detached.write_u32::<NetworkEndian>(cpu_type).unwrap();
+        detached.write_u32::<NetworkEndian>(20).unwrap();
+        detached.extend_from_slice(&macho[dataoff..dataoff + length]);
+        macho.truncate(dataoff);
+        (macho, detached)
+    }
+
+    #[test]
+    fn test_detached_signature() {
+        let (mut macho, detached) = detached_signature(0x0100000c);
+        let detached = DetachedSignature::parse(&detached).unwrap();
+        assert!(detached.signature_for(Some(0x01000007)).is_none());
+        let signatures = detached.parse_image(None, &macho).unwrap();
+        assert_eq!(signatures.len(), 1);
+        assert_hashes_match(&signatures[0]);
+        assert_eq!(signatures[0].identity(), Some(("detached", None)));
+        assert!(detached.uncovered_slices(&macho).unwrap().is_empty());
+
+        LittleEndian::write_u32(&mut macho[4..], 0x01000007);
+        assert_eq!(
+            detached.uncovered_slices(&macho).unwrap(),
+            vec![Some(0x01000007)]
+        );
+    }
+
+    #[test]
+    fn test_find_link_edit_truncated() {
+        let macho = fake_macho(0x1000);
+        assert!(find_link_edit(&macho).is_ok());
+        // the last load command cut short of its fields, at the end of the
+        // buffer
+        for &(cmd, name) in &[
+            (LC_CODE_SIGNATURE, &b""[..]),
+            (LC_SEGMENT_64, &b"__LINKEDIT"[..]),
+            (LC_SEGMENT_64, &b"__TEXT"[..]),
+            (LC_SEGMENT, &b"__TEXT"[..]),
+        ] {
+            let mut truncated = macho[..32].to_vec();
+            LittleEndian::write_u32(&mut truncated[16..], 1);
+            truncated.write_u32::<LittleEndian>(cmd).unwrap();
+            truncated
+                .write_u32::<LittleEndian>(8 + name.len() as u32)
+                .unwrap();
+            truncated.extend_from_slice(name);
+            assert!(find_link_edit(&truncated).is_err());
+        }
+    }
+
+    #[test]
+    fn test_image_slices() {
+        let a = fake_macho(0x1000);
+        let b = fake_macho(0x2000);
+        let fat = |magic: u32, offset: usize| {
+            let mut fat = vec![];
+            fat.write_u32::<NetworkEndian>(magic).unwrap();
+            fat.write_u32::<NetworkEndian>(2).unwrap();
+            for &(offset, len) in &[(offset, a.len()), (offset + a.len(), b.len())] {
+                fat.extend_from_slice(&[0; 8]);
+                if magic == FAT_MAGIC_64 {
+                    fat.write_u64::<NetworkEndian>(offset as u64).unwrap();
+                    fat.write_u64::<NetworkEndian>(len as u64).unwrap();
+                    fat.extend_from_slice(&[0; 8]);
+                } else {
+                    fat.write_u32::<NetworkEndian>(offset as u32).unwrap();
+                    fat.write_u32::<NetworkEndian>(len as u32).unwrap();
+                    fat.extend_from_slice(&[0; 4]);
+                }
+            }
+            fat.resize(offset, 0);
+            fat.extend_from_slice(&a);
+            fat.extend_from_slice(&b);
+            fat
+        };
+        for &magic in &[FAT_MAGIC, FAT_MAGIC_64] {
+            let data = fat(magic, 0x100);
+            assert_eq!(image_slices(&data).unwrap(), vec![&a[..], &b[..]]);
+            assert!(image_slices(&data[..data.len() - 1]).is_err());
+        }
+        assert_eq!(image_slices(&a).unwrap(), vec![&a[..]]);
+    }
 
     #[test]
     fn test_code_directory() {
@@ -17,6 +17,8 @@ pub const CSMAGIC_DETACHED_SIGNATURE: u32 = 0xfade0cc1;
 pub const CSMAGIC_BLOBWRAPPER: u32 = 0xfade0b01;
 /// used for Entitlements blob
 pub const CSMAGIC_EMBEDDED_ENTITLEMENTS: u32 = 0xfade7171;
+/// used for DER encoded Entitlements blob
+pub const CSMAGIC_EMBEDDED_DER_ENTITLEMENTS: u32 = 0xfade7172;
 
 // pub enum MagicTypes {
 //     Requirement = CSMAGIC_REQUIREMENT as isize,
@@ -29,14 +31,82 @@ pub const CSMAGIC_EMBEDDED_ENTITLEMENTS: u32 = 0xfade7171;
 // }
 
 pub const CS_PAGE_SIZE: u32 = 4096;
+/// page size used on arm64 hardware
+pub const CS_PAGE_SIZE_16K: u32 = 16384;
+
+/// CodeDirectory flags (cs_blobs.h)
+/// dynamically valid
+pub const CS_VALID: u32 = 0x0000_0001;
+/// ad hoc signed
+pub const CS_ADHOC: u32 = 0x0000_0002;
+/// has get-task-allow entitlement
+pub const CS_GET_TASK_ALLOW: u32 = 0x0000_0004;
+/// has installer entitlement
+pub const CS_INSTALLER: u32 = 0x0000_0008;
+/// Library Validation required by Hardened System Policy
+pub const CS_FORCED_LV: u32 = 0x0000_0010;
+/// (macOS Only) Page invalidation allowed by task port policy
+pub const CS_INVALID_ALLOWED: u32 = 0x0000_0020;
+/// don't load invalid pages
+pub const CS_HARD: u32 = 0x0000_0100;
+/// kill process if it becomes invalid
+pub const CS_KILL: u32 = 0x0000_0200;
+/// force expiration checking
+pub const CS_CHECK_EXPIRATION: u32 = 0x0000_0400;
+/// tell dyld to treat restricted
+pub const CS_RESTRICT: u32 = 0x0000_0800;
+/// require enforcement
+pub const CS_ENFORCEMENT: u32 = 0x0000_1000;
+/// require library validation
+pub const CS_REQUIRE_LV: u32 = 0x0000_2000;
+/// code signature permits restricted entitlements
+pub const CS_ENTITLEMENTS_VALIDATED: u32 = 0x0000_4000;
+/// has com.apple.rootless.restricted-nvram-variables.heritable entitlement
+pub const CS_NVRAM_UNRESTRICTED: u32 = 0x0000_8000;
+/// Apply hardened runtime policies
+pub const CS_RUNTIME: u32 = 0x0001_0000;
+/// Automatically signed by the linker
+pub const CS_LINKER_SIGNED: u32 = 0x0002_0000;
+
+/// Names `codesign -d` uses for the CodeDirectory flags
+pub const CS_FLAG_NAMES: [(u32, &str); 10] = [
+    (CS_VALID, "host"),
+    (CS_ADHOC, "adhoc"),
+    (CS_HARD, "hard"),
+    (CS_KILL, "kill"),
+    (CS_CHECK_EXPIRATION, "expires"),
+    (CS_RESTRICT, "restrict"),
+    (CS_ENFORCEMENT, "enforcement"),
+    (CS_REQUIRE_LV, "library-validation"),
+    (CS_RUNTIME, "runtime"),
+    (CS_LINKER_SIGNED, "linker-signed"),
+];
+
+/// executable segment flags
+/// executable segment denotes main binary
+pub const CS_EXECSEG_MAIN_BINARY: u64 = 0x1;
+/// allow unsigned pages (for debugging)
+pub const CS_EXECSEG_ALLOW_UNSIGNED: u64 = 0x10;
+/// main binary is debugger
+pub const CS_EXECSEG_DEBUGGER: u64 = 0x20;
+/// JIT enabled
+pub const CS_EXECSEG_JIT: u64 = 0x40;
+/// skip library validation
+pub const CS_EXECSEG_SKIP_LV: u64 = 0x80;
+/// can bless cdhash for execution
+pub const CS_EXECSEG_CAN_LOAD_CDHASH: u64 = 0x100;
+/// can execute blessed cdhash
+pub const CS_EXECSEG_CAN_EXEC_CDHASH: u64 = 0x200;
 
 pub const CS_HASHTYPE_SHA1: u32 = 1;
 pub const CS_HASHTYPE_SHA256: u32 = 2;
 pub const CS_HASHTYPE_SHA256_TRUNCATED: u32 = 3;
+pub const CS_HASHTYPE_SHA384: u32 = 4;
 
 pub const CS_HASH_SIZE_SHA1: u32 = 20;
 pub const CS_HASH_SIZE_SHA256: u32 = 32;
 pub const CS_HASH_SIZE_SHA256_TRUNCATED: u32 = 20;
+pub const CS_HASH_SIZE_SHA384: u32 = 48;
 
 pub const CSSLOT_CODEDIRECTORY: u32 = 0;
 pub const CSSLOT_INFOSLOT: u32 = 1;
@@ -44,10 +114,24 @@ pub const CSSLOT_REQUIREMENTS: u32 = 2;
 pub const CSSLOT_RESOURCEDIR: u32 = 3;
 pub const CSSLOT_APPLICATION: u32 = 4;
 pub const CSSLOT_ENTITLEMENTS: u32 = 5;
+pub const CSSLOT_REP_SPECIFIC: u32 = 6;
+pub const CSSLOT_DER_ENTITLEMENTS: u32 = 7;
 pub const CSSLOT_ALTERNATE_CODEDIRECTORIES: u32 = 0x1000;
 pub const CSSLOT_ALTERNATE_CODEDIRECTORY_MAX: u32 = 5;
 pub const CSSLOT_ALTERNATE_CODEDIRECTORY_LIMIT: u32 =
     CSSLOT_ALTERNATE_CODEDIRECTORIES + CSSLOT_ALTERNATE_CODEDIRECTORY_MAX;
 pub const CSSLOT_CMS_SIGNATURE: u32 = 0x10000;
+pub const CSSLOT_IDENTIFICATIONSLOT: u32 = 0x10001;
+pub const CSSLOT_TICKETSLOT: u32 = 0x10002;
 
 pub const kSecCodeSignatureAdhoc: u32 = 2;
+
+/// universal binary with 64-bit offsets and sizes (`fat_arch_64`)
+pub const FAT_MAGIC_64: u32 = 0xcafebabf;
+
+/// requirement types of a requirement set
+pub const kSecHostRequirementType: u32 = 1;
+pub const kSecGuestRequirementType: u32 = 2;
+pub const kSecDesignatedRequirementType: u32 = 3;
+pub const kSecLibraryRequirementType: u32 = 4;
+pub const kSecPluginRequirementType: u32 = 5;
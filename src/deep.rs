@@ -0,0 +1,248 @@
+#![allow(dead_code)]
+
+use hex;
+use plist::Value;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use errors::{CDMachError::*, Result};
+
+use bundle::{parse_dictionary, Bundle};
+use der::encode_der_entitlements;
+use resources::nested_seal;
+use signer::{sign_bundle, sign_macho, SigningSettings};
+use verify::find_nested_code;
+
+/// Identifier and entitlements of one component of a deep signature
+#[derive(Debug, Clone, Default)]
+pub struct ComponentSettings {
+    /// defaults to `CFBundleIdentifier` for bundles, the file name without
+    /// its extension otherwise
+    pub identifier: Option<String>,
+    /// XML entitlements plist
+    pub entitlements: Option<Vec<u8>>,
+    /// DER encoded entitlements; derived from `entitlements` when `None`
+    pub der_entitlements: Option<Vec<u8>>,
+}
+
+/// How to sign a bundle and everything nested in it
+#[derive(Debug, Clone, Default)]
+pub struct DeepSigningConfig {
+    /// settings shared by every component (hash types, flags, runtime...);
+    /// its identifier and entitlements are ignored
+    pub settings: SigningSettings,
+    /// per component settings, keyed by path relative to the top-level
+    /// bundle (`""` for the bundle itself)
+    pub components: BTreeMap<String, ComponentSettings>,
+}
+
+impl DeepSigningConfig {
+    /// Read the components from a property list: a dictionary keyed by
+    /// component path whose values may hold an `identifier` string and an
+    /// `entitlements` dictionary
+    pub fn parse(data: &[u8]) -> Result<DeepSigningConfig> {
+        let mut components = BTreeMap::new();
+        for (path, value) in parse_dictionary(data)?.iter() {
+            let dict = match value.as_dictionary() {
+                Some(dict) => dict,
+                None => {
+                    return Err(
+                        UnexpectedPlist(format!("bad component settings for {}", path)).into(),
+                    )
+                }
+            };
+            let (entitlements, der_entitlements) = match dict.get("entitlements") {
+                Some(&Value::Dictionary(ref entitlements)) => {
+                    let mut buf = vec![];
+                    Value::Dictionary(entitlements.clone()).to_writer_xml(&mut buf)?;
+                    (Some(buf), Some(encode_der_entitlements(entitlements)?))
+                }
+                Some(_) => {
+                    return Err(UnexpectedPlist(format!("bad entitlements for {}", path)).into())
+                }
+                None => (None, None),
+            };
+            components.insert(
+                path.clone(),
+                ComponentSettings {
+                    identifier: dict
+                        .get("identifier")
+                        .and_then(Value::as_string)
+                        .map(|s| s.to_string()),
+                    entitlements,
+                    der_entitlements,
+                },
+            );
+        }
+        Ok(DeepSigningConfig {
+            components,
+            ..Default::default()
+        })
+    }
+
+    fn settings_for(&self, relative: &str, default_identifier: &str) -> Result<SigningSettings> {
+        let component = self.components.get(relative).cloned().unwrap_or_default();
+        let der_entitlements = match component.entitlements {
+            Some(ref xml) if component.der_entitlements.is_none() => {
+                Some(encode_der_entitlements(&parse_dictionary(xml)?)?)
+            }
+            _ => component.der_entitlements,
+        };
+        Ok(SigningSettings {
+            identifier: component
+                .identifier
+                .unwrap_or_else(|| default_identifier.to_string()),
+            entitlements: component.entitlements,
+            der_entitlements,
+            ..self.settings.clone()
+        })
+    }
+}
+
+/// Sign a bundle (or a lone Mach-O file) inside out: bare Mach-O
+/// files (dylibs, helper tools) first, then nested bundles, each one after its
+/// own nested code, and the main executable last, so every CodeResources
+/// seals the cdhashes of the signatures just made.
+///
+/// Returns the path (relative to `path`) and cdhash of every component, in
+/// signing order. Executables must be thin images (see `sign_macho`).
+pub fn sign_deep(path: &Path, config: &DeepSigningConfig) -> Result<Vec<(String, String)>> {
+    let mut signed = vec![];
+    sign_component(path, path, config, &mut signed)?;
+    Ok(signed)
+}
+
+fn sign_component(
+    root: &Path,
+    path: &Path,
+    config: &DeepSigningConfig,
+    signed: &mut Vec<(String, String)>,
+) -> Result<()> {
+    let relative = path
+        .strip_prefix(root)
+        .map(|p| p.to_string_lossy().replace('\\', "/"))
+        .unwrap_or_default();
+
+    if Bundle::is_bundle(path) {
+        let bundle = Bundle::open(path)?;
+        let mut nested = find_nested_code(&bundle)?;
+        // stable: bare files first, bundles after, each in directory order
+        nested.sort_by_key(|nested| bundle.contents.join(nested).is_dir());
+        for nested in nested {
+            sign_component(root, &bundle.contents.join(nested), config, signed)?;
+        }
+        sign_bundle(&bundle, &config.settings_for(&relative, "")?)?;
+    } else {
+        let name = path
+            .file_stem()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let mut macho = vec![];
+        File::open(path)?.read_to_end(&mut macho)?;
+        sign_macho(&config.settings_for(&relative, &name)?, &mut macho)?;
+        File::create(path)?.write_all(&macho)?;
+    }
+
+    let cdhash = nested_seal(path)?.cdhash.unwrap_or_default();
+    signed.push((relative, hex::encode(cdhash)));
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use bundle::tests::info_plist;
+    use bundle::Bundle;
+    use codedir::{Blob, CodeSignature};
+    use deep::{sign_deep, DeepSigningConfig};
+    use der::encode_der_entitlements;
+    use hex;
+    use plist::{Dictionary, Value};
+    use resources::CodeResources;
+    use signer::tests::fake_macho;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_sign_deep() {
+        let dir = TempDir::new().unwrap();
+        let contents = dir.path().join("Foo.app/Contents");
+        let framework = contents.join("Frameworks/Bar.framework");
+        let plugin = contents.join("PlugIns/Ext.appex/Contents");
+        fs::create_dir_all(contents.join("MacOS")).unwrap();
+        fs::create_dir_all(&framework).unwrap();
+        fs::create_dir_all(plugin.join("MacOS")).unwrap();
+        fs::write(
+            contents.join("Info.plist"),
+            info_plist("Foo", "com.example.foo"),
+        )
+        .unwrap();
+        fs::write(contents.join("MacOS/Foo"), fake_macho(0x2000)).unwrap();
+        fs::write(
+            framework.join("Info.plist"),
+            info_plist("Bar", "com.example.bar"),
+        )
+        .unwrap();
+        fs::write(framework.join("Bar"), fake_macho(0x1000)).unwrap();
+        fs::write(
+            plugin.join("Info.plist"),
+            info_plist("Ext", "com.example.ext"),
+        )
+        .unwrap();
+        fs::write(plugin.join("MacOS/Ext"), fake_macho(0x1000)).unwrap();
+
+        let config = DeepSigningConfig::parse(
+            br#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0"><dict>
+  <key>Contents/PlugIns/Ext.appex</key>
+  <dict>
+    <key>entitlements</key>
+    <dict><key>com.apple.security.app-sandbox</key><true/></dict>
+  </dict>
+</dict></plist>"#,
+        )
+        .unwrap();
+        let app = dir.path().join("Foo.app");
+        let signed = sign_deep(&app, &config).unwrap();
+
+        // children first, the app last
+        let order: Vec<&str> = signed.iter().map(|&(ref path, _)| path.as_str()).collect();
+        assert_eq!(
+            order,
+            vec![
+                "Contents/Frameworks/Bar.framework",
+                "Contents/PlugIns/Ext.appex",
+                "",
+            ]
+        );
+        // and the app seals the signatures its children ended up with
+        let resources = CodeResources::open(&Bundle::open(&app).unwrap()).unwrap();
+        for &(ref path, ref cdhash) in &signed[..2] {
+            let seal = &resources.files2[&path["Contents/".len()..]];
+            assert_eq!(seal.cdhash.as_ref().map(hex::encode), Some(cdhash.clone()));
+        }
+
+        // entitlements are signed as XML and as DER
+        let mut entitlements = Dictionary::new();
+        entitlements.insert(
+            "com.apple.security.app-sandbox".to_string(),
+            Value::Boolean(true),
+        );
+        let signatures =
+            CodeSignature::parse_image(None, &fs::read(plugin.join("MacOS/Ext")).unwrap()).unwrap();
+        let der = signatures[0]
+            .blobs
+            .iter()
+            .flat_map(|blobs| blobs.iter())
+            .find_map(|blob| match blob {
+                Blob::DerEntitlements {
+                    ref der_entitlements,
+                    ..
+                } => Some(der_entitlements.clone()),
+                _ => None,
+            });
+        assert_eq!(der, Some(encode_der_entitlements(&entitlements).unwrap()));
+    }
+}
@@ -0,0 +1,343 @@
+#![allow(dead_code)]
+
+use plist::{Dictionary, Value};
+
+use errors::{CDMachError::*, Result};
+
+pub const TAG_BOOLEAN: u8 = 0x01;
+pub const TAG_INTEGER: u8 = 0x02;
+pub const TAG_BIT_STRING: u8 = 0x03;
+pub const TAG_OCTET_STRING: u8 = 0x04;
+pub const TAG_NULL: u8 = 0x05;
+pub const TAG_OID: u8 = 0x06;
+pub const TAG_UTF8_STRING: u8 = 0x0c;
+pub const TAG_PRINTABLE_STRING: u8 = 0x13;
+pub const TAG_IA5_STRING: u8 = 0x16;
+pub const TAG_UTC_TIME: u8 = 0x17;
+pub const TAG_GENERALIZED_TIME: u8 = 0x18;
+pub const TAG_SEQUENCE: u8 = 0x30;
+pub const TAG_SET: u8 = 0x31;
+
+/// constructed bit of an identifier octet
+pub const CONSTRUCTED: u8 = 0x20;
+/// context specific, constructed `[n]`
+pub const CONTEXT_0: u8 = 0xa0;
+pub const CONTEXT_1: u8 = 0xa1;
+pub const CONTEXT_3: u8 = 0xa3;
+/// `[APPLICATION 16]` and `[16]`, constructed, of DER entitlements
+pub const DER_ENTITLEMENTS_TAG: u8 = 0x70;
+pub const DER_DICTIONARY_TAG: u8 = 0xb0;
+
+/// 1.2.840.113549.1.7.1
+pub const OID_DATA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x01];
+/// 1.2.840.113549.1.7.2
+pub const OID_SIGNED_DATA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x02];
+/// 1.2.840.113549.1.1.1
+pub const OID_RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+/// 1.2.840.113549.1.1.5
+pub const OID_SHA1_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x05];
+/// 1.2.840.113549.1.1.11
+pub const OID_SHA256_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b];
+/// 1.2.840.113549.1.1.12
+pub const OID_SHA384_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0c];
+/// 1.2.840.113549.1.1.13
+pub const OID_SHA512_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0d];
+/// 1.2.840.113549.1.9.3
+pub const OID_CONTENT_TYPE: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x03];
+/// 1.2.840.113549.1.9.4
+pub const OID_MESSAGE_DIGEST: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x04];
+/// 1.2.840.113635.100.9.1, the cdhashes plist of a code signature
+pub const OID_APPLE_CDHASHES: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x63, 0x64, 0x09, 0x01];
+/// 1.2.840.113635.100.6.1.18, marks Developer ID certificates allowed to
+/// sign kernel extensions
+pub const OID_APPLE_DEVELOPER_ID_KEXT: &[u8] =
+    &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x63, 0x64, 0x06, 0x01, 0x12];
+/// 1.3.14.3.2.26
+pub const OID_SHA1: &[u8] = &[0x2b, 0x0e, 0x03, 0x02, 0x1a];
+/// 2.16.840.1.101.3.4.2.1
+pub const OID_SHA256: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+/// 2.16.840.1.101.3.4.2.2
+pub const OID_SHA384: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x02];
+/// 2.16.840.1.101.3.4.2.3
+pub const OID_SHA512: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03];
+/// 2.5.4.3
+pub const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+/// 2.5.4.11
+pub const OID_ORGANIZATIONAL_UNIT: &[u8] = &[0x55, 0x04, 0x0b];
+
+/// One BER/DER element: tag, contents and the whole encoding.
+///
+/// Only single byte tags are supported; indefinite lengths (used by Apple's
+/// CMS encoder) are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tlv<'a> {
+    pub tag: u8,
+    /// contents octets; for indefinite lengths, without the end-of-contents
+    pub contents: &'a [u8],
+    /// identifier, length and contents octets
+    pub raw: &'a [u8],
+}
+
+impl<'a> Tlv<'a> {
+    /// Parse the element at the start of `data`, returning it and what follows
+    pub fn parse(data: &'a [u8]) -> Result<(Tlv<'a>, &'a [u8])> {
+        if data.len() < 2 {
+            return Err(BadDer("truncated element".to_string()).into());
+        }
+        let tag = data[0];
+        if tag & 0x1f == 0x1f {
+            return Err(BadDer(format!("multi-byte tag 0x{:x}", tag)).into());
+        }
+        let (length, header) = match data[1] {
+            n if n < 0x80 => (Some(n as usize), 2),
+            0x80 => (None, 2),
+            n => {
+                let count = (n & 0x7f) as usize;
+                if count > 4 || data.len() < 2 + count {
+                    return Err(BadDer("bad length".to_string()).into());
+                }
+                let length = data[2..2 + count]
+                    .iter()
+                    .fold(0usize, |length, &b| length << 8 | b as usize);
+                (Some(length), 2 + count)
+            }
+        };
+
+        match length {
+            Some(length) => {
+                if data.len() - header < length {
+                    return Err(BadDer("truncated contents".to_string()).into());
+                }
+                let end = header + length;
+                Ok((
+                    Tlv {
+                        tag,
+                        contents: &data[header..end],
+                        raw: &data[..end],
+                    },
+                    &data[end..],
+                ))
+            }
+            None => {
+                if tag & CONSTRUCTED == 0 {
+                    return Err(BadDer("indefinite length primitive".to_string()).into());
+                }
+                let mut rest = &data[header..];
+                while !rest.starts_with(&[0, 0]) {
+                    rest = Tlv::parse(rest)?.1;
+                }
+                let end = data.len() - rest.len();
+                Ok((
+                    Tlv {
+                        tag,
+                        contents: &data[header..end],
+                        raw: &data[..end + 2],
+                    },
+                    &rest[2..],
+                ))
+            }
+        }
+    }
+
+    /// Parse `data` as a single element, ignoring trailing bytes
+    pub fn from_bytes(data: &'a [u8]) -> Result<Tlv<'a>> {
+        Ok(Tlv::parse(data)?.0)
+    }
+
+    /// The elements of a constructed element
+    pub fn children(&self) -> Result<Vec<Tlv<'a>>> {
+        let mut children = vec![];
+        let mut rest = self.contents;
+        while !rest.is_empty() {
+            let (child, next) = Tlv::parse(rest)?;
+            children.push(child);
+            rest = next;
+        }
+        Ok(children)
+    }
+
+    /// The elements of a constructed element with tag `tag`
+    pub fn expect(&self, tag: u8) -> Result<Vec<Tlv<'a>>> {
+        if self.tag != tag {
+            return Err(BadDer(format!("expected tag 0x{:x}, got 0x{:x}", tag, self.tag)).into());
+        }
+        self.children()
+    }
+
+    /// Contents of an octet string, joining the segments of a constructed one
+    pub fn octets(&self) -> Result<Vec<u8>> {
+        match self.tag {
+            TAG_OCTET_STRING => Ok(self.contents.to_vec()),
+            tag if tag == TAG_OCTET_STRING | CONSTRUCTED => {
+                let mut octets = vec![];
+                for child in self.children()? {
+                    octets.extend(child.octets()?);
+                }
+                Ok(octets)
+            }
+            tag => Err(BadDer(format!("expected an octet string, got 0x{:x}", tag)).into()),
+        }
+    }
+
+    /// Contents of a string element, whatever its character set
+    pub fn string(&self) -> String {
+        String::from_utf8_lossy(self.contents).to_string()
+    }
+}
+
+/// Encode a length
+fn encode_length(length: usize, out: &mut Vec<u8>) {
+    if length < 0x80 {
+        out.push(length as u8);
+        return;
+    }
+    let bytes: Vec<u8> = (0..4)
+        .rev()
+        .map(|i| (length >> (8 * i)) as u8)
+        .skip_while(|&b| b == 0)
+        .collect();
+    out.push(0x80 | bytes.len() as u8);
+    out.extend(bytes);
+}
+
+/// DER encode an element
+pub fn encode(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    encode_length(contents.len(), &mut out);
+    out.extend_from_slice(contents);
+    out
+}
+
+/// DER encode a constructed element from encoded children
+pub fn encode_constructed(tag: u8, children: &[Vec<u8>]) -> Vec<u8> {
+    encode(tag, &children.concat())
+}
+
+/// DER encode an INTEGER
+pub fn encode_integer(value: i64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    // drop leading bytes that only repeat the sign
+    let mut start = 0;
+    while start < 7
+        && ((bytes[start] == 0 && bytes[start + 1] & 0x80 == 0)
+            || (bytes[start] == 0xff && bytes[start + 1] & 0x80 != 0))
+    {
+        start += 1;
+    }
+    encode(TAG_INTEGER, &bytes[start..])
+}
+
+/// DER encode an AlgorithmIdentifier without parameters (a NULL)
+pub fn encode_algorithm(oid: &[u8]) -> Vec<u8> {
+    encode_constructed(TAG_SEQUENCE, &[encode(TAG_OID, oid), encode(TAG_NULL, &[])])
+}
+
+/// DER encode a SET OF, sorting the encoded elements
+pub fn encode_set(mut children: Vec<Vec<u8>>) -> Vec<u8> {
+    children.sort();
+    encode_constructed(TAG_SET, &children)
+}
+
+/// Encode entitlements the way the `CSSLOT_DER_ENTITLEMENTS` blob holds
+/// them: `[APPLICATION 16] { 1, [16] { { key, value }... } }`, the pairs of
+/// each dictionary sorted by key
+pub fn encode_der_entitlements(entitlements: &Dictionary) -> Result<Vec<u8>> {
+    Ok(encode_constructed(
+        DER_ENTITLEMENTS_TAG,
+        &[
+            encode_integer(1),
+            encode_der_value(&Value::Dictionary(entitlements.clone()))?,
+        ],
+    ))
+}
+
+fn encode_der_value(value: &Value) -> Result<Vec<u8>> {
+    Ok(match *value {
+        Value::Boolean(b) => encode(TAG_BOOLEAN, &[if b { 0xff } else { 0 }]),
+        Value::Integer(i) => match i.as_signed() {
+            Some(i) => encode_integer(i),
+            None => {
+                return Err(UnexpectedPlist("entitlement integer out of range".to_string()).into())
+            }
+        },
+        Value::String(ref s) => encode(TAG_UTF8_STRING, s.as_bytes()),
+        Value::Data(ref data) => encode(TAG_OCTET_STRING, data),
+        Value::Array(ref values) => {
+            let values: Result<Vec<Vec<u8>>> = values.iter().map(encode_der_value).collect();
+            encode_constructed(TAG_SEQUENCE, &values?)
+        }
+        Value::Dictionary(ref dict) => {
+            let mut keys: Vec<&String> = dict.keys().collect();
+            keys.sort();
+            let mut pairs = vec![];
+            for key in keys {
+                pairs.push(encode_constructed(
+                    TAG_SEQUENCE,
+                    &[
+                        encode(TAG_UTF8_STRING, key.as_bytes()),
+                        encode_der_value(&dict[key])?,
+                    ],
+                ));
+            }
+            encode_constructed(DER_DICTIONARY_TAG, &pairs)
+        }
+        _ => return Err(UnexpectedPlist("unsupported entitlement value".to_string()).into()),
+    })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use der::{
+        encode, encode_constructed, encode_der_entitlements, Tlv, TAG_INTEGER, TAG_OCTET_STRING,
+        TAG_SEQUENCE,
+    };
+    use plist::{Dictionary, Value};
+
+    #[test]
+    fn test_indefinite_length() {
+        // SEQUENCE (indefinite) { INTEGER 5, OCTET STRING (constructed) { "ab", "c" } }
+        let ber = [
+            0x30, 0x80, 0x02, 0x01, 0x05, 0x24, 0x80, 0x04, 0x02, b'a', b'b', 0x04, 0x01, b'c',
+            0x00, 0x00, 0x00, 0x00, 0xff,
+        ];
+        let (seq, rest) = Tlv::parse(&ber).unwrap();
+        assert_eq!(rest, &[0xff]);
+        let children = seq.expect(TAG_SEQUENCE).unwrap();
+        assert_eq!(children[0].contents, &[5]);
+        assert_eq!(children[1].octets().unwrap(), b"abc");
+
+        let der = encode_constructed(
+            TAG_SEQUENCE,
+            &[
+                encode(TAG_INTEGER, &[5]),
+                encode(TAG_OCTET_STRING, &[0; 200]),
+            ],
+        );
+        assert_eq!(&der[..4], &[0x30, 0x81, 206, 0x02]);
+        let seq = Tlv::from_bytes(&der).unwrap();
+        assert_eq!(seq.children().unwrap()[1].contents.len(), 200);
+    }
+
+    #[test]
+    fn test_der_entitlements() {
+        // inserted out of order: the encoding sorts them
+        let mut entitlements = Dictionary::new();
+        entitlements.insert(
+            "keychain-access-groups".to_string(),
+            Value::Array(vec![Value::String("ABCDE12345.app".to_string())]),
+        );
+        entitlements.insert("get-task-allow".to_string(), Value::Boolean(true));
+        let der = encode_der_entitlements(&entitlements).unwrap();
+
+        // [APPLICATION 16] { 1, [16] { {key, true}, {key, {string}} } }
+        let mut expected = vec![0x70, 0x46, 0x02, 0x01, 0x01, 0xb0, 0x41];
+        expected.extend_from_slice(&[0x30, 0x13, 0x0c, 0x0e]);
+        expected.extend_from_slice(b"get-task-allow");
+        expected.extend_from_slice(&[0x01, 0x01, 0xff]);
+        expected.extend_from_slice(&[0x30, 0x2a, 0x0c, 0x16]);
+        expected.extend_from_slice(b"keychain-access-groups");
+        expected.extend_from_slice(&[0x30, 0x10, 0x0c, 0x0e]);
+        expected.extend_from_slice(b"ABCDE12345.app");
+        assert_eq!(der, expected);
+    }
+}
@@ -1,5 +1,5 @@
 use codedir::{CodeSignature, SuperBlob};
-use consts::CSMAGIC_EMBEDDED_SIGNATURE;
+use consts::{CSMAGIC_DETACHED_SIGNATURE, CSMAGIC_EMBEDDED_SIGNATURE};
 use std::fmt;
 
 impl fmt::Display for SuperBlob {
@@ -10,7 +10,16 @@ impl fmt::Display for SuperBlob {
                 "is an embedded signature of {} bytes, and {} blobs",
                 self.length, self.count
             ),
-            _ => unimplemented!(),
+            CSMAGIC_DETACHED_SIGNATURE => write!(
+                f,
+                "is a detached signature of {} bytes, for {} architectures",
+                self.length, self.count
+            ),
+            magic => write!(
+                f,
+                "is a superblob with magic 0x{:08x} of {} bytes, and {} blobs",
+                magic, self.length, self.count
+            ),
         }
     }
 }
@@ -63,3 +72,27 @@ impl fmt::Display for CodeSignature {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use codedir::SuperBlob;
+    use consts::CSMAGIC_DETACHED_SIGNATURE;
+
+    #[test]
+    fn test_display_super_blob() {
+        let sb = |magic| SuperBlob {
+            magic,
+            length: 100,
+            count: 2,
+            index: vec![],
+        };
+        assert_eq!(
+            sb(CSMAGIC_DETACHED_SIGNATURE).to_string(),
+            "is a detached signature of 100 bytes, for 2 architectures"
+        );
+        assert_eq!(
+            sb(0xfade0cc2).to_string(),
+            "is a superblob with magic 0xfade0cc2 of 100 bytes, and 2 blobs"
+        );
+    }
+}
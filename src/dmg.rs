@@ -0,0 +1,181 @@
+#![allow(dead_code)]
+
+use byteorder::{ByteOrder, NetworkEndian};
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use errors::{CDMachError::*, Result};
+
+use codedir::CodeSignature;
+use consts::CSSLOT_REP_SPECIFIC;
+use verify::VerificationReport;
+
+/// Magic of the UDIF trailer
+pub const KOLY_MAGIC: &[u8] = b"koly";
+/// Size of the UDIF trailer ending every disk image
+pub const KOLY_SIZE: usize = 512;
+/// Offsets of the code signature fields in the trailer
+const KOLY_CODE_SIGNATURE_OFFSET: usize = 296;
+const KOLY_CODE_SIGNATURE_SIZE: usize = 304;
+
+/// The `koly` trailer of a UDIF disk image (all fields big endian)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KolyTrailer {
+    pub version: u32,
+    pub data_fork_offset: u64,
+    pub data_fork_length: u64,
+    pub rsrc_fork_offset: u64,
+    pub rsrc_fork_length: u64,
+    /// offset and length of the XML resource plist
+    pub plist_offset: u64,
+    pub plist_length: u64,
+    /// offset and size of the embedded signature SuperBlob; zero when unsigned
+    pub code_signature_offset: u64,
+    pub code_signature_size: u64,
+    /// the whole trailer
+    pub raw: Vec<u8>,
+}
+
+impl KolyTrailer {
+    pub fn parse(raw: &[u8]) -> Result<KolyTrailer> {
+        if raw.len() != KOLY_SIZE || !raw.starts_with(KOLY_MAGIC) {
+            return Err(NotADiskImage("no koly trailer".to_string()).into());
+        }
+        let u64_at = |offset: usize| NetworkEndian::read_u64(&raw[offset..]);
+        Ok(KolyTrailer {
+            version: NetworkEndian::read_u32(&raw[4..]),
+            data_fork_offset: u64_at(24),
+            data_fork_length: u64_at(32),
+            rsrc_fork_offset: u64_at(40),
+            rsrc_fork_length: u64_at(48),
+            plist_offset: u64_at(216),
+            plist_length: u64_at(224),
+            code_signature_offset: u64_at(KOLY_CODE_SIGNATURE_OFFSET),
+            code_signature_size: u64_at(KOLY_CODE_SIGNATURE_SIZE),
+            raw: raw.to_vec(),
+        })
+    }
+
+    /// The trailer ending `data`
+    pub fn find(data: &[u8]) -> Result<KolyTrailer> {
+        if data.len() < KOLY_SIZE {
+            return Err(NotADiskImage("too short".to_string()).into());
+        }
+        KolyTrailer::parse(&data[data.len() - KOLY_SIZE..])
+    }
+
+    pub fn is_signed(&self) -> bool {
+        self.code_signature_offset != 0 && self.code_signature_size != 0
+    }
+
+    /// The trailer as sealed by `CSSLOT_REP_SPECIFIC`: its code signature
+    /// fields are filled in after signing, so they are hashed as zeros
+    pub fn sealed_bytes(&self) -> Vec<u8> {
+        let mut raw = self.raw.clone();
+        for b in &mut raw[KOLY_CODE_SIGNATURE_OFFSET..KOLY_CODE_SIGNATURE_SIZE + 8] {
+            *b = 0;
+        }
+        raw
+    }
+}
+
+/// Parse the embedded signature of a disk image; `None` when it is unsigned.
+///
+/// The signature follows the data it hashes, so page hashes are computed
+/// over the image up to the signature.
+pub fn parse_signature(data: &[u8]) -> Result<Option<CodeSignature>> {
+    let trailer = KolyTrailer::find(data)?;
+    if !trailer.is_signed() {
+        return Ok(None);
+    }
+    let end = trailer
+        .code_signature_offset
+        .checked_add(trailer.code_signature_size);
+    match end {
+        Some(end) if end <= (data.len() - KOLY_SIZE) as u64 => {}
+        _ => return Err(NotADiskImage("code signature past the trailer".to_string()).into()),
+    }
+    CodeSignature::parse_appended(
+        data,
+        trailer.code_signature_offset,
+        trailer.code_signature_size,
+    )
+}
+
+/// Verify the signature of the disk image at `path`
+pub fn verify_dmg(path: &Path) -> Result<VerificationReport> {
+    let mut data = vec![];
+    File::open(path)?.read_to_end(&mut data)?;
+    verify_dmg_data(path, &data)
+}
+
+/// Verify a disk image: the hashes of the image data, the trailer sealed in
+/// `CSSLOT_REP_SPECIFIC`
+pub fn verify_dmg_data(path: &Path, data: &[u8]) -> Result<VerificationReport> {
+    let mut report = VerificationReport::new(path);
+    let signatures: Vec<CodeSignature> = parse_signature(data)?.into_iter().collect();
+    report.check_signatures(&signatures)?;
+    if let Some(cs) = signatures.first() {
+        let trailer = KolyTrailer::find(data)?;
+        match cs.verify_special_slot(CSSLOT_REP_SPECIFIC, &trailer.sealed_bytes())? {
+            Some(true) => {}
+            Some(false) => report.problem("koly trailer was modified".to_string()),
+            None => report.problem("signature does not seal the koly trailer".to_string()),
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use byteorder::{ByteOrder, NetworkEndian};
+    use consts::CSSLOT_REP_SPECIFIC;
+    use dmg::{parse_signature, verify_dmg_data, KolyTrailer, KOLY_MAGIC, KOLY_SIZE};
+    use signer::SigningSettings;
+    use std::path::Path;
+
+    #[test]
+    fn test_verify_dmg() {
+        let mut data: Vec<u8> = (0..0x3000u32).map(|i| i as u8).collect();
+        let mut koly = vec![0u8; KOLY_SIZE];
+        koly[..4].copy_from_slice(KOLY_MAGIC);
+        NetworkEndian::write_u32(&mut koly[4..], 4);
+        NetworkEndian::write_u64(&mut koly[32..], data.len() as u64);
+
+        let mut settings = SigningSettings {
+            identifier: "image".to_string(),
+            ..Default::default()
+        };
+        let sealed = KolyTrailer::parse(&koly).unwrap().sealed_bytes();
+        settings
+            .set_special_slot(CSSLOT_REP_SPECIFIC, &sealed)
+            .unwrap();
+        let signature = settings.build_signature(&data).unwrap();
+        NetworkEndian::write_u64(&mut koly[296..], data.len() as u64);
+        NetworkEndian::write_u64(&mut koly[304..], signature.len() as u64);
+        data.extend(signature);
+        data.extend(koly);
+
+        let report = verify_dmg_data(Path::new("a.dmg"), &data).unwrap();
+        assert!(report.is_valid(), "{:?}", report);
+        assert_eq!(report.identifier, Some("image".to_string()));
+
+        // image data
+        data[0x1234] ^= 1;
+        assert!(!verify_dmg_data(Path::new("a.dmg"), &data)
+            .unwrap()
+            .is_valid());
+        data[0x1234] ^= 1;
+        // trailer
+        let len = data.len();
+        data[len - KOLY_SIZE + 40] ^= 1;
+        assert!(!verify_dmg_data(Path::new("a.dmg"), &data)
+            .unwrap()
+            .is_valid());
+        // a signature range wrapping around
+        NetworkEndian::write_u64(&mut data[len - KOLY_SIZE + 296..], u64::MAX);
+        assert!(parse_signature(&data).is_err());
+    }
+}
@@ -0,0 +1,325 @@
+#![allow(dead_code)]
+
+use byteorder::{ByteOrder, LittleEndian};
+use hex;
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str;
+
+use errors::{CDMachError::*, Result};
+
+use codedir::CodeSignature;
+use verify::VerificationReport;
+
+/// Start of the magic of every dyld shared cache, e.g. `dyld_v1  arm64e`
+pub const DYLD_CACHE_MAGIC_PREFIX: &[u8] = b"dyld_v1";
+/// Header fields, little endian
+const MAPPING_OFFSET: usize = 16;
+const CODE_SIGNATURE_OFFSET: usize = 40;
+const CODE_SIGNATURE_SIZE: usize = 48;
+const UUID: usize = 88;
+const SUB_CACHE_ARRAY_OFFSET: usize = 392;
+const SUB_CACHE_ARRAY_COUNT: usize = 396;
+const SYMBOL_FILE_UUID: usize = 400;
+/// Offset of `cacheSubType`: headers ending before it list sub-caches
+/// without a file suffix
+const CACHE_SUB_TYPE: u32 = 456;
+/// Size of a sub-cache entry: uuid and VM offset, then a file suffix in the
+/// current format
+const SUB_CACHE_ENTRY_V1_SIZE: usize = 24;
+const SUB_CACHE_ENTRY_SIZE: usize = 56;
+/// Suffix of the file holding the local symbols of a split cache
+pub const SYMBOLS_SUFFIX: &str = ".symbols";
+
+/// A file of a split cache, named after the main cache plus `file_suffix`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubCache {
+    /// UUID the sub-cache's own header must carry
+    pub uuid: Vec<u8>,
+    pub vm_offset: u64,
+    /// e.g. `.1` or `.01`
+    pub file_suffix: String,
+}
+
+/// The parts of a `dyld_cache_header` needed to find its code signature and
+/// its sub-caches
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DyldCacheHeader {
+    pub magic: String,
+    /// size of the header, which grew with each format revision
+    pub mapping_offset: u32,
+    pub code_signature_offset: u64,
+    pub code_signature_size: u64,
+    pub uuid: Vec<u8>,
+    pub sub_caches: Vec<SubCache>,
+    /// UUID of the `.symbols` file, all zeros without one
+    pub symbol_file_uuid: Vec<u8>,
+}
+
+impl DyldCacheHeader {
+    pub fn is_dyld_cache(data: &[u8]) -> bool {
+        data.starts_with(DYLD_CACHE_MAGIC_PREFIX)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<DyldCacheHeader> {
+        if !DyldCacheHeader::is_dyld_cache(data) || data.len() < UUID + 16 {
+            return Err(BadDyldCache("no dyld cache magic".to_string()).into());
+        }
+        let magic = str::from_utf8(&data[..16])?
+            .trim_end_matches('\0')
+            .to_string();
+        let mapping_offset = LittleEndian::read_u32(&data[MAPPING_OFFSET..]);
+        // fields past the header belong to the mappings
+        let field = |offset: usize, size: usize| {
+            if offset + size <= mapping_offset as usize && offset + size <= data.len() {
+                Some(&data[offset..offset + size])
+            } else {
+                None
+            }
+        };
+
+        let mut sub_caches = vec![];
+        if let (Some(array), Some(count)) = (
+            field(SUB_CACHE_ARRAY_OFFSET, 4),
+            field(SUB_CACHE_ARRAY_COUNT, 4),
+        ) {
+            let array = LittleEndian::read_u32(array) as usize;
+            let count = LittleEndian::read_u32(count) as usize;
+            let entry_size = if mapping_offset <= CACHE_SUB_TYPE {
+                SUB_CACHE_ENTRY_V1_SIZE
+            } else {
+                SUB_CACHE_ENTRY_SIZE
+            };
+            if array + count * entry_size > data.len() {
+                return Err(BadDyldCache("sub-cache array past the end".to_string()).into());
+            }
+            for (i, entry) in data[array..array + count * entry_size]
+                .chunks(entry_size)
+                .enumerate()
+            {
+                let file_suffix = if entry_size == SUB_CACHE_ENTRY_V1_SIZE {
+                    format!(".{}", i + 1)
+                } else {
+                    let suffix = &entry[24..];
+                    let end = suffix.iter().position(|&b| b == 0).unwrap_or(suffix.len());
+                    str::from_utf8(&suffix[..end])?.to_string()
+                };
+                sub_caches.push(SubCache {
+                    uuid: entry[..16].to_vec(),
+                    vm_offset: LittleEndian::read_u64(&entry[16..]),
+                    file_suffix,
+                });
+            }
+        }
+
+        Ok(DyldCacheHeader {
+            magic,
+            mapping_offset,
+            code_signature_offset: LittleEndian::read_u64(&data[CODE_SIGNATURE_OFFSET..]),
+            code_signature_size: LittleEndian::read_u64(&data[CODE_SIGNATURE_SIZE..]),
+            uuid: data[UUID..UUID + 16].to_vec(),
+            sub_caches,
+            symbol_file_uuid: field(SYMBOL_FILE_UUID, 16)
+                .map(|uuid| uuid.to_vec())
+                .unwrap_or_else(|| vec![0; 16]),
+        })
+    }
+
+    /// Sub-cache files, `.symbols` included, and the UUID each must carry
+    pub fn sub_cache_files(&self, path: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+        let mut files: Vec<(PathBuf, Vec<u8>)> = self
+            .sub_caches
+            .iter()
+            .map(|sub| (suffixed(path, &sub.file_suffix), sub.uuid.clone()))
+            .collect();
+        if self.symbol_file_uuid.iter().any(|&b| b != 0) {
+            files.push((
+                suffixed(path, SYMBOLS_SUFFIX),
+                self.symbol_file_uuid.clone(),
+            ));
+        }
+        files
+    }
+}
+
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Parse the code signature of a cache file, which hashes the file up to
+/// the signature; `None` when it has none
+pub fn parse_signature(data: &[u8]) -> Result<Option<CodeSignature>> {
+    let header = DyldCacheHeader::parse(data)?;
+    if header.code_signature_offset == 0 || header.code_signature_size == 0 {
+        return Ok(None);
+    }
+    CodeSignature::parse_appended(
+        data,
+        header.code_signature_offset,
+        header.code_signature_size,
+    )
+}
+
+/// Verify the dyld shared cache at `path` and each of its sub-caches
+pub fn verify_dyld_cache(path: &Path) -> Result<VerificationReport> {
+    let mut data = vec![];
+    File::open(path)?.read_to_end(&mut data)?;
+    verify_dyld_cache_data(path, &data)
+}
+
+/// Verify the page hashes of a main cache file, read from `path`, then of
+/// the sub-caches next to it, reported as children
+pub fn verify_dyld_cache_data(path: &Path, data: &[u8]) -> Result<VerificationReport> {
+    let header = DyldCacheHeader::parse(data)?;
+    let mut report = verify_cache_file(path, data)?;
+    for (sub_path, uuid) in header.sub_cache_files(path) {
+        if !sub_path.is_file() {
+            report.problem(format!("sub-cache {} is missing", sub_path.display()));
+            continue;
+        }
+        let mut sub_data = vec![];
+        File::open(&sub_path)?.read_to_end(&mut sub_data)?;
+        let mut child = verify_cache_file(&sub_path, &sub_data)?;
+        if DyldCacheHeader::parse(&sub_data)?.uuid != uuid {
+            child.problem(format!(
+                "UUID does not match the main cache's {}",
+                hex::encode(&uuid)
+            ));
+        }
+        report.children.push(child);
+    }
+    Ok(report)
+}
+
+/// Verify the page hashes of a single cache file
+fn verify_cache_file(path: &Path, data: &[u8]) -> Result<VerificationReport> {
+    let mut report = VerificationReport::new(path);
+    let signatures: Vec<CodeSignature> = parse_signature(data)?.into_iter().collect();
+    report.check_signatures(&signatures)?;
+    Ok(report)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use byteorder::{ByteOrder, LittleEndian};
+    use dyld_cache::{verify_dyld_cache_data, DyldCacheHeader};
+    use signer::SigningSettings;
+    use std::fs;
+    use std::path::Path;
+    use tempfile::TempDir;
+    use verify::VerificationStatus;
+
+    /// A signed cache file of UUID `uuid` listing `sub_caches` as (uuid,
+    /// file suffix)
+    fn signed_cache(uuid: u8, sub_caches: &[(u8, &str)]) -> Vec<u8> {
+        let mut data = vec![0u8; 0x4000];
+        data[..16].copy_from_slice(b"dyld_v1  arm64e\0");
+        LittleEndian::write_u32(&mut data[16..], 0x200);
+        data[88..104].copy_from_slice(&[uuid; 16]);
+        LittleEndian::write_u32(&mut data[392..], 0x300);
+        LittleEndian::write_u32(&mut data[396..], sub_caches.len() as u32);
+        for (i, &(uuid, suffix)) in sub_caches.iter().enumerate() {
+            let entry = 0x300 + i * 56;
+            data[entry..entry + 16].copy_from_slice(&[uuid; 16]);
+            data[entry + 24..entry + 24 + suffix.len()].copy_from_slice(suffix.as_bytes());
+        }
+
+        let settings = SigningSettings {
+            identifier: "com.apple.dyld.cache.arm64e.release".to_string(),
+            ..Default::default()
+        };
+        LittleEndian::write_u64(&mut data[40..], 0x4000);
+        let signature = settings.build_signature(&data).unwrap();
+        LittleEndian::write_u64(&mut data[48..], signature.len() as u64);
+        let signature = settings.build_signature(&data).unwrap();
+        data.extend(signature);
+        data
+    }
+
+    #[test]
+    fn test_dyld_cache_header() {
+        let mut data = vec![0u8; 0x4000];
+        data[..16].copy_from_slice(b"dyld_v1  arm64e\0");
+        LittleEndian::write_u32(&mut data[16..], 0x200);
+        data[88..104].copy_from_slice(&[0x11; 16]);
+        LittleEndian::write_u32(&mut data[392..], 0x300);
+        LittleEndian::write_u32(&mut data[396..], 1);
+        data[0x300..0x310].copy_from_slice(&[0x22; 16]);
+        LittleEndian::write_u64(&mut data[0x310..], 0x8000_0000);
+        data[0x318..0x31b].copy_from_slice(b".01");
+
+        let header = DyldCacheHeader::parse(&data).unwrap();
+        assert_eq!(header.magic, "dyld_v1  arm64e");
+        assert_eq!(header.sub_caches.len(), 1);
+        assert_eq!(header.sub_caches[0].file_suffix, ".01");
+        assert_eq!(header.sub_caches[0].uuid, vec![0x22; 16]);
+        let files = header.sub_cache_files(Path::new("dyld_shared_cache_arm64e"));
+        assert_eq!(files[0].0, Path::new("dyld_shared_cache_arm64e.01"));
+
+        // no sub-caches, signed
+        LittleEndian::write_u32(&mut data[396..], 0);
+        let settings = SigningSettings {
+            identifier: "com.apple.dyld.cache.arm64e.release".to_string(),
+            ..Default::default()
+        };
+        LittleEndian::write_u64(&mut data[40..], 0x4000);
+        let signature = settings.build_signature(&data).unwrap();
+        LittleEndian::write_u64(&mut data[48..], signature.len() as u64);
+        // the size is hashed as well: sign again once it is set
+        data.truncate(0x4000);
+        let signature = settings.build_signature(&data).unwrap();
+        data.extend(signature);
+
+        let report = verify_dyld_cache_data(Path::new("cache"), &data).unwrap();
+        assert!(report.is_valid(), "{:?}", report);
+        data[0x1000] ^= 1;
+        let report = verify_dyld_cache_data(Path::new("cache"), &data).unwrap();
+        assert!(!report.is_valid());
+        // a signature range wrapping around
+        LittleEndian::write_u64(&mut data[40..], u64::MAX);
+        assert!(verify_dyld_cache_data(Path::new("cache"), &data).is_err());
+    }
+
+    #[test]
+    fn test_sub_caches() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("dyld_shared_cache_arm64e");
+        let main = signed_cache(0x11, &[(0x22, ".01")]);
+        let report = verify_dyld_cache_data(&path, &main).unwrap();
+        assert_eq!(
+            report.status,
+            VerificationStatus::Invalid(vec![format!(
+                "sub-cache {}.01 is missing",
+                path.display()
+            )])
+        );
+
+        fs::write(
+            dir.path().join("dyld_shared_cache_arm64e.01"),
+            signed_cache(0x22, &[]),
+        )
+        .unwrap();
+        let report = verify_dyld_cache_data(&path, &main).unwrap();
+        assert!(report.is_valid(), "{:?}", report);
+        assert_eq!(report.children.len(), 1);
+
+        fs::write(
+            dir.path().join("dyld_shared_cache_arm64e.01"),
+            signed_cache(0x33, &[]),
+        )
+        .unwrap();
+        let report = verify_dyld_cache_data(&path, &main).unwrap();
+        assert_eq!(report.status, VerificationStatus::Valid);
+        assert_eq!(
+            report.children[0].status,
+            VerificationStatus::Invalid(vec![format!(
+                "UUID does not match the main cache's {}",
+                "22".repeat(16)
+            )])
+        );
+    }
+}
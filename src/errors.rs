@@ -24,6 +24,90 @@ pub enum CDMachError {
 
     #[fail(display = "No Code Directory")]
     NoCodeDirectory,
+
+    #[fail(display = "unsupported hash type {}.", _0)]
+    UnsupportedHashType(u32),
+
+    #[fail(display = "unsupported page size {}.", _0)]
+    UnsupportedPageSize(u32),
+
+    #[fail(display = "too many code directories ({}).", _0)]
+    TooManyCodeDirectories(usize),
+
+    #[fail(display = "signature is not ad-hoc, patching would invalidate its CMS signature.")]
+    NotAdHoc,
+
+    #[fail(
+        display = "patched range {}..{} is not covered by the code limit.",
+        _0, _1
+    )]
+    PatchOutsideCodeLimit(u64, u64),
+
+    #[fail(display = "{} page hashes given for {} code slots.", _0, _1)]
+    PageHashCountMismatch(usize, u32),
+
+    #[fail(display = "{} page hash sets given for {} hash types.", _0, _1)]
+    HashTypeCountMismatch(usize, usize),
+
+    #[fail(display = "{} is not a bundle.", _0)]
+    NotABundle(String),
+
+    #[fail(display = "Info.plist has no {}.", _0)]
+    MissingInfoKey(String),
+
+    #[fail(display = "unexpected property list: {}.", _0)]
+    UnexpectedPlist(String),
+
+    #[fail(display = "bad blob length {}.", _0)]
+    BadBlobLength(u32),
+
+    #[fail(display = "unexpected blob magic 0x{:x}.", _0)]
+    BadMagic(u32),
+
+    #[fail(display = "unsupported mach-o image: {}.", _0)]
+    UnsupportedMachO(String),
+
+    #[fail(display = "No Code Signature")]
+    NoCodeSignature,
+
+    #[fail(display = "{} is not signed.", _0)]
+    UnsignedCode(String),
+
+    #[fail(display = "{} is missing.", _0)]
+    MissingFile(String),
+
+    #[fail(display = "malformed DER: {}.", _0)]
+    BadDer(String),
+
+    #[fail(display = "bad signing key: {}.", _0)]
+    BadSigningKey(String),
+
+    #[fail(display = "signing failed: {}.", _0)]
+    SigningFailed(String),
+
+    #[fail(display = "provisioning profile does not fit: {}.", _0)]
+    ProfileMismatch(String),
+
+    #[fail(display = "archive entry {} escapes the destination.", _0)]
+    UnexpectedArchivePath(String),
+
+    #[fail(display = "malformed ar archive: {}.", _0)]
+    BadArchive(String),
+
+    #[fail(display = "not a disk image: {}.", _0)]
+    NotADiskImage(String),
+
+    #[fail(display = "malformed xar archive: {}.", _0)]
+    BadXar(String),
+
+    #[fail(display = "malformed dyld shared cache: {}.", _0)]
+    BadDyldCache(String),
+
+    #[fail(display = "malformed code requirement: {}.", _0)]
+    BadRequirement(String),
+
+    #[fail(display = "no designated requirement: {}.", _0)]
+    NoDesignatedRequirement(String),
 }
 
 impl From<str::Utf8Error> for CDMachError {
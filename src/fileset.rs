@@ -0,0 +1,294 @@
+#![allow(dead_code)]
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use std::path::{Path, PathBuf};
+use std::str;
+
+use errors::{CDMachError::*, Result};
+
+use codedir::{image_file_type, min_cmdsize, Blob, CodeSignature};
+use mach_object::{LC_SEGMENT_64, MH_MAGIC_64};
+use verify::{VerificationReport, VerificationStatus};
+
+/// File type of kernel collections
+pub const MH_FILESET: u32 = 0xc;
+/// Load command of an image embedded in a fileset
+pub const LC_FILESET_ENTRY: u32 = 0x8000_0035;
+/// Size of a 64 bit Mach-O header
+const MACH_HEADER_64_SIZE: usize = 32;
+/// Size of a `fileset_entry_command` without its entry id
+const FILESET_ENTRY_SIZE: usize = 32;
+
+/// An image of a kernel collection, e.g. `com.apple.kernel` or a kext
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilesetEntry {
+    pub entry_id: String,
+    pub vmaddr: u64,
+    /// offset of the entry's Mach-O header in the collection
+    pub fileoff: u64,
+    /// name and file range of each of the entry's segments; their offsets
+    /// are relative to the collection, whose segments they interleave with
+    /// those of the other entries
+    pub segments: Vec<(String, u64, u64)>,
+}
+
+impl FilesetEntry {
+    /// Whether the byte at `offset` of the collection belongs to this entry
+    pub fn contains(&self, offset: u64) -> bool {
+        self.segments.iter().any(
+            |&(_, fileoff, filesize)| match fileoff.checked_add(filesize) {
+                Some(end) => offset >= fileoff && offset < end,
+                None => false,
+            },
+        )
+    }
+}
+
+pub fn is_fileset(data: &[u8]) -> bool {
+    image_file_type(data) == Some(MH_FILESET)
+}
+
+/// Load commands of a little endian 64 bit image at `offset` in `data`, as
+/// (command, offset of the command in `data`). Each command is checked to be
+/// large enough for the fields read from it.
+fn load_commands(data: &[u8], offset: usize) -> Result<Vec<(u32, usize)>> {
+    match offset.checked_add(MACH_HEADER_64_SIZE) {
+        Some(end)
+            if end <= data.len() && LittleEndian::read_u32(&data[offset..]) == MH_MAGIC_64 => {}
+        _ => return Err(UnsupportedMachO(format!("no 64 bit image at {}", offset)).into()),
+    }
+    let ncmds = LittleEndian::read_u32(&data[offset + 16..]);
+    let mut commands = vec![];
+    let mut cmd_offset = offset + MACH_HEADER_64_SIZE;
+    for _ in 0..ncmds {
+        if cmd_offset + 8 > data.len() {
+            return Err(UnsupportedMachO("truncated load commands".to_string()).into());
+        }
+        let cmd = LittleEndian::read_u32(&data[cmd_offset..]);
+        let cmdsize = LittleEndian::read_u32(&data[cmd_offset + 4..]) as usize;
+        let min_size = match cmd {
+            LC_FILESET_ENTRY => FILESET_ENTRY_SIZE,
+            cmd => min_cmdsize(cmd),
+        };
+        if cmdsize < min_size || cmd_offset + cmdsize > data.len() {
+            return Err(UnsupportedMachO(format!("bad load command size {}", cmdsize)).into());
+        }
+        commands.push((cmd, cmd_offset));
+        cmd_offset += cmdsize;
+    }
+    Ok(commands)
+}
+
+/// A NUL terminated string at `offset` in `data`
+fn c_string(data: &[u8], offset: usize) -> Result<String> {
+    let bytes = &data[offset.min(data.len())..];
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    Ok(str::from_utf8(&bytes[..end])?.to_string())
+}
+
+/// The entries of a thin, little endian `MH_FILESET` image, with the
+/// segments of each
+pub fn fileset_entries(data: &[u8]) -> Result<Vec<FilesetEntry>> {
+    if !is_fileset(data) {
+        return Err(UnsupportedMachO("not a fileset".to_string()).into());
+    }
+    let mut entries = vec![];
+    for (cmd, offset) in load_commands(data, 0)? {
+        if cmd != LC_FILESET_ENTRY {
+            continue;
+        }
+        // cmd, cmdsize, vmaddr, fileoff, entry_id (lc_str), reserved
+        let fileoff = LittleEndian::read_u64(&data[offset + 16..]);
+        let entry_id = c_string(
+            data,
+            offset + LittleEndian::read_u32(&data[offset + 24..]) as usize,
+        )?;
+        let mut segments = vec![];
+        for (cmd, segment) in load_commands(data, fileoff as usize)? {
+            if cmd == LC_SEGMENT_64 {
+                let name = c_string(&data[segment + 8..segment + 24], 0)?;
+                let segment_fileoff = LittleEndian::read_u64(&data[segment + 40..]);
+                let segment_filesize = LittleEndian::read_u64(&data[segment + 48..]);
+                if segment_fileoff.checked_add(segment_filesize).is_none() {
+                    return Err(UnsupportedMachO(format!("segment {} overflows", name)).into());
+                }
+                if segment_filesize != 0 {
+                    segments.push((name, segment_fileoff, segment_filesize));
+                }
+            }
+        }
+        entries.push(FilesetEntry {
+            entry_id,
+            vmaddr: LittleEndian::read_u64(&data[offset + 8..]),
+            fileoff,
+            segments,
+        });
+    }
+    Ok(entries)
+}
+
+/// File offsets of the pages whose hashes do not match, under any hash type
+fn mismatched_pages(cs: &CodeSignature) -> Vec<u64> {
+    let mut pages = vec![];
+    for blob in cs.blobs.iter().flat_map(|blobs| blobs.iter()) {
+        if let Blob::CodeDirectory {
+            ref code_directory,
+            cd_hashes: Ok(ref hashes),
+            computed_cd_hashes: Ok(ref computed),
+            ..
+        } = blob
+        {
+            let page_size = match code_directory.pageSize {
+                0 => code_directory.codeLimit as u64,
+                page_size => 1 << page_size,
+            };
+            let code = hashes.iter().filter(|&&(i, _)| i >= 0);
+            for (expected, computed) in code.zip(computed.iter()) {
+                if expected != computed {
+                    pages.push(expected.0 as u64 * page_size);
+                }
+            }
+        }
+    }
+    pages.sort();
+    pages.dedup();
+    pages
+}
+
+/// Verify a kernel collection read from `path`: its signature, then each
+/// entry, reported as `path(entry_id)` with the pages that do not match
+/// attributed to the entries whose segments hold them
+pub fn verify_fileset(path: &Path, data: &[u8]) -> Result<VerificationReport> {
+    let mut report = VerificationReport::new(path);
+    let signatures = CodeSignature::parse_image(None, data)?;
+    report.check_signatures(&signatures)?;
+    let mismatched: Vec<u64> = signatures.iter().flat_map(mismatched_pages).collect();
+    for entry in fileset_entries(data)? {
+        let mut child = VerificationReport::new(&PathBuf::from(format!(
+            "{}({})",
+            path.display(),
+            entry.entry_id
+        )));
+        child.identifier = Some(entry.entry_id.clone());
+        if signatures.is_empty() {
+            child.status = VerificationStatus::Unsigned;
+        }
+        for &page in mismatched.iter().filter(|&&page| entry.contains(page)) {
+            child.problem(format!("page at 0x{:x} does not match", page));
+        }
+        report.children.push(child);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use byteorder::{ByteOrder, LittleEndian};
+    use fileset::{fileset_entries, verify_fileset, LC_FILESET_ENTRY, MH_FILESET};
+    use mach_object::{LC_CODE_SIGNATURE, LC_SEGMENT_64, MH_KEXT_BUNDLE, MH_MAGIC_64};
+    use signer::{sign_macho, SigningSettings};
+    use std::path::Path;
+
+    /// A little endian 64 bit Mach-O header of type `filetype` followed by
+    /// `commands`
+    fn image(filetype: u32, commands: &[Vec<u8>]) -> Vec<u8> {
+        let mut header = vec![0; 32];
+        LittleEndian::write_u32(&mut header[0..], MH_MAGIC_64);
+        LittleEndian::write_u32(&mut header[12..], filetype);
+        LittleEndian::write_u32(&mut header[16..], commands.len() as u32);
+        let sizeofcmds = commands.iter().map(|cmd| cmd.len()).sum::<usize>();
+        LittleEndian::write_u32(&mut header[20..], sizeofcmds as u32);
+        header.extend(commands.iter().flat_map(|cmd| cmd.iter().cloned()));
+        header
+    }
+
+    /// An LC_SEGMENT_64 command mapping `filesize` bytes at `fileoff`
+    fn segment(name: &str, fileoff: u64, filesize: u64) -> Vec<u8> {
+        let mut cmd = vec![0; 72];
+        LittleEndian::write_u32(&mut cmd[0..], LC_SEGMENT_64);
+        LittleEndian::write_u32(&mut cmd[4..], 72);
+        cmd[8..8 + name.len()].copy_from_slice(name.as_bytes());
+        LittleEndian::write_u64(&mut cmd[32..], 0x4000);
+        LittleEndian::write_u64(&mut cmd[40..], fileoff);
+        LittleEndian::write_u64(&mut cmd[48..], filesize);
+        cmd
+    }
+
+    /// An LC_FILESET_ENTRY command for the image at `fileoff`, its entry id
+    /// following the fixed fields
+    fn fileset_entry(entry_id: &str, fileoff: u64) -> Vec<u8> {
+        let cmdsize = (32 + entry_id.len() + 8) & !7;
+        let mut cmd = vec![0; cmdsize];
+        LittleEndian::write_u32(&mut cmd[0..], LC_FILESET_ENTRY);
+        LittleEndian::write_u32(&mut cmd[4..], cmdsize as u32);
+        LittleEndian::write_u64(&mut cmd[16..], fileoff);
+        LittleEndian::write_u32(&mut cmd[24..], 32);
+        cmd[32..32 + entry_id.len()].copy_from_slice(entry_id.as_bytes());
+        cmd
+    }
+
+    /// A kernel collection of two entries, at 0x1000 and 0x2000, each with a
+    /// page of __TEXT, and room for a signature at 0x3000
+    fn kernel_collection() -> Vec<u8> {
+        let mut code_signature = vec![0; 16];
+        LittleEndian::write_u32(&mut code_signature[0..], LC_CODE_SIGNATURE);
+        LittleEndian::write_u32(&mut code_signature[4..], 16);
+        LittleEndian::write_u32(&mut code_signature[8..], 0x3000);
+        LittleEndian::write_u32(&mut code_signature[12..], 16);
+        let header = image(
+            MH_FILESET,
+            &[
+                segment("__LINKEDIT", 0x3000, 16),
+                code_signature,
+                fileset_entry("com.apple.kernel", 0x1000),
+                fileset_entry("com.example.driver", 0x2000),
+            ],
+        );
+        let mut kc = vec![0; 0x3010];
+        kc[..header.len()].copy_from_slice(&header);
+        for &fileoff in &[0x1000, 0x2000] {
+            let entry = image(MH_KEXT_BUNDLE, &[segment("__TEXT", fileoff as u64, 0x1000)]);
+            kc[fileoff..fileoff + entry.len()].copy_from_slice(&entry);
+        }
+        kc
+    }
+
+    #[test]
+    fn test_fileset_entries() {
+        let mut macho = kernel_collection();
+        sign_macho(&SigningSettings::default(), &mut macho).unwrap();
+
+        let entries = fileset_entries(&macho).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].entry_id, "com.example.driver");
+        assert!(entries[1].contains(0x2fff));
+        assert!(!entries[1].contains(0x1fff));
+
+        let report = verify_fileset(Path::new("kc"), &macho).unwrap();
+        assert!(report.is_valid(), "{:?}", report);
+        macho[0x2100] ^= 1;
+        let report = verify_fileset(Path::new("kc"), &macho).unwrap();
+        assert!(report.children[0].is_valid());
+        assert!(!report.children[1].is_valid());
+    }
+
+    #[test]
+    fn test_truncated_commands() {
+        // an LC_FILESET_ENTRY too short for its fileoff and entry id
+        let mut kc = kernel_collection();
+        let entry = 32 + 72 + 16;
+        LittleEndian::write_u32(&mut kc[entry + 4..], 16);
+        assert!(fileset_entries(&kc).is_err());
+
+        // an entry's LC_SEGMENT_64 too short for its file range
+        let mut kc = kernel_collection();
+        LittleEndian::write_u32(&mut kc[0x1000 + 32 + 4..], 24);
+        assert!(fileset_entries(&kc).is_err());
+
+        // a segment whose end overflows
+        let mut kc = kernel_collection();
+        LittleEndian::write_u64(&mut kc[0x1000 + 32 + 48..], u64::MAX);
+        assert!(fileset_entries(&kc).is_err());
+    }
+}
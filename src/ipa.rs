@@ -0,0 +1,365 @@
+#![allow(dead_code)]
+
+use plist::Value;
+use zip::ZipArchive;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Seek};
+use std::path::Path;
+
+use errors::{CDMachError::*, Result};
+
+use bundle::parse_dictionary;
+use codedir::{CodeSignature, DetachedSignature, DETACHED_SIGNATURE_PATH};
+use profile::EMBEDDED_PROFILE_PATH;
+use resources::{CodeResources, CODE_RESOURCES_PATH};
+use rules::is_macho_magic;
+use verify::{VerificationReport, VerificationStatus, NESTED_CODE_DIRS};
+
+/// unix file type bits of a zip entry's external attributes
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFLNK: u32 = 0o120000;
+
+/// An iOS app archive, read into memory.
+///
+/// Only the entries below `Payload/` are kept; bundles inside it are
+/// verified straight from those entries.
+#[derive(Debug, Clone)]
+pub struct Ipa {
+    /// archive path of the app bundle, e.g. `Payload/Foo.app`
+    pub app: String,
+    /// regular files, keyed by archive path
+    pub files: BTreeMap<String, Vec<u8>>,
+    /// symbolic links, keyed by archive path, with their target
+    pub symlinks: BTreeMap<String, String>,
+}
+
+impl Ipa {
+    pub fn parse<R: Read + Seek>(reader: R) -> Result<Ipa> {
+        let mut archive = ZipArchive::new(reader)?;
+        let mut files = BTreeMap::new();
+        let mut symlinks = BTreeMap::new();
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i)?;
+            let name = entry.name().trim_end_matches('/').to_string();
+            if !name.starts_with("Payload/") || entry.is_dir() {
+                continue;
+            }
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)?;
+            match entry.unix_mode() {
+                Some(mode) if mode & S_IFMT == S_IFLNK => {
+                    symlinks.insert(name, String::from_utf8_lossy(&data).to_string());
+                }
+                _ => {
+                    files.insert(name, data);
+                }
+            }
+        }
+
+        let app = files
+            .keys()
+            .filter_map(|path| path.split('/').nth(1))
+            .find(|name| name.ends_with(".app"))
+            .map(|name| format!("Payload/{}", name));
+        match app {
+            Some(app) => Ok(Ipa {
+                app,
+                files,
+                symlinks,
+            }),
+            None => Err(NotABundle("no Payload/*.app in archive".to_string()).into()),
+        }
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Ipa> {
+        Ipa::parse(File::open(path)?)
+    }
+
+    fn file(&self, path: &str) -> Result<&[u8]> {
+        match self.files.get(path) {
+            Some(data) => Ok(data),
+            None => Err(MissingFile(path.to_string()).into()),
+        }
+    }
+
+    fn is_bundle(&self, dir: &str) -> bool {
+        self.files.contains_key(&format!("{}/Info.plist", dir))
+    }
+
+    /// Files and symlinks below `dir`, keyed by path relative to it
+    fn below(&self, dir: &str) -> (BTreeMap<String, &[u8]>, BTreeMap<String, String>) {
+        let prefix = format!("{}/", dir);
+        let files = self
+            .files
+            .iter()
+            .filter(|&(path, _)| path.starts_with(&prefix))
+            .map(|(path, data)| (path[prefix.len()..].to_string(), &data[..]))
+            .collect();
+        let symlinks = self
+            .symlinks
+            .iter()
+            .filter(|&(path, _)| path.starts_with(&prefix))
+            .map(|(path, target)| (path[prefix.len()..].to_string(), target.clone()))
+            .collect();
+        (files, symlinks)
+    }
+
+    /// Verify the app and everything nested in it
+    pub fn verify(&self) -> Result<VerificationReport> {
+        self.verify_bundle(&self.app)
+    }
+
+    /// Verify the signature of every slice of a Mach-O entry
+    pub fn verify_file(&self, path: &str) -> Result<VerificationReport> {
+        let mut report = VerificationReport::new(Path::new(path));
+        report.check_signatures(&CodeSignature::parse_image(None, self.file(path)?)?)?;
+        Ok(report)
+    }
+
+    /// Verify a (shallow) bundle of the archive, like `verify::verify_bundle`
+    pub fn verify_bundle(&self, dir: &str) -> Result<VerificationReport> {
+        let info_plist = self.file(&format!("{}/Info.plist", dir))?;
+        let executable = match parse_dictionary(info_plist)?
+            .get("CFBundleExecutable")
+            .and_then(Value::as_string)
+        {
+            Some(name) => name.to_string(),
+            None => return Err(MissingInfoKey("CFBundleExecutable".to_string()).into()),
+        };
+
+        let mut report = VerificationReport::new(Path::new(dir));
+        let code = self.file(&format!("{}/{}", dir, executable))?;
+        let mut signatures = CodeSignature::parse_image(None, code)?;
+        let mut uncovered = vec![];
+        if let Some(detached) = self
+            .files
+            .get(&format!("{}/{}", dir, DETACHED_SIGNATURE_PATH))
+        {
+            if signatures.is_empty() {
+                let detached = DetachedSignature::parse(detached)?;
+                signatures = detached.parse_image(None, code)?;
+                uncovered = detached.uncovered_slices(code)?;
+            }
+        }
+        report.check_signatures(&signatures)?;
+        report.check_uncovered_slices(&uncovered);
+        if report.status == VerificationStatus::Unsigned {
+            return Ok(report);
+        }
+
+        let resources = match self.files.get(&format!("{}/{}", dir, CODE_RESOURCES_PATH)) {
+            Some(data) => Some(CodeResources::parse(data)?),
+            None => None,
+        };
+        report.check_bundle_seals(&signatures, info_plist, resources.as_ref())?;
+        if let Some(profile) = self
+            .files
+            .get(&format!("{}/{}", dir, EMBEDDED_PROFILE_PATH))
+        {
+            report.check_profile(profile, &signatures)?;
+        }
+
+        let mut nested = self.find_nested_code(dir, &executable);
+        if let Some(ref resources) = resources {
+            let (files, symlinks) = self.below(dir);
+            let rules = resources.resource_rules_for(true, Some(&executable))?;
+            report.add_diff(&resources.diff_files(&rules, &files, &symlinks));
+            nested.extend(resources.nested_code());
+            nested.sort();
+            nested.dedup();
+        }
+
+        for path in nested {
+            let full = format!("{}/{}", dir, path);
+            let verified = if self.is_bundle(&full) {
+                self.verify_bundle(&full)
+            } else if self.files.contains_key(&full) {
+                self.verify_file(&full)
+            } else {
+                // reported as a removed resource
+                continue;
+            };
+            // code that cannot be read fails its own verification, not the bundle's
+            let mut child = match verified {
+                Ok(child) => child,
+                Err(err) => {
+                    let mut child = VerificationReport::new(Path::new(&full));
+                    child.problem(err.to_string());
+                    child
+                }
+            };
+            child.check_nested_seal(
+                resources
+                    .as_ref()
+                    .and_then(|resources| resources.seals().get(&path)),
+            );
+            report.children.push(child);
+        }
+        Ok(report)
+    }
+
+    /// Nested bundles and Mach-O files in the standard locations of the
+    /// bundle at `dir`, relative to it
+    pub fn find_nested_code(&self, dir: &str, executable: &str) -> Vec<String> {
+        let mut nested = vec![];
+        for location in NESTED_CODE_DIRS.iter() {
+            let prefix = format!("{}/{}/", dir, location);
+            let mut entries: Vec<String> = self
+                .files
+                .iter()
+                .filter(|&(path, _)| path.starts_with(&prefix))
+                .filter_map(|(path, data)| {
+                    let name = path[prefix.len()..].split('/').next().unwrap_or("");
+                    let relative = format!("{}/{}", location, name);
+                    let is_code = if path.len() > prefix.len() + name.len() {
+                        name.contains('.') && self.is_bundle(&format!("{}{}", prefix, name))
+                    } else {
+                        is_macho_magic(data)
+                    };
+                    if is_code && relative != executable {
+                        Some(relative)
+                    } else {
+                        None
+                    }
+                })
+                .collect();
+            entries.sort();
+            entries.dedup();
+            nested.extend(entries);
+        }
+        nested
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use bundle::tests::info_plist;
+    use deep::{sign_deep, DeepSigningConfig};
+    use ipa::Ipa;
+    use signer::tests::fake_macho;
+    use std::fs;
+    use std::io::{Cursor, Write};
+    use std::path::Path;
+    use tempfile::TempDir;
+    use verify::VerificationStatus;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    /// Zip the files below `dir`, flipping a bit in the middle of `tamper`
+    pub fn zip_dir(dir: &Path, tamper: &str) -> Vec<u8> {
+        let mut paths = vec![dir.to_path_buf()];
+        let mut files = vec![];
+        while let Some(path) = paths.pop() {
+            if path.is_dir() {
+                paths.extend(fs::read_dir(&path).unwrap().map(|e| e.unwrap().path()));
+            } else {
+                let name = path
+                    .strip_prefix(dir)
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string();
+                files.push((name, fs::read(&path).unwrap()));
+            }
+        }
+        files.sort();
+
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        for (name, mut data) in files {
+            if name == tamper {
+                let middle = data.len() / 2;
+                data[middle] ^= 1;
+            }
+            zip.start_file(name, FileOptions::default()).unwrap();
+            zip.write_all(&data).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    /// A `Payload/Foo.app` signed ad hoc, nesting a framework and an app
+    /// extension
+    pub fn signed_payload() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        let app = dir.path().join("Payload/Foo.app");
+        fs::create_dir_all(app.join("Frameworks/Bar.framework")).unwrap();
+        fs::create_dir_all(app.join("PlugIns/Ext.appex")).unwrap();
+        fs::write(app.join("Info.plist"), info_plist("Foo", "com.example.foo")).unwrap();
+        fs::write(app.join("Foo"), fake_macho(0x2000)).unwrap();
+        fs::write(app.join("a.txt"), b"a").unwrap();
+        fs::write(
+            app.join("Frameworks/Bar.framework/Info.plist"),
+            info_plist("Bar", "com.example.bar"),
+        )
+        .unwrap();
+        fs::write(app.join("Frameworks/Bar.framework/Bar"), fake_macho(0x1000)).unwrap();
+        fs::write(
+            app.join("PlugIns/Ext.appex/Info.plist"),
+            info_plist("Ext", "com.example.ext"),
+        )
+        .unwrap();
+        fs::write(app.join("PlugIns/Ext.appex/Ext"), fake_macho(0x1000)).unwrap();
+        sign_deep(&app, &DeepSigningConfig::default()).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_verify() {
+        let dir = signed_payload();
+        let ipa = Ipa::parse(Cursor::new(zip_dir(dir.path(), ""))).unwrap();
+        assert_eq!(ipa.app, "Payload/Foo.app");
+        let report = ipa.verify().unwrap();
+        assert!(report.is_valid(), "{:?}", report);
+        let children: Vec<_> = report.children.iter().map(|c| c.path.clone()).collect();
+        assert_eq!(
+            children,
+            vec![
+                Path::new("Payload/Foo.app/Frameworks/Bar.framework"),
+                Path::new("Payload/Foo.app/PlugIns/Ext.appex"),
+            ]
+        );
+
+        let ipa = Ipa::parse(Cursor::new(zip_dir(dir.path(), "Payload/Foo.app/a.txt"))).unwrap();
+        assert_eq!(
+            ipa.verify().unwrap().status,
+            VerificationStatus::Invalid(vec!["resource modified: a.txt".to_string()])
+        );
+
+        let tampered = "Payload/Foo.app/PlugIns/Ext.appex/Ext";
+        let report = Ipa::parse(Cursor::new(zip_dir(dir.path(), tampered)))
+            .unwrap()
+            .verify()
+            .unwrap();
+        // the flat layout seals the files of nested bundles too
+        assert_eq!(
+            report.status,
+            VerificationStatus::Invalid(vec![
+                "resource modified: PlugIns/Ext.appex/Ext".to_string()
+            ])
+        );
+        assert!(!report.children[1].is_valid());
+    }
+
+    #[test]
+    fn test_verify_unreadable_nested_code() {
+        let dir = signed_payload();
+        let app = dir.path().join("Payload/Foo.app");
+        fs::write(app.join("PlugIns/Ext.appex/Info.plist"), b"not a plist").unwrap();
+        fs::write(
+            app.join("Frameworks/Bar.framework/Bar"),
+            &fake_macho(0x1000)[..40],
+        )
+        .unwrap();
+        let report = Ipa::parse(Cursor::new(zip_dir(dir.path(), "")))
+            .unwrap()
+            .verify()
+            .unwrap();
+        assert_eq!(report.children.len(), 2);
+        for child in &report.children {
+            match child.status {
+                VerificationStatus::Invalid(ref problems) => assert_eq!(problems.len(), 1),
+                _ => panic!("{:?}", child),
+            }
+        }
+    }
+}
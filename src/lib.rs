@@ -1,13 +1,43 @@
+pub mod archive;
+pub mod bundle;
+pub mod cms;
 pub mod codedir;
 pub mod consts;
+pub mod deep;
+pub mod der;
+pub mod dmg;
+pub mod dyld_cache;
 pub mod errors;
+pub mod fileset;
+pub mod ipa;
+pub mod profile;
+pub mod rehash;
+pub mod requirements;
+pub mod resign;
+pub mod resources;
+pub mod rules;
+pub mod sections;
+pub mod signer;
+pub mod stream;
+pub mod tarball;
+pub mod verify;
+pub mod xar;
 
+extern crate base64;
 extern crate byteorder;
+extern crate flate2;
 extern crate hex;
 extern crate hexdump;
 extern crate mach_object;
+extern crate plist;
+extern crate regex;
 extern crate ring;
+extern crate untrusted;
 extern crate slog_stdlog;
+extern crate tar;
+extern crate tempfile;
+extern crate xz2;
+extern crate zip;
 
 /// Re-export slog
 ///
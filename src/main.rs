@@ -6,16 +6,46 @@ extern crate slog_stdlog;
 #[macro_use]
 extern crate failure;
 
+extern crate base64;
 extern crate byteorder;
+extern crate flate2;
 extern crate hex;
 extern crate hexdump;
 extern crate mach_object;
+extern crate plist;
+extern crate regex;
 extern crate ring;
+extern crate tar;
+extern crate tempfile;
+extern crate untrusted;
+extern crate xz2;
+extern crate zip;
 
+pub mod archive;
+pub mod bundle;
+pub mod cms;
 pub mod codedir;
 pub mod consts;
+pub mod deep;
+pub mod der;
 pub mod display;
+pub mod dmg;
+pub mod dyld_cache;
 pub mod errors;
+pub mod fileset;
+pub mod ipa;
+pub mod profile;
+pub mod rehash;
+pub mod requirements;
+pub mod resign;
+pub mod resources;
+pub mod rules;
+pub mod sections;
+pub mod signer;
+pub mod stream;
+pub mod tarball;
+pub mod verify;
+pub mod xar;
 
 pub use codedir::*;
 pub use consts::*;
@@ -25,14 +55,24 @@ pub use slog::Logger;
 
 use slog::Drain;
 
+use bundle::{parse_dictionary, Bundle};
+use ipa::Ipa;
+use resources::CodeResources;
+use sections::{find_section, section_data, SECT_ENTITLEMENTS, SECT_INFO_PLIST, SEG_TEXT};
+use verify::{VerificationReport, VerificationStatus};
 use mach_object::{FatArch, LoadCommand, MachCommand, OFile};
+use plist::Value;
 use std::env;
 use std::fs::File;
 use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
 use std::sync::Mutex;
 
 use errors::Result;
 
+/// Extensions of the tarballs scanned entry by entry
+const TARBALL_EXTENSIONS: [&str; 5] = [".tar", ".tar.gz", ".tgz", ".tar.xz", ".txz"];
+
 pub fn main() -> Result<()> {
     let root = slog::Logger::root(
         Mutex::new(slog_bunyan::default(std::io::stdout())).fuse(),
@@ -42,7 +82,48 @@ pub fn main() -> Result<()> {
     let log = root.new(o!());
 
     let args: Vec<String> = env::args().collect();
-    let binary = &args[1];
+    if args.len() > 2 {
+        // a file and its detached signature
+        log_report(
+            &log,
+            &verify::verify_detached(Path::new(&args[1]), Path::new(&args[2]))?,
+        );
+        return Ok(());
+    }
+    if args[1].ends_with(".dmg") {
+        log_report(&log, &dmg::verify_dmg(Path::new(&args[1]))?);
+        return Ok(());
+    }
+    if TARBALL_EXTENSIONS.iter().any(|ext| args[1].ends_with(ext)) {
+        print!(
+            "{}",
+            tarball::entry_table(&tarball::scan_tarball(Path::new(&args[1]))?)
+        );
+        return Ok(());
+    }
+    if args[1].ends_with(".pkg") || args[1].ends_with(".xar") {
+        log_report(&log, &xar::verify_xar(Path::new(&args[1]))?);
+        return Ok(());
+    }
+    if args[1].ends_with(".ipa") {
+        let ipa = Ipa::open(&args[1])?;
+        info!(log, "ipa"; "path" => &args[1], "app" => &ipa.app);
+        log_report(&log, &ipa.verify()?);
+        return Ok(());
+    }
+    let bundle = if Bundle::is_bundle(&args[1]) {
+        let bundle = Bundle::open(&args[1])?;
+        info!(log, "bundle"; "path" => &args[1], "identifier" => bundle.identifier());
+        log_report(&log, &verify::verify_bundle(&bundle)?);
+        Some(bundle)
+    } else {
+        None
+    };
+    let binary = match bundle {
+        Some(ref bundle) => bundle.executable.as_path(),
+        None => Path::new(&args[1]),
+    };
+    let binary = &binary.display().to_string();
     trace!(
         log,
         "searching {binary} for macho headers/code signature command",
@@ -51,11 +132,33 @@ pub fn main() -> Result<()> {
     let mut f = File::open(binary).unwrap();
     let mut buf = Vec::new();
     let size = f.read_to_end(&mut buf).unwrap();
+    if dyld_cache::DyldCacheHeader::is_dyld_cache(&buf) {
+        log_report(
+            &log,
+            &dyld_cache::verify_dyld_cache_data(Path::new(binary), &buf)?,
+        );
+        return Ok(());
+    }
+    if fileset::is_fileset(&buf) {
+        log_report(&log, &fileset::verify_fileset(Path::new(binary), &buf)?);
+        return Ok(());
+    }
+    if archive::is_archive(&buf) {
+        log_members(&log, Path::new(binary), &buf)?;
+        return Ok(());
+    }
     let mut cur = Cursor::new(&buf[..size]);
 
     match &OFile::parse(&mut cur) {
         Ok(OFile::MachFile { header, commands }) => {
-            handle_mach_file(log, header, commands, &mut cur, 0 /* Not fat */)?
+            handle_mach_file(
+                log,
+                header,
+                commands,
+                &mut cur,
+                0, /* Not fat */
+                bundle.as_ref(),
+            )?
         }
         Ok(OFile::FatFile {
             magic,
@@ -67,24 +170,94 @@ pub fn main() -> Result<()> {
                     (FatArch { ref offset, .. }, OFile::MachFile { header, commands }) => {
                         trace!(log, "file offset: {}", offset);
                         cur.set_position(*offset as u64);
-                        handle_mach_file(log.clone(), header, commands, &mut cur, *offset)?;
+                        handle_mach_file(
+                            log.clone(),
+                            header,
+                            commands,
+                            &mut cur,
+                            *offset,
+                            bundle.as_ref(),
+                        )?;
+                    }
+                    (
+                        FatArch {
+                            ref offset,
+                            size: ref slice_size,
+                            ..
+                        },
+                        _,
+                    ) => {
+                        let slice = offset
+                            .checked_add(*slice_size)
+                            .and_then(|end| buf.get(*offset as usize..end as usize));
+                        match slice {
+                            Some(slice) if archive::is_archive(slice) => {
+                                log_members(&log, Path::new(binary), slice)?
+                            }
+                            Some(_) => {
+                                warn!(log, "fat slice is not a Mach-O file"; "offset" => offset)
+                            }
+                            None => warn!(log, "fat slice past the end of the file";
+                                          "offset" => offset,
+                                          "size" => slice_size),
+                        }
                     }
-                    _ => unimplemented!(),
                 }
             }
         }
-        _ => unimplemented!(),
+        _ => warn!(log, "not a Mach-O file or archive"; "path" => binary),
     }
 
     Ok(())
 }
 
+/// Log the signature status of every Mach-O member of an archive
+fn log_members(log: &Logger, path: &Path, data: &[u8]) -> Result<()> {
+    for report in archive::verify_members(path, data)? {
+        let member = report.path.display().to_string();
+        match report.status {
+            VerificationStatus::Unsigned => info!(log, "member is not signed"; "member" => member),
+            VerificationStatus::Valid => info!(log, "member is signed";
+                                               "member" => member,
+                                               "flags" => &report.flags,
+                                               "cdhash" => &report.cdhash),
+            VerificationStatus::Invalid(_) => log_report(log, &report),
+        }
+    }
+    Ok(())
+}
+
+/// Log the verdict on every item of a deep verification report
+fn log_report(log: &Logger, report: &VerificationReport) {
+    let path = report.path.display().to_string();
+    match report.status {
+        VerificationStatus::Valid => info!(log, "valid on disk";
+                                           "path" => path,
+                                           "identifier" => &report.identifier,
+                                           "bundle_identifier" => &report.bundle_identifier,
+                                           "cdhash" => &report.cdhash,
+                                           "entitlements" => report.entitlements
+                                               .as_ref()
+                                               .map(|&(source, _)| source.to_string())),
+        VerificationStatus::Unsigned => warn!(log, "code object is not signed"; "path" => path),
+        VerificationStatus::Invalid(ref problems) => {
+            for problem in problems {
+                warn!(log, "invalid signature"; "path" => &path, "problem" => problem);
+            }
+        }
+    }
+    for child in &report.children {
+        log_report(log, child);
+    }
+}
+
 fn handle_mach_file<T: AsRef<[u8]>>(
     log: Logger,
     header: &MachHeader,
     commands: &Vec<MachCommand>,
     cur: &mut Cursor<T>,
     slice_offset: u32,
+    bundle: Option<&Bundle>,
 ) -> Result<()> {
     assert_eq!(header.ncmds as usize, commands.len());
 
@@ -94,6 +267,11 @@ fn handle_mach_file<T: AsRef<[u8]>>(
         header,
         cur.position()
     );
+    if let Some(section) = find_section(commands, SEG_TEXT, SECT_ENTITLEMENTS) {
+        info!(log, "simulator entitlements";
+              "offset" => section.offset,
+              "size" => section.size);
+    }
     for (i, &MachCommand(ref cmd, _cmdsize)) in commands.iter().enumerate() {
         if let LoadCommand::CodeSignature { 0: link } = &cmd {
             info!(
@@ -105,11 +283,50 @@ fn handle_mach_file<T: AsRef<[u8]>>(
                 CodeSignature::parse(log.clone(), slice_offset + link.off, link.size, cur)?
             {
                 trace!(log, "{:?}", cs);
+                let slice = &cur.get_ref().as_ref()[slice_offset as usize..];
+                if let Some(info_plist) = section_data(slice, commands, SEG_TEXT, SECT_INFO_PLIST)?
+                {
+                    let info = parse_dictionary(info_plist)?;
+                    info!(log, "embedded Info.plist";
+                          "identifier" => info
+                              .get("CFBundleIdentifier")
+                              .and_then(Value::as_string));
+                    match cs.verify_special_slot(CSSLOT_INFOSLOT, info_plist)? {
+                        Some(true) => info!(log, "embedded Info.plist is bound to the signature"),
+                        Some(false) => {
+                            warn!(log, "embedded Info.plist does not match the signature")
+                        }
+                        None => warn!(log, "signature does not bind the embedded Info.plist"),
+                    }
+                }
+                if let Some(bundle) = bundle {
+                    match cs.verify_special_slot(CSSLOT_INFOSLOT, &bundle.info_plist)? {
+                        Some(true) => info!(log, "Info.plist is bound to the signature"),
+                        Some(false) => warn!(log, "Info.plist does not match the signature"),
+                        None => warn!(log, "signature does not bind an Info.plist"),
+                    }
+                    if let Ok(resources) = CodeResources::open(bundle) {
+                        match resources.verify_envelope(&cs)? {
+                            Some(true) => info!(log, "CodeResources is bound to the signature"),
+                            Some(false) => warn!(log, "CodeResources does not match the signature"),
+                            None => warn!(log, "signature does not bind CodeResources"),
+                        }
+                    }
+                }
                 cs.blobs.unwrap().iter().for_each(|ref blob| {
-                    if let Blob::CodeDirectory { ref cd_hash, .. } = blob {
+                    if let Blob::CodeDirectory {
+                        ref cd_hash,
+                        ref code_directory,
+                        ..
+                    } = blob
+                    {
                         let cpuinfo = get_arch_name_from_types(header.cputype, header.cpusubtype)
                             .unwrap_or("unk");
-                        info!(log, "code signature"; "cd_hash" => cd_hash, "cpuinfo" => cpuinfo);
+                        info!(log, "code signature";
+                              "cd_hash" => cd_hash,
+                              "cpuinfo" => cpuinfo,
+                              "flags" => code_directory.flags_str(),
+                              "linker_signed" => code_directory.is_linker_signed());
                     }
                 });
             } else {
@@ -0,0 +1,318 @@
+#![allow(dead_code)]
+
+use hex;
+use plist::{Dictionary, Value};
+
+use std::time::SystemTime;
+
+use errors::{CDMachError::*, Result};
+
+use bundle::parse_dictionary;
+use cms::{Certificate, SignedData};
+use codedir::CodeSignature;
+
+/// Location of the provisioning profile, relative to the bundle contents
+pub const EMBEDDED_PROFILE_PATH: &str = "embedded.mobileprovision";
+
+/// A provisioning profile (`embedded.mobileprovision`): a CMS signed plist
+#[derive(Debug, Clone)]
+pub struct ProvisioningProfile {
+    pub name: Option<String>,
+    pub uuid: Option<String>,
+    /// `TeamIdentifier`
+    pub team_identifiers: Vec<String>,
+    /// `ApplicationIdentifierPrefix`
+    pub app_id_prefixes: Vec<String>,
+    /// entitlements the profile grants
+    pub entitlements: Dictionary,
+    /// UDIDs of the devices the profile is valid on
+    pub provisioned_devices: Vec<String>,
+    /// enterprise profiles are valid on every device
+    pub provisions_all_devices: bool,
+    pub expiration: Option<SystemTime>,
+    /// certificates allowed to sign with the profile
+    pub developer_certificates: Vec<Certificate>,
+    /// the signed plist
+    pub plist: Vec<u8>,
+    /// whether the CMS signature of the plist verifies with the embedded
+    /// signer certificate; its chain is not checked
+    pub signature_verifies: bool,
+}
+
+/// Where a signature departs from a provisioning profile
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ProfileCheck {
+    /// entitlement keys the profile does not grant
+    pub ungranted_entitlements: Vec<String>,
+    /// common names (or hex serials) of signing certificates the profile
+    /// does not list
+    pub unlisted_certificates: Vec<String>,
+    pub expired: bool,
+    /// the profile's own signature does not verify, e.g. it was edited
+    pub bad_signature: bool,
+}
+
+impl ProfileCheck {
+    pub fn is_clean(&self) -> bool {
+        self.ungranted_entitlements.is_empty()
+            && self.unlisted_certificates.is_empty()
+            && !self.expired
+            && !self.bad_signature
+    }
+}
+
+impl ProvisioningProfile {
+    pub fn parse(data: &[u8]) -> Result<ProvisioningProfile> {
+        let signed_data = SignedData::parse(data)?;
+        let plist = match signed_data.content {
+            Some(ref content) => content.clone(),
+            None => return Err(UnexpectedPlist("profile has no content".to_string()).into()),
+        };
+        // a signature that cannot be checked does not verify either
+        let signature_verifies = signed_data.verify_detached(&plist).unwrap_or(false);
+        let dict = parse_dictionary(&plist)?;
+
+        let string = |key: &str| {
+            dict.get(key)
+                .and_then(Value::as_string)
+                .map(|s| s.to_string())
+        };
+        let strings = |key: &str| -> Vec<String> {
+            dict.get(key)
+                .and_then(Value::as_array)
+                .map(|values| {
+                    values
+                        .iter()
+                        .filter_map(Value::as_string)
+                        .map(|s| s.to_string())
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        let mut developer_certificates = vec![];
+        if let Some(certs) = dict.get("DeveloperCertificates").and_then(Value::as_array) {
+            for cert in certs.iter().filter_map(Value::as_data) {
+                developer_certificates.push(Certificate::parse(cert)?);
+            }
+        }
+
+        Ok(ProvisioningProfile {
+            name: string("Name"),
+            uuid: string("UUID"),
+            team_identifiers: strings("TeamIdentifier"),
+            app_id_prefixes: strings("ApplicationIdentifierPrefix"),
+            entitlements: dict
+                .get("Entitlements")
+                .and_then(Value::as_dictionary)
+                .cloned()
+                .unwrap_or_default(),
+            provisioned_devices: strings("ProvisionedDevices"),
+            provisions_all_devices: dict
+                .get("ProvisionsAllDevices")
+                .and_then(Value::as_boolean)
+                .unwrap_or(false),
+            expiration: dict
+                .get("ExpirationDate")
+                .and_then(Value::as_date)
+                .map(SystemTime::from),
+            developer_certificates,
+            plist,
+            signature_verifies,
+        })
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expiration
+            .map(|expiration| expiration < SystemTime::now())
+            .unwrap_or(false)
+    }
+
+    /// Keys of `entitlements` the profile does not grant
+    pub fn ungranted_entitlements(&self, entitlements: &Dictionary) -> Vec<String> {
+        entitlements
+            .iter()
+            .filter(|&(key, requested)| match self.entitlements.get(key) {
+                Some(granted) => !grants(granted, requested),
+                None => true,
+            })
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Whether `cert` is one of the profile's developer certificates
+    pub fn lists_certificate(&self, cert: &Certificate) -> bool {
+        self.developer_certificates
+            .iter()
+            .any(|listed| listed.der == cert.der)
+    }
+
+    /// Cross-check a signature: its entitlements and the leaf certificate of
+    /// its CMS signature (ad-hoc signatures have none to check)
+    pub fn check(&self, cs: &CodeSignature) -> Result<ProfileCheck> {
+        let mut check = ProfileCheck {
+            expired: self.is_expired(),
+            bad_signature: !self.signature_verifies,
+            ..Default::default()
+        };
+        if let Some(entitlements) = cs.entitlements() {
+            check.ungranted_entitlements =
+                self.ungranted_entitlements(&parse_dictionary(entitlements)?);
+        }
+        if let Some(cms) = cs.cms() {
+            let signed_data = SignedData::parse(cms)?;
+            match signed_data.leaf_certificate() {
+                Some(leaf) if self.lists_certificate(leaf) => {}
+                Some(leaf) => check.unlisted_certificates.push(
+                    leaf.common_name()
+                        .unwrap_or_else(|| hex::encode(&leaf.serial)),
+                ),
+                None => check
+                    .unlisted_certificates
+                    .push("unknown signer".to_string()),
+            }
+        }
+        Ok(check)
+    }
+}
+
+/// Whether a profile entitlement value grants a requested one: strings match
+/// exactly or through a trailing `*`, arrays grant each requested element,
+/// and `true` grants any boolean
+fn grants(granted: &Value, requested: &Value) -> bool {
+    match (granted, requested) {
+        (&Value::String(ref granted), &Value::String(ref requested)) => {
+            granted == requested
+                || (granted.ends_with('*') && requested.starts_with(&granted[..granted.len() - 1]))
+        }
+        (&Value::Array(ref granted), &Value::Array(ref requested)) => requested
+            .iter()
+            .all(|requested| granted.iter().any(|granted| grants(granted, requested))),
+        (&Value::Array(ref granted), requested) => {
+            granted.iter().any(|granted| grants(granted, requested))
+        }
+        (&Value::Boolean(granted), &Value::Boolean(requested)) => granted || !requested,
+        (granted, requested) => granted == requested,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use cms::tests::identity;
+    use cms::SigningIdentity;
+    use plist::Value;
+    use profile::{grants, ProfileCheck, ProvisioningProfile};
+    use resign::tests::EXAMPLE_PROFILE;
+    use signer::tests::{fake_macho, parse};
+    use signer::{sign_macho, SigningSettings};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_parse() {
+        let profile = ProvisioningProfile::parse(EXAMPLE_PROFILE).unwrap();
+        assert_eq!(profile.name, Some("Example Development".to_string()));
+        assert_eq!(
+            profile.uuid,
+            Some("6F1C2D3E-4A5B-4C6D-8E7F-901234567890".to_string())
+        );
+        assert_eq!(profile.team_identifiers, vec!["ABCDE12345".to_string()]);
+        assert_eq!(profile.app_id_prefixes, vec!["ABCDE12345".to_string()]);
+        assert_eq!(
+            profile.provisioned_devices,
+            vec!["00008030-000A1B2C3D4E5F60".to_string()]
+        );
+        assert!(!profile.provisions_all_devices);
+        assert!(!profile.is_expired());
+        assert_eq!(
+            profile
+                .entitlements
+                .get("application-identifier")
+                .and_then(Value::as_string),
+            Some("ABCDE12345.com.example.*")
+        );
+        assert_eq!(profile.developer_certificates, vec![identity().certificate]);
+        assert!(profile.signature_verifies);
+    }
+
+    #[test]
+    fn test_forged_profile() {
+        let mut forged = EXAMPLE_PROFILE.to_vec();
+        // the AppIDName, which precedes the Name
+        let name = b"Example Development";
+        let at = forged
+            .windows(name.len())
+            .position(|window| window == name)
+            .unwrap();
+        forged[at] = b'F';
+        let profile = ProvisioningProfile::parse(&forged).unwrap();
+        assert_eq!(profile.name, Some("Example Development".to_string()));
+        assert!(!profile.signature_verifies);
+    }
+
+    #[test]
+    fn test_check() {
+        let profile = ProvisioningProfile::parse(EXAMPLE_PROFILE).unwrap();
+        let sign = |identity: SigningIdentity, entitlements: &str| {
+            let settings = SigningSettings {
+                identifier: "com.example.app".to_string(),
+                entitlements: Some(entitlements.as_bytes().to_vec()),
+                identity: Some(Arc::new(identity)),
+                ..Default::default()
+            };
+            let mut macho = fake_macho(0x1000);
+            sign_macho(&settings, &mut macho).unwrap();
+            parse(&macho)
+        };
+        let granted = r#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0"><dict>
+<key>application-identifier</key><string>ABCDE12345.com.example.app</string>
+<key>get-task-allow</key><true/>
+</dict></plist>"#;
+        let check = profile.check(&sign(identity(), granted)).unwrap();
+        assert!(check.is_clean(), "{:?}", check);
+
+        let ungranted = r#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0"><dict>
+<key>application-identifier</key><string>ZZZZZ12345.com.example.app</string>
+<key>com.apple.developer.icloud-services</key><array><string>CloudKit</string></array>
+</dict></plist>"#;
+        let other = SigningIdentity::from_der(
+            include_bytes!("../testdata/developer_id.p8"),
+            include_bytes!("../testdata/developer_id_kext.der"),
+            &[],
+        )
+        .unwrap();
+        assert_eq!(
+            profile.check(&sign(other, ungranted)).unwrap(),
+            ProfileCheck {
+                ungranted_entitlements: vec![
+                    "application-identifier".to_string(),
+                    "com.apple.developer.icloud-services".to_string(),
+                ],
+                unlisted_certificates: vec![
+                    "Developer ID Application: Test (ABCDE12345)".to_string()
+                ],
+                expired: false,
+                bad_signature: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_grants() {
+        let s = |s: &str| Value::String(s.to_string());
+        let a = |v: Vec<Value>| Value::Array(v);
+        assert!(grants(&s("ABCDE12345.*"), &s("ABCDE12345.com.example.app")));
+        assert!(!grants(
+            &s("ABCDE12345.com.example.*"),
+            &s("ZZZZZ.com.example.app")
+        ));
+        assert!(grants(
+            &a(vec![s("ABCDE12345.*")]),
+            &a(vec![s("ABCDE12345.shared"), s("ABCDE12345.app")])
+        ));
+        assert!(!grants(&a(vec![s("group.a")]), &a(vec![s("group.b")])));
+        assert!(grants(&Value::Boolean(true), &Value::Boolean(false)));
+        assert!(!grants(&Value::Boolean(false), &Value::Boolean(true)));
+        assert!(grants(&s("production"), &s("production")));
+    }
+}
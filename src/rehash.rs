@@ -0,0 +1,169 @@
+#![allow(dead_code)]
+
+use byteorder::{ByteOrder, LittleEndian, NetworkEndian};
+use hex;
+
+use std::collections::BTreeSet;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+use errors::{CDMachError::*, Result};
+
+use codedir::{find_link_edit, hash_digest, CodeDirectory, SuperBlob};
+use consts::{
+    CSMAGIC_CODEDIRECTORY, CSMAGIC_EMBEDDED_SIGNATURE, CSSLOT_ALTERNATE_CODEDIRECTORIES,
+    CSSLOT_ALTERNATE_CODEDIRECTORY_LIMIT, CSSLOT_CMS_SIGNATURE, CSSLOT_CODEDIRECTORY,
+};
+
+/// Refresh the page hashes of an ad-hoc signed thin Mach-O image after the
+/// bytes in `ranges` were patched in place.
+///
+/// Only the pages touched by `ranges` are read back and hashed; every
+/// CodeDirectory (primary and alternates) is updated and the signature is
+/// written back where it was. Returns the new cdhash of each CodeDirectory,
+/// keyed by its slot.
+pub fn rehash_ranges<T: Read + Write + Seek>(
+    image: &mut T,
+    ranges: &[Range<u64>],
+) -> Result<Vec<(u32, String)>> {
+    let mut header = vec![0u8; 32];
+    image.seek(SeekFrom::Start(0))?;
+    image.read_exact(&mut header)?;
+    let sizeofcmds = LittleEndian::read_u32(&header[20..]) as usize;
+    header.resize(32 + sizeofcmds, 0);
+    image.read_exact(&mut header[32..])?;
+    let layout = find_link_edit(&header)?;
+
+    let mut signature = vec![0u8; layout.datasize as usize];
+    image.seek(SeekFrom::Start(layout.dataoff as u64))?;
+    image.read_exact(&mut signature)?;
+
+    let super_blob = SuperBlob::parse::<NetworkEndian, _>(&mut Cursor::new(&signature[..]))?;
+    if super_blob.magic != CSMAGIC_EMBEDDED_SIGNATURE {
+        return Err(NoCodeDirectory.into());
+    }
+
+    let mut code_directories = vec![];
+    for bi in super_blob.index.iter().filter_map(|bi| bi.as_ref()) {
+        let offset = bi.offset as usize;
+        if offset + 8 > signature.len() {
+            return Err(BadBlobLength(bi.offset).into());
+        }
+        let magic = NetworkEndian::read_u32(&signature[offset..]);
+        let length = NetworkEndian::read_u32(&signature[offset + 4..]);
+        match bi.typ {
+            // an empty wrapper is what ad-hoc signatures carry
+            CSSLOT_CMS_SIGNATURE if length > 8 => return Err(NotAdHoc.into()),
+            typ if magic == CSMAGIC_CODEDIRECTORY
+                && (typ == CSSLOT_CODEDIRECTORY
+                    || (typ >= CSSLOT_ALTERNATE_CODEDIRECTORIES
+                        && typ < CSSLOT_ALTERNATE_CODEDIRECTORY_LIMIT)) =>
+            {
+                let cd = CodeDirectory::parse::<NetworkEndian, _>(&mut Cursor::new(
+                    &signature[offset..],
+                ))?;
+                if offset + cd.length as usize > signature.len() {
+                    return Err(BadBlobLength(cd.length).into());
+                }
+                code_directories.push((typ, offset, cd));
+            }
+            _ => {}
+        }
+    }
+    if code_directories.is_empty() {
+        return Err(NoCodeDirectory.into());
+    }
+
+    let mut cd_hashes = vec![];
+    for &(typ, offset, ref cd) in &code_directories {
+        let code_limit = cd.codeLimit as u64;
+        let page_size = match cd.pageSize {
+            0 => code_limit.max(1),
+            n => 1u64 << n,
+        };
+
+        let mut pages = BTreeSet::new();
+        for range in ranges.iter().filter(|range| range.start < range.end) {
+            if range.end > code_limit {
+                return Err(PatchOutsideCodeLimit(range.start, range.end).into());
+            }
+            pages.extend(range.start / page_size..(range.end - 1) / page_size + 1);
+        }
+
+        for page in pages {
+            let start = page * page_size;
+            let mut data = vec![0u8; (code_limit - start).min(page_size) as usize];
+            image.seek(SeekFrom::Start(start))?;
+            image.read_exact(&mut data)?;
+            let digest = hash_digest(cd.hashType as u32, &data)?;
+            let slot = offset + cd.hashOffset as usize + page as usize * cd.hashSize as usize;
+            // the slot must lie within its CodeDirectory
+            if digest.len() != cd.hashSize as usize
+                || slot + digest.len() > offset + cd.length as usize
+            {
+                return Err(BadBlobLength(cd.length).into());
+            }
+            signature[slot..slot + digest.len()].copy_from_slice(&digest);
+        }
+
+        let blob = &signature[offset..offset + cd.length as usize];
+        cd_hashes.push((typ, hex::encode(hash_digest(cd.hashType as u32, blob)?)));
+    }
+
+    image.seek(SeekFrom::Start(layout.dataoff as u64))?;
+    image.write_all(&signature)?;
+    Ok(cd_hashes)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use byteorder::{ByteOrder, NetworkEndian};
+    use codedir::Blob;
+    use consts::CSMAGIC_CODEDIRECTORY;
+    use errors::CDMachError::{self, BadBlobLength};
+    use rehash::rehash_ranges;
+    use signer::tests::{assert_hashes_match, fake_macho, parse};
+    use signer::{sign_macho, SigningSettings};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_rehash_ranges() {
+        let mut macho = fake_macho(0x5000);
+        let settings = SigningSettings {
+            identifier: "com.example.patched".to_string(),
+            ..Default::default()
+        };
+        sign_macho(&settings, &mut macho).unwrap();
+
+        macho[0x1800..0x1810].copy_from_slice(b"version 2.0.1\0\0\0");
+        macho[0x4fff] = 0x42;
+        let mut image = Cursor::new(macho);
+        let cd_hashes = rehash_ranges(&mut image, &[0x1800..0x1810, 0x4fff..0x5000]).unwrap();
+
+        let cs = parse(image.get_ref());
+        assert_hashes_match(&cs);
+        for blob in cs.blobs.as_ref().unwrap() {
+            if let Blob::CodeDirectory { ref cd_hash, .. } = blob {
+                assert_eq!(cd_hash.as_ref(), Some(&cd_hashes[0].1));
+            }
+        }
+    }
+
+    #[test]
+    fn test_rehash_slot_outside_code_directory() {
+        let mut macho = fake_macho(0x3000);
+        sign_macho(&SigningSettings::default(), &mut macho).unwrap();
+        let magic = &mut [0; 4];
+        NetworkEndian::write_u32(magic, CSMAGIC_CODEDIRECTORY);
+        let cd = macho.windows(4).position(|window| window == magic).unwrap();
+        // hashOffset past the end of the CodeDirectory
+        NetworkEndian::write_u32(&mut macho[cd + 16..], 0x10000);
+
+        let err =
+            rehash_ranges(&mut Cursor::new(macho), &[0x1800..0x1810, 0x2000..0x2010]).unwrap_err();
+        match err.downcast_ref::<CDMachError>() {
+            Some(&BadBlobLength(_)) => {}
+            _ => panic!("{}", err),
+        }
+    }
+}
@@ -0,0 +1,636 @@
+#![allow(dead_code)]
+
+use byteorder::{ByteOrder, NetworkEndian};
+use hex;
+
+use std::fmt;
+use std::str;
+
+use errors::{CDMachError::*, Result};
+
+use consts::{kSecDesignatedRequirementType, CSMAGIC_REQUIREMENT, CSMAGIC_REQUIREMENTS};
+
+/// The only requirement form: an expression
+const EXPR_FORM: u32 = 1;
+
+/// Opcodes of the requirement language
+const OP_FALSE: u32 = 0;
+const OP_TRUE: u32 = 1;
+const OP_IDENT: u32 = 2;
+const OP_APPLE_ANCHOR: u32 = 3;
+const OP_ANCHOR_HASH: u32 = 4;
+const OP_INFO_KEY_VALUE: u32 = 5;
+const OP_AND: u32 = 6;
+const OP_OR: u32 = 7;
+const OP_CD_HASH: u32 = 8;
+const OP_NOT: u32 = 9;
+const OP_INFO_KEY_FIELD: u32 = 10;
+const OP_CERT_FIELD: u32 = 11;
+const OP_TRUSTED_CERT: u32 = 12;
+const OP_TRUSTED_CERTS: u32 = 13;
+const OP_CERT_GENERIC: u32 = 14;
+const OP_APPLE_GENERIC_ANCHOR: u32 = 15;
+const OP_ENTITLEMENT_FIELD: u32 = 16;
+const OP_CERT_POLICY: u32 = 17;
+const OP_NAMED_ANCHOR: u32 = 18;
+const OP_NAMED_CODE: u32 = 19;
+const OP_PLATFORM: u32 = 20;
+const OP_NOTARIZED: u32 = 21;
+const OP_LEGACY_DEV_ID: u32 = 23;
+/// flag bits of an opcode
+const OP_FLAG_MASK: u32 = 0xff00_0000;
+
+/// Match operations of field tests
+const MATCH_EXISTS: u32 = 0;
+const MATCH_EQUAL: u32 = 1;
+const MATCH_CONTAINS: u32 = 2;
+const MATCH_BEGINS_WITH: u32 = 3;
+const MATCH_ENDS_WITH: u32 = 4;
+const MATCH_LESS_THAN: u32 = 5;
+const MATCH_GREATER_THAN: u32 = 6;
+const MATCH_LESS_EQUAL: u32 = 7;
+const MATCH_GREATER_EQUAL: u32 = 8;
+const MATCH_ABSENT: u32 = 14;
+
+/// Certificate slots: the leaf, the anchor, or a position in the chain
+pub const LEAF_CERT: i32 = 0;
+pub const ANCHOR_CERT: i32 = -1;
+
+/// A field test of a requirement
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Match {
+    Exists,
+    Absent,
+    Equal(Vec<u8>),
+    Contains(Vec<u8>),
+    BeginsWith(Vec<u8>),
+    EndsWith(Vec<u8>),
+    LessThan(Vec<u8>),
+    GreaterThan(Vec<u8>),
+    LessEqual(Vec<u8>),
+    GreaterEqual(Vec<u8>),
+}
+
+/// A compiled code requirement, e.g. the designated requirement of a signature
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    False,
+    True,
+    Identifier(String),
+    AppleAnchor,
+    AppleGenericAnchor,
+    /// certificate slot and SHA-1 of the certificate
+    AnchorHash(i32, Vec<u8>),
+    /// truncated cdhash
+    CdHash(Vec<u8>),
+    InfoKeyValue(String, String),
+    InfoKeyField(String, Match),
+    EntitlementField(String, Match),
+    /// certificate slot, field name (e.g. `subject.OU`) and test
+    CertField(i32, String, Match),
+    /// certificate slot, DER encoded extension OID and test
+    CertGeneric(i32, Vec<u8>, Match),
+    CertPolicy(i32, Vec<u8>, Match),
+    TrustedCert(i32),
+    TrustedCerts,
+    NamedAnchor(String),
+    NamedCode(String),
+    Platform(u32),
+    Notarized,
+    LegacyDevId,
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn u32(&mut self) -> Result<u32> {
+        if self.pos + 4 > self.data.len() {
+            return Err(BadRequirement("truncated expression".to_string()).into());
+        }
+        let value = NetworkEndian::read_u32(&self.data[self.pos..]);
+        self.pos += 4;
+        Ok(value)
+    }
+
+    /// Length prefixed bytes, padded to 4 bytes
+    fn data(&mut self) -> Result<&'a [u8]> {
+        let length = self.u32()? as usize;
+        if self.pos + length > self.data.len() {
+            return Err(BadRequirement("truncated data".to_string()).into());
+        }
+        let data = &self.data[self.pos..self.pos + length];
+        self.pos += (length + 3) & !3;
+        Ok(data)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        Ok(str::from_utf8(self.data()?)?.to_string())
+    }
+
+    fn match_(&mut self) -> Result<Match> {
+        Ok(match self.u32()? {
+            MATCH_EXISTS => Match::Exists,
+            MATCH_ABSENT => Match::Absent,
+            MATCH_EQUAL => Match::Equal(self.data()?.to_vec()),
+            MATCH_CONTAINS => Match::Contains(self.data()?.to_vec()),
+            MATCH_BEGINS_WITH => Match::BeginsWith(self.data()?.to_vec()),
+            MATCH_ENDS_WITH => Match::EndsWith(self.data()?.to_vec()),
+            MATCH_LESS_THAN => Match::LessThan(self.data()?.to_vec()),
+            MATCH_GREATER_THAN => Match::GreaterThan(self.data()?.to_vec()),
+            MATCH_LESS_EQUAL => Match::LessEqual(self.data()?.to_vec()),
+            MATCH_GREATER_EQUAL => Match::GreaterEqual(self.data()?.to_vec()),
+            op => return Err(BadRequirement(format!("unsupported match {}", op)).into()),
+        })
+    }
+
+    fn expr(&mut self) -> Result<Expr> {
+        Ok(match self.u32()? & !OP_FLAG_MASK {
+            OP_FALSE => Expr::False,
+            OP_TRUE => Expr::True,
+            OP_IDENT => Expr::Identifier(self.string()?),
+            OP_APPLE_ANCHOR => Expr::AppleAnchor,
+            OP_APPLE_GENERIC_ANCHOR => Expr::AppleGenericAnchor,
+            OP_ANCHOR_HASH => Expr::AnchorHash(self.u32()? as i32, self.data()?.to_vec()),
+            OP_CD_HASH => Expr::CdHash(self.data()?.to_vec()),
+            OP_INFO_KEY_VALUE => Expr::InfoKeyValue(self.string()?, self.string()?),
+            OP_INFO_KEY_FIELD => Expr::InfoKeyField(self.string()?, self.match_()?),
+            OP_ENTITLEMENT_FIELD => Expr::EntitlementField(self.string()?, self.match_()?),
+            OP_CERT_FIELD => Expr::CertField(self.u32()? as i32, self.string()?, self.match_()?),
+            OP_CERT_GENERIC => {
+                Expr::CertGeneric(self.u32()? as i32, self.data()?.to_vec(), self.match_()?)
+            }
+            OP_CERT_POLICY => {
+                Expr::CertPolicy(self.u32()? as i32, self.data()?.to_vec(), self.match_()?)
+            }
+            OP_TRUSTED_CERT => Expr::TrustedCert(self.u32()? as i32),
+            OP_TRUSTED_CERTS => Expr::TrustedCerts,
+            OP_NAMED_ANCHOR => Expr::NamedAnchor(self.string()?),
+            OP_NAMED_CODE => Expr::NamedCode(self.string()?),
+            OP_PLATFORM => Expr::Platform(self.u32()?),
+            OP_NOTARIZED => Expr::Notarized,
+            OP_LEGACY_DEV_ID => Expr::LegacyDevId,
+            OP_NOT => Expr::Not(Box::new(self.expr()?)),
+            OP_AND => Expr::And(Box::new(self.expr()?), Box::new(self.expr()?)),
+            OP_OR => Expr::Or(Box::new(self.expr()?), Box::new(self.expr()?)),
+            op => return Err(BadRequirement(format!("unsupported opcode {}", op)).into()),
+        })
+    }
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    let mut buf = [0; 4];
+    NetworkEndian::write_u32(&mut buf, value);
+    out.extend_from_slice(&buf);
+}
+
+/// `magic`, length and `payload`
+fn blob(magic: u32, payload: &[u8]) -> Vec<u8> {
+    let mut blob = vec![];
+    put_u32(&mut blob, magic);
+    put_u32(&mut blob, payload.len() as u32 + 8);
+    blob.extend_from_slice(payload);
+    blob
+}
+
+fn put_data(out: &mut Vec<u8>, data: &[u8]) {
+    put_u32(out, data.len() as u32);
+    out.extend_from_slice(data);
+    while out.len() & 3 != 0 {
+        out.push(0);
+    }
+}
+
+impl Match {
+    fn encode(&self, out: &mut Vec<u8>) {
+        let (op, value) = match *self {
+            Match::Exists => (MATCH_EXISTS, None),
+            Match::Absent => (MATCH_ABSENT, None),
+            Match::Equal(ref v) => (MATCH_EQUAL, Some(v)),
+            Match::Contains(ref v) => (MATCH_CONTAINS, Some(v)),
+            Match::BeginsWith(ref v) => (MATCH_BEGINS_WITH, Some(v)),
+            Match::EndsWith(ref v) => (MATCH_ENDS_WITH, Some(v)),
+            Match::LessThan(ref v) => (MATCH_LESS_THAN, Some(v)),
+            Match::GreaterThan(ref v) => (MATCH_GREATER_THAN, Some(v)),
+            Match::LessEqual(ref v) => (MATCH_LESS_EQUAL, Some(v)),
+            Match::GreaterEqual(ref v) => (MATCH_GREATER_EQUAL, Some(v)),
+        };
+        put_u32(out, op);
+        if let Some(value) = value {
+            put_data(out, value);
+        }
+    }
+}
+
+impl Expr {
+    /// Decode an expression in compiled form
+    pub fn parse(data: &[u8]) -> Result<Expr> {
+        Reader { data, pos: 0 }.expr()
+    }
+
+    /// Encode the expression in compiled form
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match *self {
+            Expr::False => put_u32(out, OP_FALSE),
+            Expr::True => put_u32(out, OP_TRUE),
+            Expr::Identifier(ref id) => {
+                put_u32(out, OP_IDENT);
+                put_data(out, id.as_bytes());
+            }
+            Expr::AppleAnchor => put_u32(out, OP_APPLE_ANCHOR),
+            Expr::AppleGenericAnchor => put_u32(out, OP_APPLE_GENERIC_ANCHOR),
+            Expr::AnchorHash(slot, ref hash) => {
+                put_u32(out, OP_ANCHOR_HASH);
+                put_u32(out, slot as u32);
+                put_data(out, hash);
+            }
+            Expr::CdHash(ref hash) => {
+                put_u32(out, OP_CD_HASH);
+                put_data(out, hash);
+            }
+            Expr::InfoKeyValue(ref key, ref value) => {
+                put_u32(out, OP_INFO_KEY_VALUE);
+                put_data(out, key.as_bytes());
+                put_data(out, value.as_bytes());
+            }
+            Expr::InfoKeyField(ref key, ref m) => {
+                put_u32(out, OP_INFO_KEY_FIELD);
+                put_data(out, key.as_bytes());
+                m.encode(out);
+            }
+            Expr::EntitlementField(ref key, ref m) => {
+                put_u32(out, OP_ENTITLEMENT_FIELD);
+                put_data(out, key.as_bytes());
+                m.encode(out);
+            }
+            Expr::CertField(slot, ref field, ref m) => {
+                put_u32(out, OP_CERT_FIELD);
+                put_u32(out, slot as u32);
+                put_data(out, field.as_bytes());
+                m.encode(out);
+            }
+            Expr::CertGeneric(slot, ref oid, ref m) => {
+                put_u32(out, OP_CERT_GENERIC);
+                put_u32(out, slot as u32);
+                put_data(out, oid);
+                m.encode(out);
+            }
+            Expr::CertPolicy(slot, ref oid, ref m) => {
+                put_u32(out, OP_CERT_POLICY);
+                put_u32(out, slot as u32);
+                put_data(out, oid);
+                m.encode(out);
+            }
+            Expr::TrustedCert(slot) => {
+                put_u32(out, OP_TRUSTED_CERT);
+                put_u32(out, slot as u32);
+            }
+            Expr::TrustedCerts => put_u32(out, OP_TRUSTED_CERTS),
+            Expr::NamedAnchor(ref name) => {
+                put_u32(out, OP_NAMED_ANCHOR);
+                put_data(out, name.as_bytes());
+            }
+            Expr::NamedCode(ref name) => {
+                put_u32(out, OP_NAMED_CODE);
+                put_data(out, name.as_bytes());
+            }
+            Expr::Platform(platform) => {
+                put_u32(out, OP_PLATFORM);
+                put_u32(out, platform);
+            }
+            Expr::Notarized => put_u32(out, OP_NOTARIZED),
+            Expr::LegacyDevId => put_u32(out, OP_LEGACY_DEV_ID),
+            Expr::Not(ref expr) => {
+                put_u32(out, OP_NOT);
+                expr.encode(out);
+            }
+            Expr::And(ref left, ref right) => {
+                put_u32(out, OP_AND);
+                left.encode(out);
+                right.encode(out);
+            }
+            Expr::Or(ref left, ref right) => {
+                put_u32(out, OP_OR);
+                left.encode(out);
+                right.encode(out);
+            }
+        }
+    }
+
+    /// `left and right`
+    pub fn and(self, right: Expr) -> Expr {
+        Expr::And(Box::new(self), Box::new(right))
+    }
+
+    /// The expression as a `CSMAGIC_REQUIREMENT` blob
+    pub fn to_blob(&self) -> Vec<u8> {
+        let mut payload = vec![];
+        put_u32(&mut payload, EXPR_FORM);
+        self.encode(&mut payload);
+        blob(CSMAGIC_REQUIREMENT, &payload)
+    }
+
+    /// Text of `self` nested in an expression of precedence `level`, as
+    /// `codesign -d -r-` prints it
+    fn write(&self, f: &mut fmt::Formatter, level: Level) -> fmt::Result {
+        match *self {
+            Expr::False => write!(f, "never"),
+            Expr::True => write!(f, "always"),
+            Expr::Identifier(ref id) => {
+                write!(f, "identifier ")?;
+                write_data(f, id.as_bytes(), false)
+            }
+            Expr::AppleAnchor => write!(f, "anchor apple"),
+            Expr::AppleGenericAnchor => write!(f, "anchor apple generic"),
+            Expr::AnchorHash(slot, ref hash) => {
+                write!(
+                    f,
+                    "certificate {} = H\"{}\"",
+                    CertSlot(slot),
+                    hex::encode(hash)
+                )
+            }
+            Expr::CdHash(ref hash) => write!(f, "cdhash H\"{}\"", hex::encode(hash)),
+            Expr::InfoKeyValue(ref key, ref value) => {
+                write!(f, "info[")?;
+                write_data(f, key.as_bytes(), true)?;
+                write!(f, "] = ")?;
+                write_data(f, value.as_bytes(), false)
+            }
+            Expr::InfoKeyField(ref key, ref m) => {
+                write!(f, "info[")?;
+                write_data(f, key.as_bytes(), true)?;
+                write!(f, "]{}", m)
+            }
+            Expr::EntitlementField(ref key, ref m) => {
+                write!(f, "entitlement[")?;
+                write_data(f, key.as_bytes(), true)?;
+                write!(f, "]{}", m)
+            }
+            Expr::CertField(slot, ref field, ref m) => {
+                write!(f, "certificate {}[", CertSlot(slot))?;
+                write_data(f, field.as_bytes(), true)?;
+                write!(f, "]{}", m)
+            }
+            Expr::CertGeneric(slot, ref oid, ref m) => {
+                write!(
+                    f,
+                    "certificate {}[field.{}]{}",
+                    CertSlot(slot),
+                    oid_string(oid),
+                    m
+                )
+            }
+            Expr::CertPolicy(slot, ref oid, ref m) => {
+                write!(
+                    f,
+                    "certificate {}[policy.{}]{}",
+                    CertSlot(slot),
+                    oid_string(oid),
+                    m
+                )
+            }
+            Expr::TrustedCert(slot) => write!(f, "certificate {} trusted", CertSlot(slot)),
+            Expr::TrustedCerts => write!(f, "anchor trusted"),
+            Expr::NamedAnchor(ref name) => {
+                write!(f, "anchor apple ")?;
+                write_data(f, name.as_bytes(), false)
+            }
+            Expr::NamedCode(ref name) => {
+                write!(f, "(")?;
+                write_data(f, name.as_bytes(), false)?;
+                write!(f, ")")
+            }
+            Expr::Platform(platform) => write!(f, "platform = {}", platform),
+            Expr::Notarized => write!(f, "notarized"),
+            Expr::LegacyDevId => write!(f, "legacy"),
+            Expr::Not(ref expr) => {
+                write!(f, "! ")?;
+                expr.write(f, Level::Primary)
+            }
+            Expr::And(ref left, ref right) => {
+                if level < Level::And {
+                    write!(f, "(")?;
+                }
+                left.write(f, Level::And)?;
+                write!(f, " and ")?;
+                right.write(f, Level::And)?;
+                if level < Level::And {
+                    write!(f, ")")?;
+                }
+                Ok(())
+            }
+            Expr::Or(ref left, ref right) => {
+                if level < Level::Or {
+                    write!(f, "(")?;
+                }
+                left.write(f, Level::Or)?;
+                write!(f, " or ")?;
+                right.write(f, Level::Or)?;
+                if level < Level::Or {
+                    write!(f, ")")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Precedence of the context an expression is printed in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Level {
+    Primary,
+    And,
+    Or,
+    Top,
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, Level::Top)
+    }
+}
+
+impl fmt::Display for Match {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (op, value, suffix) = match *self {
+            Match::Exists => return write!(f, " /* exists */"),
+            Match::Absent => return write!(f, " absent"),
+            Match::Equal(ref v) => (" = ", v, ""),
+            Match::Contains(ref v) => (" ~ ", v, ""),
+            Match::BeginsWith(ref v) => (" = ", v, "*"),
+            Match::EndsWith(ref v) => (" = *", v, ""),
+            Match::LessThan(ref v) => (" < ", v, ""),
+            Match::GreaterThan(ref v) => (" > ", v, ""),
+            Match::LessEqual(ref v) => (" <= ", v, ""),
+            Match::GreaterEqual(ref v) => (" >= ", v, ""),
+        };
+        write!(f, "{}", op)?;
+        write_data(f, value, false)?;
+        write!(f, "{}", suffix)
+    }
+}
+
+struct CertSlot(i32);
+
+impl fmt::Display for CertSlot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            LEAF_CERT => write!(f, "leaf"),
+            ANCHOR_CERT => write!(f, "root"),
+            slot => write!(f, "{}", slot),
+        }
+    }
+}
+
+/// Print a string value: bare when alphanumeric (dots allowed in field
+/// names), quoted when printable, as hex otherwise
+fn write_data(f: &mut fmt::Formatter, data: &[u8], dot_okay: bool) -> fmt::Result {
+    if data.is_empty() || data.iter().any(|b| !(0x20..=0x7e).contains(b)) {
+        return write!(f, "H\"{}\"", hex::encode(data));
+    }
+    if data
+        .iter()
+        .all(|&b| b.is_ascii_alphanumeric() || (dot_okay && b == b'.'))
+    {
+        return write!(f, "{}", String::from_utf8_lossy(data));
+    }
+    write!(f, "\"")?;
+    for &b in data {
+        if b == b'"' || b == b'\\' {
+            write!(f, "\\")?;
+        }
+        write!(f, "{}", b as char)?;
+    }
+    write!(f, "\"")
+}
+
+/// Dotted form of the contents of a DER OID
+pub fn oid_string(oid: &[u8]) -> String {
+    let mut arcs = vec![];
+    let mut arc = 0u64;
+    for &b in oid {
+        arc = arc << 7 | (b & 0x7f) as u64;
+        if b & 0x80 != 0 {
+            continue;
+        }
+        if arcs.is_empty() {
+            let first = (arc / 40).min(2);
+            arcs.push(first);
+            arcs.push(arc - first * 40);
+        } else {
+            arcs.push(arc);
+        }
+        arc = 0;
+    }
+    let arcs: Vec<String> = arcs.iter().map(|arc| arc.to_string()).collect();
+    arcs.join(".")
+}
+
+/// The requirements of a `CSMAGIC_REQUIREMENTS` blob, by type
+pub fn parse_requirements(blob: &[u8]) -> Result<Vec<(u32, Expr)>> {
+    if blob.len() < 12 || NetworkEndian::read_u32(blob) != CSMAGIC_REQUIREMENTS {
+        return Err(BadRequirement("not a requirement set".to_string()).into());
+    }
+    let count = NetworkEndian::read_u32(&blob[8..]) as usize;
+    let mut requirements = vec![];
+    for i in 0..count {
+        let entry = 12 + i * 8;
+        if entry + 8 > blob.len() {
+            return Err(BadRequirement("truncated requirement set".to_string()).into());
+        }
+        let kind = NetworkEndian::read_u32(&blob[entry..]);
+        let offset = NetworkEndian::read_u32(&blob[entry + 4..]) as usize;
+        if offset + 12 > blob.len()
+            || NetworkEndian::read_u32(&blob[offset..]) != CSMAGIC_REQUIREMENT
+        {
+            return Err(BadRequirement(format!("bad requirement at {}", offset)).into());
+        }
+        let length = NetworkEndian::read_u32(&blob[offset + 4..]) as usize;
+        if length < 12 || offset + length > blob.len() {
+            return Err(BadRequirement(format!("bad requirement length {}", length)).into());
+        }
+        let form = NetworkEndian::read_u32(&blob[offset + 8..]);
+        if form != EXPR_FORM {
+            return Err(BadRequirement(format!("unsupported form {}", form)).into());
+        }
+        requirements.push((kind, Expr::parse(&blob[offset + 12..offset + length])?));
+    }
+    Ok(requirements)
+}
+
+/// A `CSMAGIC_REQUIREMENTS` blob holding `requirements`, by type
+pub fn encode_requirements(requirements: &[(u32, Expr)]) -> Vec<u8> {
+    let mut index = vec![];
+    let mut blobs = vec![];
+    let mut offset = 12 + 8 * requirements.len();
+    put_u32(&mut index, requirements.len() as u32);
+    for &(kind, ref expr) in requirements {
+        let requirement = expr.to_blob();
+        put_u32(&mut index, kind);
+        put_u32(&mut index, offset as u32);
+        offset += requirement.len();
+        blobs.extend(requirement);
+    }
+    index.extend(blobs);
+    blob(CSMAGIC_REQUIREMENTS, &index)
+}
+
+/// The designated requirement of a requirement set, if it holds one
+pub fn designated_requirement(blob: &[u8]) -> Result<Option<Expr>> {
+    Ok(parse_requirements(blob)?
+        .into_iter()
+        .find(|&(kind, _)| kind == kSecDesignatedRequirementType)
+        .map(|(_, expr)| expr))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use consts::kSecDesignatedRequirementType;
+    use requirements::{
+        designated_requirement, encode_requirements, Expr, Match, ANCHOR_CERT, LEAF_CERT,
+    };
+
+    #[test]
+    fn test_designated_requirement() {
+        let developer_id = vec![0x2a, 0x86, 0x48, 0x86, 0xf7, 0x63, 0x64, 0x06, 0x02, 0x06];
+        let dr = Expr::Identifier("com.example.tool".to_string())
+            .and(Expr::AppleGenericAnchor)
+            .and(Expr::CertGeneric(1, developer_id, Match::Exists))
+            .and(Expr::CertField(
+                LEAF_CERT,
+                "subject.OU".to_string(),
+                Match::Equal(b"ABCDE12345".to_vec()),
+            ));
+        let blob = encode_requirements(&[(kSecDesignatedRequirementType, dr.clone())]);
+        assert_eq!(designated_requirement(&blob).unwrap(), Some(dr.clone()));
+        assert_eq!(
+            dr.to_string(),
+            "identifier \"com.example.tool\" and anchor apple generic and \
+             certificate 1[field.1.2.840.113635.100.6.2.6] /* exists */ and \
+             certificate leaf[subject.OU] = ABCDE12345"
+        );
+
+        let either = Expr::Or(
+            Box::new(Expr::CdHash(vec![0xab; 20])),
+            Box::new(Expr::AnchorHash(ANCHOR_CERT, vec![0x01; 20])),
+        );
+        assert_eq!(
+            Expr::AppleAnchor.and(either).to_string(),
+            format!(
+                "anchor apple and (cdhash H\"{}\" or certificate root = H\"{}\")",
+                "ab".repeat(20),
+                "01".repeat(20)
+            )
+        );
+        assert_eq!(
+            designated_requirement(&encode_requirements(&[])).unwrap(),
+            None
+        );
+    }
+}
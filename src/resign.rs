@@ -0,0 +1,498 @@
+#![allow(dead_code)]
+
+use plist::{Dictionary, Value};
+use tempfile::TempDir;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, DateTime, ZipArchive, ZipWriter};
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use errors::{CDMachError::*, Result};
+
+use bundle::Bundle;
+use cms::SigningIdentity;
+use deep::{sign_deep, ComponentSettings, DeepSigningConfig};
+use der::encode_der_entitlements;
+use ipa::{S_IFLNK, S_IFMT};
+use profile::{ProvisioningProfile, EMBEDDED_PROFILE_PATH};
+use signer::SigningSettings;
+use verify::find_nested_code;
+
+/// How to re-sign an app with a new provisioning profile
+#[derive(Debug, Clone)]
+pub struct ResignOptions {
+    pub identity: Arc<SigningIdentity>,
+    /// `embedded.mobileprovision` of the app
+    pub profile: Vec<u8>,
+    /// profiles of nested apps and extensions, keyed by path relative to the
+    /// app; those without one get the app's
+    pub nested_profiles: BTreeMap<String, Vec<u8>>,
+    /// settings shared by every component (hash types, flags...); identity,
+    /// team identifier, identifier and entitlements are replaced
+    pub settings: SigningSettings,
+}
+
+/// Re-sign every app in the `Payload/` of the IPA at `input` and write the
+/// result to `output`.
+///
+/// The archive is unpacked to a temporary directory; entries are written
+/// back sorted, with fixed timestamps, so the same input and options give
+/// the same archive. Returns the components signed, as `sign_deep` does,
+/// with paths relative to the archive root.
+pub fn resign_ipa(
+    input: &Path,
+    output: &Path,
+    options: &ResignOptions,
+) -> Result<Vec<(String, String)>> {
+    let mut archive = ZipArchive::new(File::open(input)?)?;
+    // removed when dropped
+    let work = TempDir::new()?;
+    extract(&mut archive, work.path())?;
+    let signed = resign_payload(work.path(), options)?;
+    write_archive(work.path(), File::create(output)?)?;
+    Ok(signed)
+}
+
+/// Re-sign the apps of an unpacked IPA
+fn resign_payload(root: &Path, options: &ResignOptions) -> Result<Vec<(String, String)>> {
+    let mut apps = vec![];
+    for entry in fs::read_dir(root.join("Payload"))? {
+        let path = entry?.path();
+        if path.extension() == Some(OsStr::new("app")) {
+            apps.push(path);
+        }
+    }
+    if apps.is_empty() {
+        return Err(NotABundle("no Payload/*.app in archive".to_string()).into());
+    }
+    apps.sort();
+
+    let mut signed = vec![];
+    for app in apps {
+        let prefix = app
+            .strip_prefix(root)
+            .unwrap()
+            .to_string_lossy()
+            .to_string();
+        for (relative, cdhash) in resign_app(&app, options)? {
+            let path = match relative.as_str() {
+                "" => prefix.clone(),
+                relative => format!("{}/{}", prefix, relative),
+            };
+            signed.push((path, cdhash));
+        }
+    }
+    Ok(signed)
+}
+
+/// Re-sign an (unpacked) app bundle inside out: install the provisioning
+/// profiles, derive each app's and extension's entitlements from its
+/// profile, and sign everything with `options.identity`.
+pub fn resign_app(app: &Path, options: &ResignOptions) -> Result<Vec<(String, String)>> {
+    let identity = &options.identity;
+    let mut config = DeepSigningConfig {
+        settings: SigningSettings {
+            team_id: identity.team_id(),
+            identity: Some(identity.clone()),
+            ..options.settings.clone()
+        },
+        ..Default::default()
+    };
+
+    let mut bundles = vec![];
+    collect_bundles(app, &Bundle::open(app)?, &mut bundles)?;
+    for (relative, bundle) in bundles {
+        let profile_path = bundle.contents.join(EMBEDDED_PROFILE_PATH);
+        let profile = match options.nested_profiles.get(&relative) {
+            Some(profile) => profile,
+            // nested code other than apps and extensions is not provisioned
+            None if relative.is_empty() || is_provisioned(&bundle) || profile_path.is_file() => {
+                &options.profile
+            }
+            None => continue,
+        };
+        let parsed = ProvisioningProfile::parse(profile)?;
+        if !parsed.lists_certificate(&identity.certificate) {
+            return Err(ProfileMismatch(format!(
+                "{} does not list the signing certificate",
+                parsed.name.unwrap_or_default()
+            ))
+            .into());
+        }
+        let identifier = match bundle.identifier() {
+            Some(identifier) => identifier.to_string(),
+            None => return Err(MissingInfoKey("CFBundleIdentifier".to_string()).into()),
+        };
+        let entitlements = profile_entitlements(&parsed, &identifier);
+        match entitlements
+            .get("application-identifier")
+            .and_then(Value::as_string)
+        {
+            Some(app_id) if app_id.split_once('.').map(|(_, id)| id) == Some(&identifier[..]) => {}
+            app_id => {
+                return Err(ProfileMismatch(format!(
+                    "{} is for {}, not {}",
+                    parsed.name.unwrap_or_default(),
+                    app_id.unwrap_or("no application"),
+                    identifier
+                ))
+                .into())
+            }
+        }
+        File::create(&profile_path)?.write_all(profile)?;
+
+        let mut xml = vec![];
+        Value::Dictionary(entitlements.clone()).to_writer_xml(&mut xml)?;
+        config.components.insert(
+            relative,
+            ComponentSettings {
+                identifier: Some(identifier),
+                entitlements: Some(xml),
+                der_entitlements: Some(encode_der_entitlements(&entitlements)?),
+            },
+        );
+    }
+    sign_deep(app, &config)
+}
+
+/// Whether `bundle` is an app or an app extension, which run with the
+/// entitlements of a provisioning profile
+fn is_provisioned(bundle: &Bundle) -> bool {
+    matches!(
+        bundle.path.extension().and_then(OsStr::to_str),
+        Some("app") | Some("appex")
+    )
+}
+
+/// `bundle` and the bundles nested in it, keyed by path relative to `root`
+fn collect_bundles(
+    root: &Path,
+    bundle: &Bundle,
+    bundles: &mut Vec<(String, Bundle)>,
+) -> Result<()> {
+    let relative = bundle
+        .path
+        .strip_prefix(root)
+        .map(|p| p.to_string_lossy().replace('\\', "/"))
+        .unwrap_or_default();
+    bundles.push((relative, bundle.clone()));
+    for nested in find_nested_code(bundle)? {
+        let path = bundle.contents.join(nested);
+        if Bundle::is_bundle(&path) {
+            collect_bundles(root, &Bundle::open(path)?, bundles)?;
+        }
+    }
+    Ok(())
+}
+
+/// The entitlements a profile grants, for the app `identifier`: wildcard
+/// strings (`ABCDE12345.*`, `ABCDE12345.com.example.*`, also inside arrays)
+/// that match `<prefix>.<identifier>` are resolved to it
+pub fn profile_entitlements(profile: &ProvisioningProfile, identifier: &str) -> Dictionary {
+    let resolve = |value: &Value| match *value {
+        Value::String(ref s) if s.ends_with('*') => {
+            let prefix = s.split('.').next().unwrap_or_default();
+            let resolved = format!("{}.{}", prefix, identifier);
+            if resolved.starts_with(&s[..s.len() - 1]) {
+                Value::String(resolved)
+            } else {
+                value.clone()
+            }
+        }
+        ref value => value.clone(),
+    };
+    let mut entitlements = Dictionary::new();
+    for (key, value) in profile.entitlements.iter() {
+        let value = match *value {
+            Value::Array(ref values) => Value::Array(values.iter().map(&resolve).collect()),
+            ref value => resolve(value),
+        };
+        entitlements.insert(key.clone(), value);
+    }
+    entitlements
+}
+
+/// Unpack every entry of `archive` below `dir`, keeping unix permissions and
+/// symbolic links.
+///
+/// Symbolic links must point inside the archive: absolute targets and
+/// targets with `..` are refused, as are entries written through a link.
+fn extract<R: Read + Seek>(archive: &mut ZipArchive<R>, dir: &Path) -> Result<()> {
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let path = match entry.enclosed_name() {
+            Some(name) if !crosses_symlink(dir, name) => dir.join(name),
+            _ => return Err(UnexpectedArchivePath(entry.name().to_string()).into()),
+        };
+        if entry.is_dir() {
+            fs::create_dir_all(&path)?;
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut data = vec![];
+        entry.read_to_end(&mut data)?;
+        match entry.unix_mode() {
+            Some(mode) if mode & S_IFMT == S_IFLNK => {
+                let target = String::from_utf8_lossy(&data).to_string();
+                let escapes = Path::new(&target)
+                    .components()
+                    .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir));
+                if escapes {
+                    return Err(
+                        UnexpectedArchivePath(format!("{} -> {}", entry.name(), target)).into(),
+                    );
+                }
+                symlink(&target, &path)?;
+            }
+            mode => {
+                File::create(&path)?.write_all(&data)?;
+                set_mode(&path, mode.unwrap_or(0o644))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether `name`, or a directory on its way below `dir`, is a symbolic link
+fn crosses_symlink(dir: &Path, name: &Path) -> bool {
+    let mut path = dir.to_path_buf();
+    name.components().any(|component| {
+        path.push(component);
+        fs::symlink_metadata(&path)
+            .map(|metadata| metadata.file_type().is_symlink())
+            .unwrap_or(false)
+    })
+}
+
+/// Archive the tree at `dir`, sorted by path, with fixed timestamps
+fn write_archive<W: Write + Seek>(dir: &Path, writer: W) -> Result<()> {
+    let mut entries = vec![];
+    walk(dir, dir, &mut entries)?;
+    entries.sort();
+
+    let mut zip = ZipWriter::new(writer);
+    for (name, path) in entries {
+        let metadata = fs::symlink_metadata(&path)?;
+        let options = FileOptions::default()
+            .last_modified_time(DateTime::default())
+            .unix_permissions(mode(&metadata));
+        if metadata.file_type().is_symlink() {
+            let target = fs::read_link(&path)?;
+            zip.add_symlink(name, target.to_string_lossy(), options)?;
+        } else if metadata.is_dir() {
+            zip.add_directory(name, options.compression_method(CompressionMethod::Stored))?;
+        } else {
+            zip.start_file(name, options)?;
+            io::copy(&mut File::open(&path)?, &mut zip)?;
+        }
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+/// Archive names and paths of everything below `dir`
+fn walk(root: &Path, dir: &Path, entries: &mut Vec<(String, PathBuf)>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let name = path
+            .strip_prefix(root)
+            .unwrap()
+            .to_string_lossy()
+            .replace('\\', "/");
+        if entry.file_type()?.is_dir() {
+            entries.push((format!("{}/", name), path.clone()));
+            walk(root, &path, entries)?;
+        } else {
+            entries.push((name, path));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(target: &str, path: &Path) -> Result<()> {
+    ::std::os::unix::fs::symlink(target, path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn symlink(target: &str, path: &Path) -> Result<()> {
+    Err(UnexpectedArchivePath(format!("{} -> {}", path.display(), target)).into())
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o777))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o777
+}
+
+#[cfg(not(unix))]
+fn mode(metadata: &fs::Metadata) -> u32 {
+    if metadata.is_dir() {
+        0o755
+    } else {
+        0o644
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use bundle::parse_dictionary;
+    use cms::tests::identity;
+    use codedir::CodeSignature;
+    use errors::CDMachError::{self, *};
+    use ipa::tests::{signed_payload, zip_dir};
+    use ipa::Ipa;
+    use plist::Value;
+    use profile::EMBEDDED_PROFILE_PATH;
+    use resign::{extract, resign_ipa, ResignOptions};
+    use signer::SigningSettings;
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::io::{Cursor, Write};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+    use zip::write::FileOptions;
+    use zip::{ZipArchive, ZipWriter};
+
+    /// Development profile of team ABCDE12345 for `com.example.*`, listing
+    /// the certificate of `cms::tests::identity`
+    pub const EXAMPLE_PROFILE: &[u8] = include_bytes!("../testdata/example.mobileprovision");
+    /// The same for `com.example.other` only
+    pub const OTHER_APP_PROFILE: &[u8] = include_bytes!("../testdata/other_app.mobileprovision");
+
+    #[test]
+    fn test_resign_ipa() {
+        let payload = signed_payload();
+        let dir = TempDir::new().unwrap();
+        let input = dir.path().join("in.ipa");
+        fs::write(&input, zip_dir(payload.path(), "")).unwrap();
+        let mut options = ResignOptions {
+            identity: Arc::new(identity()),
+            profile: EXAMPLE_PROFILE.to_vec(),
+            nested_profiles: BTreeMap::new(),
+            settings: SigningSettings::default(),
+        };
+
+        let output = dir.path().join("out.ipa");
+        let signed = resign_ipa(&input, &output, &options).unwrap();
+        let paths: Vec<_> = signed.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "Payload/Foo.app/Frameworks/Bar.framework",
+                "Payload/Foo.app/PlugIns/Ext.appex",
+                "Payload/Foo.app",
+            ]
+        );
+        let again = dir.path().join("again.ipa");
+        resign_ipa(&input, &again, &options).unwrap();
+        let data = fs::read(&output).unwrap();
+        assert_eq!(data, fs::read(&again).unwrap());
+
+        let ipa = Ipa::parse(Cursor::new(data)).unwrap();
+        let report = ipa.verify().unwrap();
+        assert!(report.is_valid(), "{:?}", report);
+        for &(app, executable, identifier) in &[
+            ("Payload/Foo.app", "Foo", "com.example.foo"),
+            (
+                "Payload/Foo.app/PlugIns/Ext.appex",
+                "Ext",
+                "com.example.ext",
+            ),
+        ] {
+            assert_eq!(
+                &ipa.files[&format!("{}/{}", app, EMBEDDED_PROFILE_PATH)][..],
+                EXAMPLE_PROFILE
+            );
+            let executable = &ipa.files[&format!("{}/{}", app, executable)];
+            let signatures = CodeSignature::parse_image(None, executable).unwrap();
+            let entitlements = parse_dictionary(signatures[0].entitlements().unwrap()).unwrap();
+            let app_id = format!("ABCDE12345.{}", identifier);
+            assert_eq!(
+                entitlements.get("application-identifier"),
+                Some(&Value::String(app_id.clone()))
+            );
+            assert_eq!(
+                entitlements.get("keychain-access-groups"),
+                Some(&Value::Array(vec![Value::String(app_id)]))
+            );
+            assert_eq!(
+                entitlements.get("get-task-allow"),
+                Some(&Value::Boolean(true))
+            );
+        }
+        let framework = "Payload/Foo.app/Frameworks/Bar.framework";
+        assert!(!ipa
+            .files
+            .contains_key(&format!("{}/{}", framework, EMBEDDED_PROFILE_PATH)));
+
+        // a profile for another app
+        options.profile = OTHER_APP_PROFILE.to_vec();
+        let err = resign_ipa(&input, &dir.path().join("other.ipa"), &options).unwrap_err();
+        match err.downcast_ref::<CDMachError>() {
+            Some(&ProfileMismatch(_)) => {}
+            _ => panic!("{}", err),
+        }
+    }
+
+    /// Extract an archive of files and, when `link` is set, symbolic links
+    /// to `data`; whether that succeeded
+    fn unpack(entries: &[(&str, &str, bool)]) -> (bool, TempDir) {
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        for &(name, data, link) in entries {
+            if link {
+                zip.add_symlink(name, data, FileOptions::default()).unwrap();
+            } else {
+                zip.start_file(name, FileOptions::default()).unwrap();
+                zip.write_all(data.as_bytes()).unwrap();
+            }
+        }
+        let data = zip.finish().unwrap().into_inner();
+        let dir = TempDir::new().unwrap();
+        let result = extract(&mut ZipArchive::new(Cursor::new(data)).unwrap(), dir.path());
+        (result.is_ok(), dir)
+    }
+
+    #[test]
+    fn test_extract_escapes() {
+        let (ok, dir) = unpack(&[
+            ("Payload/A.app/A", "a", false),
+            ("Payload/A.app/Current", "A", true),
+        ]);
+        assert!(ok);
+        assert!(dir.path().join("Payload/A.app/Current").is_file());
+
+        for target in &["/etc", "../..", "a/../../b"] {
+            assert!(!unpack(&[("Payload/x", target, true)]).0, "{}", target);
+        }
+
+        // a file written through a link extracted before it
+        let (ok, dir) = unpack(&[
+            ("Payload/x", "y", true),
+            ("Payload/x/passwd", "root", false),
+        ]);
+        assert!(!ok);
+        assert!(!dir.path().join("Payload/y/passwd").exists());
+    }
+}
@@ -0,0 +1,612 @@
+#![allow(dead_code)]
+
+use hex;
+use plist::{Dictionary, Value};
+use ring::digest;
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use errors::{CDMachError::*, Result};
+
+use bundle::{parse_dictionary, Bundle};
+use codedir::CodeSignature;
+use consts::CSSLOT_RESOURCEDIR;
+use requirements::{designated_requirement, Expr};
+use rules::{ResourceKind, ResourceRules};
+
+/// Location of the resource envelope, relative to the bundle contents
+pub const CODE_RESOURCES_PATH: &str = "_CodeSignature/CodeResources";
+
+/// How a single resource is sealed
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ResourceSeal {
+    /// SHA-1 of the file (`hash`)
+    pub hash: Option<Vec<u8>>,
+    /// SHA-256 of the file (`hash2`)
+    pub hash2: Option<Vec<u8>>,
+    /// the file may be absent
+    pub optional: bool,
+    /// target of a sealed symlink
+    pub symlink: Option<String>,
+    /// designated requirement of nested code
+    pub requirement: Option<String>,
+    /// cdhash of nested code
+    pub cdhash: Option<Vec<u8>>,
+}
+
+impl ResourceSeal {
+    /// Parse a `files`/`files2` value: either bare SHA-1 data or a dictionary
+    fn parse(value: &Value) -> Result<ResourceSeal> {
+        if let Some(hash) = value.as_data() {
+            return Ok(ResourceSeal {
+                hash: Some(hash.to_vec()),
+                ..Default::default()
+            });
+        }
+        let dict = match value.as_dictionary() {
+            Some(dict) => dict,
+            None => return Err(UnexpectedPlist("bad resource seal".to_string()).into()),
+        };
+        let data = |key: &str| dict.get(key).and_then(Value::as_data).map(|d| d.to_vec());
+        let string = |key: &str| {
+            dict.get(key)
+                .and_then(Value::as_string)
+                .map(|s| s.to_string())
+        };
+        Ok(ResourceSeal {
+            hash: data("hash"),
+            hash2: data("hash2"),
+            optional: dict
+                .get("optional")
+                .and_then(Value::as_boolean)
+                .unwrap_or(false),
+            symlink: string("symlink"),
+            requirement: string("requirement"),
+            cdhash: data("cdhash"),
+        })
+    }
+
+    /// Sealed nested code (a framework, plug-in, helper...)
+    pub fn is_nested(&self) -> bool {
+        self.requirement.is_some() || self.cdhash.is_some()
+    }
+
+    /// The value stored in `files`/`files2`: bare data for a plain SHA-1
+    /// seal, a dictionary otherwise
+    pub fn to_value(&self) -> Value {
+        if let (&Some(ref hash), &None, &None, &None, false) = (
+            &self.hash,
+            &self.hash2,
+            &self.symlink,
+            &self.cdhash,
+            self.optional,
+        ) {
+            return Value::Data(hash.clone());
+        }
+        let mut dict = Dictionary::new();
+        if let Some(ref cdhash) = self.cdhash {
+            dict.insert("cdhash".to_string(), Value::Data(cdhash.clone()));
+        }
+        if let Some(ref hash) = self.hash {
+            dict.insert("hash".to_string(), Value::Data(hash.clone()));
+        }
+        if let Some(ref hash2) = self.hash2 {
+            dict.insert("hash2".to_string(), Value::Data(hash2.clone()));
+        }
+        if self.optional {
+            dict.insert("optional".to_string(), Value::Boolean(true));
+        }
+        if let Some(ref requirement) = self.requirement {
+            dict.insert(
+                "requirement".to_string(),
+                Value::String(requirement.clone()),
+            );
+        }
+        if let Some(ref symlink) = self.symlink {
+            dict.insert("symlink".to_string(), Value::String(symlink.clone()));
+        }
+        Value::Dictionary(dict)
+    }
+}
+
+/// `_CodeSignature/CodeResources`, the resource envelope of a bundle
+#[derive(Debug, Clone)]
+pub struct CodeResources {
+    /// Raw envelope, as sealed by `CSSLOT_RESOURCEDIR`
+    pub raw: Vec<u8>,
+    /// version 1 seals (SHA-1)
+    pub files: BTreeMap<String, ResourceSeal>,
+    /// version 2 seals (SHA-1 and SHA-256, symlinks, nested code)
+    pub files2: BTreeMap<String, ResourceSeal>,
+    /// version 1 resource rules
+    pub rules: Dictionary,
+    /// version 2 resource rules
+    pub rules2: Dictionary,
+}
+
+/// Differences between a resource envelope and the bundle on disk
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ResourceDiff {
+    /// present on disk but not sealed
+    pub added: Vec<String>,
+    /// sealed but missing on disk
+    pub removed: Vec<String>,
+    /// sealed with a different hash, symlink target or type
+    pub modified: Vec<String>,
+}
+
+impl ResourceDiff {
+    pub fn is_clean(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+impl CodeResources {
+    pub fn parse(data: &[u8]) -> Result<CodeResources> {
+        let dict = parse_dictionary(data)?;
+        let seals = |key: &str| -> Result<BTreeMap<String, ResourceSeal>> {
+            let mut seals = BTreeMap::new();
+            if let Some(files) = dict.get(key).and_then(Value::as_dictionary) {
+                for (path, value) in files.iter() {
+                    seals.insert(path.clone(), ResourceSeal::parse(value)?);
+                }
+            }
+            Ok(seals)
+        };
+        let rules = |key: &str| {
+            dict.get(key)
+                .and_then(Value::as_dictionary)
+                .cloned()
+                .unwrap_or_default()
+        };
+        Ok(CodeResources {
+            raw: data.to_vec(),
+            files: seals("files")?,
+            files2: seals("files2")?,
+            rules: rules("rules"),
+            rules2: rules("rules2"),
+        })
+    }
+
+    /// Seal `bundle` with the default rules for its layout.
+    ///
+    /// Files get SHA-1 and SHA-256 hashes, symlinks their target, and nested
+    /// code (which must already be signed) its cdhash and requirement.
+    pub fn generate(bundle: &Bundle) -> Result<CodeResources> {
+        let shallow = bundle.is_shallow();
+        let executable = bundle.relative_executable();
+        let executable = executable.as_ref().map(|e| e.as_str());
+        let mut rules = ResourceRules::default_rules(shallow)?;
+        let mut rules2 = ResourceRules::default_rules2(shallow)?;
+        let rules_dict = rules.to_dictionary();
+        let rules2_dict = rules2.to_dictionary();
+        rules.add_standard_exclusions(executable)?;
+        rules2.add_standard_exclusions(executable)?;
+
+        let mut files = BTreeMap::new();
+        for entry in rules.scan(&bundle.contents)? {
+            if entry.kind == ResourceKind::File {
+                let data = read_file(&bundle.contents.join(&entry.path))?;
+                let seal = ResourceSeal {
+                    hash: Some(digest::digest(&digest::SHA1, &data).as_ref().to_vec()),
+                    optional: entry.optional,
+                    ..Default::default()
+                };
+                files.insert(entry.path, seal);
+            }
+        }
+
+        let mut files2 = BTreeMap::new();
+        for entry in rules2.scan(&bundle.contents)? {
+            let path = bundle.contents.join(&entry.path);
+            let seal = match entry.kind {
+                ResourceKind::File => {
+                    let data = read_file(&path)?;
+                    ResourceSeal {
+                        hash: Some(digest::digest(&digest::SHA1, &data).as_ref().to_vec()),
+                        hash2: Some(digest::digest(&digest::SHA256, &data).as_ref().to_vec()),
+                        ..Default::default()
+                    }
+                }
+                ResourceKind::Symlink => ResourceSeal {
+                    symlink: Some(fs::read_link(&path)?.to_string_lossy().to_string()),
+                    ..Default::default()
+                },
+                ResourceKind::Nested => nested_seal(&path)?,
+            };
+            files2.insert(
+                entry.path,
+                ResourceSeal {
+                    optional: entry.optional,
+                    ..seal
+                },
+            );
+        }
+
+        let mut resources = CodeResources {
+            raw: vec![],
+            files,
+            files2,
+            rules: rules_dict,
+            rules2: rules2_dict,
+        };
+        resources.raw = resources.to_xml()?;
+        Ok(resources)
+    }
+
+    /// Serialize as an XML property list, keys sorted the way codesign does
+    pub fn to_xml(&self) -> Result<Vec<u8>> {
+        let seals = |seals: &BTreeMap<String, ResourceSeal>| {
+            let mut dict = Dictionary::new();
+            for (path, seal) in seals {
+                dict.insert(path.clone(), seal.to_value());
+            }
+            Value::Dictionary(dict)
+        };
+        let rules = |rules: &Dictionary| {
+            let sorted: BTreeMap<&String, &Value> = rules.iter().collect();
+            let mut dict = Dictionary::new();
+            for (pattern, rule) in sorted {
+                dict.insert(pattern.clone(), rule.clone());
+            }
+            Value::Dictionary(dict)
+        };
+
+        let mut root = Dictionary::new();
+        root.insert("files".to_string(), seals(&self.files));
+        root.insert("files2".to_string(), seals(&self.files2));
+        root.insert("rules".to_string(), rules(&self.rules));
+        root.insert("rules2".to_string(), rules(&self.rules2));
+        let mut buf = vec![];
+        Value::Dictionary(root).to_writer_xml(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Write the envelope to `_CodeSignature/CodeResources` of `bundle`
+    pub fn write(&self, bundle: &Bundle) -> Result<()> {
+        let path = bundle.contents.join(CODE_RESOURCES_PATH);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        File::create(&path)?.write_all(&self.raw)?;
+        Ok(())
+    }
+
+    /// Load the envelope of `bundle`
+    pub fn open(bundle: &Bundle) -> Result<CodeResources> {
+        CodeResources::parse(&read_file(&bundle.contents.join(CODE_RESOURCES_PATH))?)
+    }
+
+    /// Check the envelope against the `CSSLOT_RESOURCEDIR` special slot
+    pub fn verify_envelope(&self, cs: &CodeSignature) -> Result<Option<bool>> {
+        cs.verify_special_slot(CSSLOT_RESOURCEDIR, &self.raw)
+    }
+
+    /// The seals verification goes by: `files2` when present, `files` otherwise
+    pub fn seals(&self) -> &BTreeMap<String, ResourceSeal> {
+        if self.files2.is_empty() {
+            &self.files
+        } else {
+            &self.files2
+        }
+    }
+
+    /// The rules `seals()` were made with, falling back to the default rules
+    /// for the bundle layout, plus the exclusions every signature gets
+    pub fn resource_rules(&self, bundle: &Bundle) -> Result<ResourceRules> {
+        let executable = bundle.relative_executable();
+        self.resource_rules_for(bundle.is_shallow(), executable.as_ref().map(|e| e.as_str()))
+    }
+
+    /// `resource_rules` for a bundle described by its layout and the path of
+    /// its main executable relative to its contents
+    pub fn resource_rules_for(
+        &self,
+        shallow: bool,
+        executable: Option<&str>,
+    ) -> Result<ResourceRules> {
+        let mut rules = if !self.files2.is_empty() {
+            if self.rules2.is_empty() {
+                ResourceRules::default_rules2(shallow)?
+            } else {
+                ResourceRules::parse(&self.rules2)?
+            }
+        } else if self.rules.is_empty() {
+            ResourceRules::default_rules(shallow)?
+        } else {
+            ResourceRules::parse(&self.rules)?
+        };
+        rules.add_standard_exclusions(executable)?;
+        Ok(rules)
+    }
+
+    /// Paths of the nested code the envelope seals
+    pub fn nested_code(&self) -> Vec<String> {
+        self.seals()
+            .iter()
+            .filter(|&(_, seal)| seal.is_nested())
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+
+    /// Walk `bundle` with the envelope's rules and compare every resource
+    /// they select with its seal
+    pub fn diff(&self, bundle: &Bundle) -> Result<ResourceDiff> {
+        let seals = self.seals();
+        let entries = self.resource_rules(bundle)?.scan(&bundle.contents)?;
+
+        let mut diff = ResourceDiff::default();
+        for entry in &entries {
+            if !seals.contains_key(&entry.path) {
+                diff.added.push(entry.path.clone());
+            }
+        }
+        for (path, seal) in seals {
+            let path_on_disk = bundle.contents.join(path);
+            if fs::symlink_metadata(&path_on_disk).is_err() {
+                if !seal.optional {
+                    diff.removed.push(path.clone());
+                }
+            } else if !seal_matches(&path_on_disk, seal)? {
+                diff.modified.push(path.clone());
+            }
+        }
+        Ok(diff)
+    }
+
+    /// Compare the seals with an in-memory tree, such as the entries of an
+    /// archive: `files` and `symlinks` are keyed by path relative to the
+    /// bundle contents, and `rules` decide which of them must be sealed
+    pub fn diff_files<T: AsRef<[u8]>>(
+        &self,
+        rules: &ResourceRules,
+        files: &BTreeMap<String, T>,
+        symlinks: &BTreeMap<String, String>,
+    ) -> ResourceDiff {
+        let seals = self.seals();
+        let mut diff = ResourceDiff::default();
+        for entry in rules.select(files, symlinks) {
+            if !seals.contains_key(&entry.path) {
+                diff.added.push(entry.path);
+            }
+        }
+        for (path, seal) in seals {
+            let matches = if let Some(target) = symlinks.get(path) {
+                seal.symlink.as_ref() == Some(target)
+            } else if let Some(data) = files.get(path) {
+                seal.symlink.is_none() && (seal.is_nested() || hash_matches(seal, data.as_ref()))
+            } else if seal.is_nested() {
+                let prefix = format!("{}/", path);
+                if files.keys().any(|file| file.starts_with(&prefix)) {
+                    true
+                } else {
+                    if !seal.optional {
+                        diff.removed.push(path.clone());
+                    }
+                    continue;
+                }
+            } else {
+                if !seal.optional {
+                    diff.removed.push(path.clone());
+                }
+                continue;
+            };
+            if !matches {
+                diff.modified.push(path.clone());
+            }
+        }
+        diff
+    }
+}
+
+/// Seal of signed nested code (a bundle or a Mach-O file): the cdhash of its
+/// primary CodeDirectory, truncated to 20 bytes, and its designated
+/// requirement.
+///
+/// The designated requirement is the one the code's requirement set holds;
+/// ad-hoc code, which has none, is designated by its cdhash as codesign does.
+pub fn nested_seal(path: &Path) -> Result<ResourceSeal> {
+    let executable = if path.is_dir() {
+        Bundle::open(path)?.executable
+    } else {
+        path.to_path_buf()
+    };
+    let signatures = CodeSignature::parse_image(None, &read_file(&executable)?)?;
+    let cs = match signatures.first() {
+        Some(cs) => cs,
+        None => return Err(UnsignedCode(path.display().to_string()).into()),
+    };
+    let (code_directory, cd_hash) = match cs.code_directory() {
+        Some(cd) => cd,
+        None => return Err(NoCodeDirectory.into()),
+    };
+    let mut cdhash = hex::decode(cd_hash)?;
+    cdhash.truncate(20);
+
+    let designated = match cs.requirements() {
+        Some(requirements) => designated_requirement(requirements)?,
+        None => None,
+    };
+    let requirement = match designated {
+        Some(requirement) => requirement,
+        None if code_directory.is_adhoc() => Expr::CdHash(cdhash.clone()),
+        None => return Err(NoDesignatedRequirement(path.display().to_string()).into()),
+    };
+    Ok(ResourceSeal {
+        cdhash: Some(cdhash),
+        requirement: Some(requirement.to_string()),
+        ..Default::default()
+    })
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>> {
+    let mut data = vec![];
+    File::open(path)?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Compare a resource on disk with its seal
+fn seal_matches(path: &Path, seal: &ResourceSeal) -> Result<bool> {
+    let metadata = fs::symlink_metadata(path)?;
+    if let Some(ref target) = seal.symlink {
+        return Ok(metadata.file_type().is_symlink()
+            && fs::read_link(path)?.to_string_lossy() == target.as_str());
+    }
+    if seal.is_nested() {
+        // nested code is verified through its own signature
+        return Ok(metadata.is_dir() || metadata.is_file());
+    }
+    if !metadata.is_file() {
+        return Ok(false);
+    }
+
+    Ok(hash_matches(seal, &read_file(path)?))
+}
+
+/// Compare file contents with the strongest hash of a seal
+fn hash_matches(seal: &ResourceSeal, data: &[u8]) -> bool {
+    match (&seal.hash2, &seal.hash) {
+        (&Some(ref hash2), _) => digest::digest(&digest::SHA256, data).as_ref() == &hash2[..],
+        (&None, &Some(ref hash)) => digest::digest(&digest::SHA1, data).as_ref() == &hash[..],
+        (&None, &None) => false,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use bundle::tests::info_plist;
+    use bundle::Bundle;
+    use consts::{kSecDesignatedRequirementType, CSSLOT_RESOURCEDIR};
+    use requirements::{encode_requirements, Expr};
+    use resources::{nested_seal, CodeResources, ResourceDiff};
+    use signer::tests::{fake_macho, parse};
+    use signer::{sign_macho, SigningSettings};
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_generate() {
+        let dir = TempDir::new().unwrap();
+        let app = dir.path().join("Foo.app");
+        let contents = app.join("Contents");
+        fs::create_dir_all(contents.join("MacOS")).unwrap();
+        fs::create_dir_all(contents.join("Resources")).unwrap();
+        fs::create_dir_all(contents.join("Frameworks")).unwrap();
+        fs::write(
+            contents.join("Info.plist"),
+            info_plist("Foo", "com.example.foo"),
+        )
+        .unwrap();
+        fs::write(contents.join("MacOS/Foo"), fake_macho(0x1000)).unwrap();
+        fs::write(contents.join("Resources/a.txt"), b"a").unwrap();
+        symlink("a.txt", contents.join("Resources/link")).unwrap();
+        let mut dylib = fake_macho(0x1000);
+        sign_macho(&SigningSettings::default(), &mut dylib).unwrap();
+        fs::write(contents.join("Frameworks/libz.dylib"), &dylib).unwrap();
+
+        let bundle = Bundle::open(&app).unwrap();
+        let resources = CodeResources::generate(&bundle).unwrap();
+        // the main executable and Info.plist are sealed by the signature
+        assert_eq!(
+            resources.files2.keys().collect::<Vec<_>>(),
+            vec!["Frameworks/libz.dylib", "Resources/a.txt", "Resources/link"]
+        );
+        assert_eq!(
+            resources.files2["Resources/link"].symlink,
+            Some("a.txt".to_string())
+        );
+        assert_eq!(
+            resources.files2["Frameworks/libz.dylib"],
+            nested_seal(&contents.join("Frameworks/libz.dylib")).unwrap()
+        );
+
+        let parsed = CodeResources::parse(&resources.raw).unwrap();
+        assert_eq!(parsed.files, resources.files);
+        assert_eq!(parsed.files2, resources.files2);
+        assert_eq!(parsed.rules, resources.rules);
+        assert_eq!(parsed.rules2, resources.rules2);
+        assert_eq!(parsed.to_xml().unwrap(), resources.raw);
+        assert!(parsed.diff(&bundle).unwrap().is_clean());
+
+        fs::write(contents.join("Resources/a.txt"), b"changed").unwrap();
+        fs::remove_file(contents.join("Resources/link")).unwrap();
+        symlink("b.txt", contents.join("Resources/link")).unwrap();
+        fs::remove_file(contents.join("Frameworks/libz.dylib")).unwrap();
+        fs::write(contents.join("Resources/b.txt"), b"b").unwrap();
+        assert_eq!(
+            parsed.diff(&bundle).unwrap(),
+            ResourceDiff {
+                added: vec!["Resources/b.txt".to_string()],
+                removed: vec!["Frameworks/libz.dylib".to_string()],
+                modified: vec!["Resources/a.txt".to_string(), "Resources/link".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_verify_envelope() {
+        let resources = CodeResources::parse(
+            br#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0"><dict>
+<key>files</key><dict/>
+<key>files2</key><dict><key>a.txt</key><dict><key>hash2</key><data>AAAA</data></dict></dict>
+</dict></plist>"#,
+        )
+        .unwrap();
+        let mut settings = SigningSettings::default();
+        let mut macho = fake_macho(0x1000);
+        sign_macho(&settings, &mut macho).unwrap();
+        // nothing sealed
+        assert_eq!(resources.verify_envelope(&parse(&macho)).unwrap(), None);
+
+        settings
+            .set_special_slot(CSSLOT_RESOURCEDIR, &resources.raw)
+            .unwrap();
+        let mut macho = fake_macho(0x1000);
+        sign_macho(&settings, &mut macho).unwrap();
+        let cs = parse(&macho);
+        assert_eq!(resources.verify_envelope(&cs).unwrap(), Some(true));
+
+        let mut tampered = resources.raw.clone();
+        let at = tampered.len() - 20;
+        tampered[at] ^= 0x20;
+        let tampered = CodeResources {
+            raw: tampered,
+            ..resources.clone()
+        };
+        assert_eq!(tampered.verify_envelope(&cs).unwrap(), Some(false));
+    }
+
+    #[test]
+    fn test_nested_seal() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("tool");
+        let mut macho = fake_macho(0x2000);
+        sign_macho(&SigningSettings::default(), &mut macho).unwrap();
+        fs::write(&path, &macho).unwrap();
+        // ad-hoc code is designated by its cdhash
+        let seal = nested_seal(&path).unwrap();
+        let cdhash = seal.cdhash.clone().unwrap();
+        assert_eq!(seal.requirement, Some(Expr::CdHash(cdhash).to_string()));
+
+        let designated = Expr::Identifier("com.example.tool".to_string()).and(Expr::AppleAnchor);
+        let settings = SigningSettings {
+            requirements: Some(encode_requirements(&[(
+                kSecDesignatedRequirementType,
+                designated,
+            )])),
+            ..Default::default()
+        };
+        let mut macho = fake_macho(0x2000);
+        sign_macho(&settings, &mut macho).unwrap();
+        fs::write(&path, &macho).unwrap();
+        assert_eq!(
+            nested_seal(&path).unwrap().requirement,
+            Some("identifier \"com.example.tool\" and anchor apple".to_string())
+        );
+    }
+}
@@ -0,0 +1,496 @@
+#![allow(dead_code)]
+
+use plist::{Dictionary, Value};
+use regex::{self, Regex};
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+
+use errors::{CDMachError::*, Result};
+
+use consts::FAT_MAGIC_64;
+use mach_object::{FAT_MAGIC, MH_CIGAM, MH_CIGAM_64, MH_MAGIC, MH_MAGIC_64};
+
+/// the resource may be absent
+pub const RULE_OPTIONAL: u32 = 0x01;
+/// the resource is not sealed
+pub const RULE_OMITTED: u32 = 0x02;
+/// the resource is nested code, sealed by its own signature
+pub const RULE_NESTED: u32 = 0x04;
+/// the resource (and everything below it) is skipped outright
+pub const RULE_EXCLUSION: u32 = 0x10;
+
+/// Apple's default version 2 rules for bundles with a `Contents` directory,
+/// as `(pattern, weight, flags)`
+pub const DEFAULT_RULES2: [(&str, u32, u32); 13] = [
+    (r".*\.dSYM($|/)", 11, 0),
+    (r"^(.*/)?\.DS_Store$", 2000, RULE_OMITTED),
+    (
+        r"^(Frameworks|SharedFrameworks|PlugIns|Plug-ins|XPCServices|Helpers|MacOS|Library/(Automator|Spotlight|LoadableBundles))/",
+        10,
+        RULE_NESTED,
+    ),
+    (r"^.*", 1, 0),
+    (r"^Info\.plist$", 20, RULE_OMITTED),
+    (r"^PkgInfo$", 20, RULE_OMITTED),
+    (r"^Resources/", 20, 0),
+    (r"^Resources/.*\.lproj/", 1000, RULE_OPTIONAL),
+    (
+        r"^Resources/.*\.lproj/locversion.plist$",
+        1100,
+        RULE_OMITTED,
+    ),
+    (r"^Resources/Base\.lproj/", 1010, 0),
+    (r"^[^/]+$", 10, RULE_NESTED),
+    (r"^embedded\.provisionprofile$", 20, 0),
+    (r"^version\.plist$", 20, 0),
+];
+
+/// Apple's default version 1 rules for bundles with a `Contents` directory
+pub const DEFAULT_RULES: [(&str, u32, u32); 5] = [
+    (r"^Resources/", 1, 0),
+    (r"^Resources/.*\.lproj/", 1000, RULE_OPTIONAL),
+    (
+        r"^Resources/.*\.lproj/locversion.plist$",
+        1100,
+        RULE_OMITTED,
+    ),
+    (r"^Resources/Base\.lproj/", 1010, 0),
+    (r"^version.plist$", 1, 0),
+];
+
+/// Apple's default version 2 rules for shallow (iOS style) bundles
+pub const DEFAULT_SHALLOW_RULES2: [(&str, u32, u32); 10] = [
+    (r".*\.dSYM($|/)", 11, 0),
+    (r"^(.*/)?\.DS_Store$", 2000, RULE_OMITTED),
+    (r"^.*", 1, 0),
+    (r"^.*\.lproj/", 1000, RULE_OPTIONAL),
+    (r"^.*\.lproj/locversion.plist$", 1100, RULE_OMITTED),
+    (r"^Base\.lproj/", 1010, 0),
+    (r"^Info\.plist$", 20, RULE_OMITTED),
+    (r"^PkgInfo$", 20, RULE_OMITTED),
+    (r"^embedded\.provisionprofile$", 20, 0),
+    (r"^version\.plist$", 20, 0),
+];
+
+/// Apple's default version 1 rules for shallow (iOS style) bundles
+pub const DEFAULT_SHALLOW_RULES: [(&str, u32, u32); 5] = [
+    (r"^.*", 1, 0),
+    (r"^.*\.lproj/", 1000, RULE_OPTIONAL),
+    (r"^.*\.lproj/locversion.plist$", 1100, RULE_OMITTED),
+    (r"^Base\.lproj/", 1010, 0),
+    (r"^version.plist$", 1, 0),
+];
+
+/// A single resource rule: a regular expression over bundle relative paths
+#[derive(Debug, Clone)]
+pub struct ResourceRule {
+    /// the pattern as written in CodeResources
+    pub pattern: String,
+    regex: Regex,
+    pub weight: u32,
+    /// `RULE_*` flags
+    pub flags: u32,
+}
+
+impl ResourceRule {
+    pub fn new(pattern: &str, weight: u32, flags: u32) -> Result<ResourceRule> {
+        Ok(ResourceRule {
+            pattern: pattern.to_string(),
+            regex: Regex::new(pattern)?,
+            weight,
+            flags,
+        })
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        self.regex.is_match(path)
+    }
+
+    pub fn is_optional(&self) -> bool {
+        self.flags & RULE_OPTIONAL != 0
+    }
+
+    pub fn is_omitted(&self) -> bool {
+        self.flags & RULE_OMITTED != 0
+    }
+
+    pub fn is_nested(&self) -> bool {
+        self.flags & RULE_NESTED != 0
+    }
+
+    pub fn is_exclusion(&self) -> bool {
+        self.flags & RULE_EXCLUSION != 0
+    }
+
+    /// The plist value of this rule, in the form CodeResources stores it
+    pub fn to_value(&self) -> Value {
+        if self.flags == 0 && self.weight == 1 {
+            return Value::Boolean(true);
+        }
+        let mut dict = Dictionary::new();
+        if self.is_nested() {
+            dict.insert("nested".to_string(), Value::Boolean(true));
+        }
+        if self.is_omitted() {
+            dict.insert("omit".to_string(), Value::Boolean(true));
+        }
+        if self.is_optional() {
+            dict.insert("optional".to_string(), Value::Boolean(true));
+        }
+        dict.insert("weight".to_string(), Value::Real(self.weight as f64));
+        Value::Dictionary(dict)
+    }
+}
+
+/// How a path is sealed once the rules have been applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceKind {
+    /// a regular file, sealed by its hashes
+    File,
+    /// a symbolic link, sealed by its target
+    Symlink,
+    /// nested code (bundle or Mach-O), sealed by its cdhash and requirement
+    Nested,
+}
+
+/// A path selected for sealing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResourceEntry {
+    /// bundle relative path, `/` separated
+    pub path: String,
+    pub kind: ResourceKind,
+    /// the rule allows the resource to be absent
+    pub optional: bool,
+}
+
+/// An ordered set of resource rules, evaluated the way Apple's
+/// `ResourceBuilder` does.
+#[derive(Debug, Clone, Default)]
+pub struct ResourceRules {
+    pub rules: Vec<ResourceRule>,
+}
+
+impl ResourceRules {
+    /// Parse a `rules`/`rules2` dictionary, in file order
+    pub fn parse(dict: &Dictionary) -> Result<ResourceRules> {
+        let mut rules = vec![];
+        for (pattern, value) in dict.iter() {
+            let rule = match value {
+                Value::Boolean(true) => ResourceRule::new(pattern, 1, 0)?,
+                Value::Dictionary(ref info) => {
+                    let flag =
+                        |key: &str, flag: u32| match info.get(key).and_then(Value::as_boolean) {
+                            Some(true) => flag,
+                            _ => 0,
+                        };
+                    let weight = match info.get("weight") {
+                        Some(&Value::Real(weight)) => weight as u32,
+                        Some(&Value::Integer(ref weight)) => {
+                            weight.as_unsigned().unwrap_or(1) as u32
+                        }
+                        _ => 1,
+                    };
+                    ResourceRule::new(
+                        pattern,
+                        weight,
+                        flag("optional", RULE_OPTIONAL)
+                            | flag("omit", RULE_OMITTED)
+                            | flag("nested", RULE_NESTED),
+                    )?
+                }
+                _ => return Err(UnexpectedPlist(format!("bad resource rule {}", pattern)).into()),
+            };
+            rules.push(rule);
+        }
+        Ok(ResourceRules { rules })
+    }
+
+    pub fn from_table(table: &[(&str, u32, u32)]) -> Result<ResourceRules> {
+        let rules: Result<Vec<ResourceRule>> = table
+            .iter()
+            .map(|&(pattern, weight, flags)| ResourceRule::new(pattern, weight, flags))
+            .collect();
+        Ok(ResourceRules { rules: rules? })
+    }
+
+    /// Default version 2 rules for a bundle layout
+    pub fn default_rules2(shallow: bool) -> Result<ResourceRules> {
+        if shallow {
+            ResourceRules::from_table(&DEFAULT_SHALLOW_RULES2)
+        } else {
+            ResourceRules::from_table(&DEFAULT_RULES2)
+        }
+    }
+
+    /// Default version 1 rules for a bundle layout
+    pub fn default_rules(shallow: bool) -> Result<ResourceRules> {
+        if shallow {
+            ResourceRules::from_table(&DEFAULT_SHALLOW_RULES)
+        } else {
+            ResourceRules::from_table(&DEFAULT_RULES)
+        }
+    }
+
+    /// Skip everything matching `pattern`, whatever the other rules say
+    pub fn add_exclusion(&mut self, pattern: &str) -> Result<()> {
+        self.rules
+            .push(ResourceRule::new(pattern, 0, RULE_EXCLUSION)?);
+        Ok(())
+    }
+
+    /// Exclusions every signature gets: the main executable and the
+    /// signature's own files
+    pub fn add_standard_exclusions(&mut self, main_executable: Option<&str>) -> Result<()> {
+        if let Some(executable) = main_executable {
+            self.add_exclusion(&format!("^{}$", regex::escape(executable)))?;
+        }
+        self.add_exclusion("^_CodeSignature$")?;
+        self.add_exclusion("^CodeResources$")?;
+        self.add_exclusion("^_MASReceipt$")
+    }
+
+    /// The rule governing `path`: the first matching exclusion, otherwise
+    /// the matching rule with the highest weight (the first one on ties)
+    pub fn find_rule(&self, path: &str) -> Option<&ResourceRule> {
+        let mut best: Option<&ResourceRule> = None;
+        for rule in self.rules.iter().filter(|rule| rule.matches(path)) {
+            if rule.is_exclusion() {
+                return Some(rule);
+            }
+            if best.map(|b| rule.weight > b.weight).unwrap_or(true) {
+                best = Some(rule);
+            }
+        }
+        best
+    }
+
+    /// The rules as a `rules`/`rules2` dictionary, exclusions left out
+    pub fn to_dictionary(&self) -> Dictionary {
+        let mut dict = Dictionary::new();
+        for rule in self.rules.iter().filter(|rule| !rule.is_exclusion()) {
+            dict.insert(rule.pattern.clone(), rule.to_value());
+        }
+        dict
+    }
+
+    /// Walk `root` and return every path the rules seal, sorted by path.
+    ///
+    /// Directories matching a nested rule whose name has an extension are
+    /// nested bundles and are not descended into; files matching a nested
+    /// rule are nested code when they are Mach-O images.
+    pub fn scan(&self, root: &Path) -> Result<Vec<ResourceEntry>> {
+        let mut entries = vec![];
+        self.scan_dir(root, "", &mut entries)?;
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(entries)
+    }
+
+    /// Apply the rules to an in-memory tree, such as the entries of an
+    /// archive, the way `scan` does to a directory: `files` maps paths to
+    /// contents and `symlinks` paths to link targets.
+    pub fn select<T: AsRef<[u8]>>(
+        &self,
+        files: &BTreeMap<String, T>,
+        symlinks: &BTreeMap<String, String>,
+    ) -> Vec<ResourceEntry> {
+        let mut entries = BTreeMap::new();
+        'paths: for path in files.keys().chain(symlinks.keys()) {
+            let components: Vec<&str> = path.split('/').collect();
+            for depth in 1..components.len() {
+                let dir = components[..depth].join("/");
+                match self.find_rule(&dir) {
+                    Some(rule) if rule.is_exclusion() => continue 'paths,
+                    Some(rule) if rule.is_nested() && components[depth - 1].contains('.') => {
+                        let entry = ResourceEntry {
+                            path: dir.clone(),
+                            kind: ResourceKind::Nested,
+                            optional: rule.is_optional(),
+                        };
+                        entries.insert(dir, entry);
+                        continue 'paths;
+                    }
+                    _ => {}
+                }
+            }
+
+            let rule = match self.find_rule(path) {
+                Some(rule) if !rule.is_omitted() && !rule.is_exclusion() => rule,
+                _ => continue,
+            };
+            let kind = match files.get(path) {
+                None => ResourceKind::Symlink,
+                Some(data) if rule.is_nested() && is_macho_magic(data.as_ref()) => {
+                    ResourceKind::Nested
+                }
+                Some(_) => ResourceKind::File,
+            };
+            let entry = ResourceEntry {
+                path: path.clone(),
+                kind,
+                optional: rule.is_optional(),
+            };
+            entries.insert(path.clone(), entry);
+        }
+        entries.into_iter().map(|(_, entry)| entry).collect()
+    }
+
+    fn scan_dir(&self, dir: &Path, prefix: &str, entries: &mut Vec<ResourceEntry>) -> Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let path = format!("{}{}", prefix, name);
+            let file_type = entry.file_type()?;
+            let rule = self.find_rule(&path);
+
+            if file_type.is_dir() {
+                match rule {
+                    Some(rule) if rule.is_exclusion() => {}
+                    Some(rule) if rule.is_nested() && name.contains('.') => {
+                        entries.push(ResourceEntry {
+                            path,
+                            kind: ResourceKind::Nested,
+                            optional: rule.is_optional(),
+                        })
+                    }
+                    _ => self.scan_dir(&entry.path(), &format!("{}/", path), entries)?,
+                }
+                continue;
+            }
+
+            let rule = match rule {
+                Some(rule) if !rule.is_omitted() && !rule.is_exclusion() => rule,
+                _ => continue,
+            };
+            let kind = if file_type.is_symlink() {
+                ResourceKind::Symlink
+            } else if rule.is_nested() && is_macho(&entry.path())? {
+                ResourceKind::Nested
+            } else {
+                ResourceKind::File
+            };
+            entries.push(ResourceEntry {
+                path,
+                kind,
+                optional: rule.is_optional(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Whether the file at `path` starts with a Mach-O or fat magic
+pub fn is_macho(path: &Path) -> Result<bool> {
+    let mut magic = [0u8; 4];
+    let mut file = File::open(path)?;
+    if file.read(&mut magic)? < 4 {
+        return Ok(false);
+    }
+    Ok(is_macho_magic(&magic))
+}
+
+/// Whether `data` starts with a Mach-O or fat magic
+pub fn is_macho_magic(data: &[u8]) -> bool {
+    if data.len() < 4 {
+        return false;
+    }
+    let magic =
+        (data[0] as u32) << 24 | (data[1] as u32) << 16 | (data[2] as u32) << 8 | data[3] as u32;
+    match magic {
+        MH_MAGIC | MH_CIGAM | MH_MAGIC_64 | MH_CIGAM_64 | FAT_MAGIC | FAT_MAGIC_64 => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use rules::{ResourceEntry, ResourceKind, ResourceRules};
+    use signer::tests::fake_macho;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_default_rules2_precedence() {
+        let mut rules = ResourceRules::default_rules2(false).unwrap();
+        rules.add_standard_exclusions(Some("MacOS/Foo")).unwrap();
+
+        let verdict = |path: &str| {
+            let rule = rules.find_rule(path).unwrap();
+            (rule.pattern.as_str(), rule.weight)
+        };
+        assert_eq!(verdict("MacOS/Foo").0, "^MacOS/Foo$");
+        assert_eq!(verdict("Info.plist"), (r"^Info\.plist$", 20));
+        assert_eq!(verdict("Resources/.DS_Store").1, 2000);
+        assert_eq!(verdict("Resources/en.lproj/locversion.plist").1, 1100);
+        assert_eq!(verdict("Resources/Base.lproj/Main.nib").1, 1010);
+        assert_eq!(verdict("Resources/en.lproj/Localizable.strings").1, 1000);
+        assert_eq!(verdict("Resources/icon.icns"), (r"^Resources/", 20));
+        assert_eq!(verdict("Foo.dSYM/Contents/Info.plist").1, 11);
+        assert_eq!(verdict("Frameworks/Bar.framework").1, 10);
+        assert_eq!(verdict("Library/LoadableBundles/Baz.bundle").1, 10);
+        assert_eq!(verdict("Library/LoginItems/Qux.app"), (r"^.*", 1));
+        assert_eq!(verdict("SharedSupport/readme.txt"), (r"^.*", 1));
+
+        assert!(rules
+            .find_rule("Frameworks/Bar.framework")
+            .unwrap()
+            .is_nested());
+        assert!(rules
+            .find_rule("Resources/en.lproj/Localizable.strings")
+            .unwrap()
+            .is_optional());
+    }
+
+    #[test]
+    fn test_scan() {
+        let dir = TempDir::new().unwrap();
+        let contents = dir.path();
+        for dir in &[
+            "MacOS",
+            "Resources/en.lproj",
+            "Frameworks/Bar.framework/Resources",
+            "Library/LoadableBundles/Baz.bundle",
+            "_CodeSignature",
+        ] {
+            fs::create_dir_all(contents.join(dir)).unwrap();
+        }
+        fs::write(contents.join("Info.plist"), b"<plist/>").unwrap();
+        fs::write(contents.join("MacOS/Foo"), fake_macho(0x1000)).unwrap();
+        fs::write(contents.join("MacOS/helper"), fake_macho(0x1000)).unwrap();
+        fs::write(contents.join("MacOS/notes.txt"), b"notes").unwrap();
+        fs::write(contents.join("Resources/.DS_Store"), b"").unwrap();
+        fs::write(contents.join("Resources/a.txt"), b"a").unwrap();
+        fs::write(contents.join("Resources/en.lproj/b.strings"), b"b").unwrap();
+        symlink("a.txt", contents.join("Resources/link")).unwrap();
+        fs::write(contents.join("Frameworks/Bar.framework/Resources/c"), b"c").unwrap();
+        fs::write(contents.join("Library/LoadableBundles/Baz.bundle/d"), b"d").unwrap();
+        fs::write(contents.join("_CodeSignature/CodeResources"), b"").unwrap();
+
+        let mut rules = ResourceRules::default_rules2(false).unwrap();
+        rules.add_standard_exclusions(Some("MacOS/Foo")).unwrap();
+        let entry = |path: &str, kind, optional| ResourceEntry {
+            path: path.to_string(),
+            kind,
+            optional,
+        };
+        assert_eq!(
+            rules.scan(contents).unwrap(),
+            vec![
+                entry("Frameworks/Bar.framework", ResourceKind::Nested, false),
+                entry(
+                    "Library/LoadableBundles/Baz.bundle",
+                    ResourceKind::Nested,
+                    false
+                ),
+                entry("MacOS/helper", ResourceKind::Nested, false),
+                entry("MacOS/notes.txt", ResourceKind::File, false),
+                entry("Resources/a.txt", ResourceKind::File, false),
+                entry("Resources/en.lproj/b.strings", ResourceKind::File, true),
+                entry("Resources/link", ResourceKind::Symlink, false),
+            ]
+        );
+    }
+}
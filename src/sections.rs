@@ -0,0 +1,231 @@
+#![allow(dead_code)]
+
+use mach_object::{LoadCommand, MachCommand, OFile, Section};
+use plist::{Dictionary, Value};
+
+use std::fmt;
+use std::io::Cursor;
+use std::rc::Rc;
+
+use errors::{CDMachError::*, Result};
+
+use bundle::parse_dictionary;
+use codedir::{image_cpu_type, image_slices, CodeSignature};
+
+pub const SEG_TEXT: &str = "__TEXT";
+/// Entitlements of simulator binaries, which are only ad-hoc signed
+pub const SECT_ENTITLEMENTS: &str = "__entitlements";
+/// Info.plist of command-line tools, bound through special slot 1
+pub const SECT_INFO_PLIST: &str = "__info_plist";
+
+/// Where the entitlements of a piece of code were found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntitlementsSource {
+    /// the signature's entitlements blob
+    Signature,
+    /// the `__TEXT,__entitlements` section of a simulator binary
+    Simulator,
+}
+
+impl fmt::Display for EntitlementsSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EntitlementsSource::Signature => write!(f, "signature"),
+            EntitlementsSource::Simulator => write!(f, "simulator"),
+        }
+    }
+}
+
+/// The load commands of a thin image
+pub fn load_commands(slice: &[u8]) -> Result<Vec<MachCommand>> {
+    match OFile::parse(&mut Cursor::new(slice))? {
+        OFile::MachFile { commands, .. } => Ok(commands),
+        _ => Err(UnsupportedMachO("not a thin image".to_string()).into()),
+    }
+}
+
+/// Section `segname,sectname` among the segment commands of an image
+pub fn find_section(
+    commands: &[MachCommand],
+    segname: &str,
+    sectname: &str,
+) -> Option<Rc<Section>> {
+    commands
+        .iter()
+        .filter_map(|MachCommand(cmd, _)| match *cmd {
+            LoadCommand::Segment { ref sections, .. }
+            | LoadCommand::Segment64 { ref sections, .. } => Some(sections),
+            _ => None,
+        })
+        .flat_map(|sections| sections.iter())
+        .find(|section| section.segname == segname && section.sectname == sectname)
+        .cloned()
+}
+
+/// Contents of section `segname,sectname` of the thin image `slice`
+pub fn section_data<'a>(
+    slice: &'a [u8],
+    commands: &[MachCommand],
+    segname: &str,
+    sectname: &str,
+) -> Result<Option<&'a [u8]>> {
+    match find_section(commands, segname, sectname) {
+        Some(section) => {
+            let start = section.offset as usize;
+            match start.checked_add(section.size) {
+                Some(end) if end <= slice.len() => Ok(Some(&slice[start..end])),
+                _ => Err(UnsupportedMachO(format!(
+                    "{},{} past the end of the image",
+                    segname, sectname
+                ))
+                .into()),
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+/// The entitlements of an image: those of its `__TEXT,__entitlements`
+/// section when a slice has one, else those of its signatures
+pub fn entitlements(
+    data: &[u8],
+    signatures: &[CodeSignature],
+) -> Result<Option<(EntitlementsSource, Dictionary)>> {
+    for slice in image_slices(data)? {
+        if image_cpu_type(slice).is_none() {
+            continue;
+        }
+        let commands = load_commands(slice)?;
+        if let Some(section) = section_data(slice, &commands, SEG_TEXT, SECT_ENTITLEMENTS)? {
+            if section.iter().all(|&b| b == 0 || b.is_ascii_whitespace()) {
+                continue;
+            }
+            return Ok(Some((
+                EntitlementsSource::Simulator,
+                parse_dictionary(section)?,
+            )));
+        }
+    }
+    match signatures.iter().find_map(|cs| cs.entitlements()) {
+        Some(entitlements) => Ok(Some((
+            EntitlementsSource::Signature,
+            parse_dictionary(entitlements)?,
+        ))),
+        None => Ok(None),
+    }
+}
+
+/// An Info.plist embedded in `__TEXT,__info_plist`
+#[derive(Debug, Clone)]
+pub struct EmbeddedInfoPlist<'a> {
+    /// the thin image holding it, whose signature binds it
+    pub slice: &'a [u8],
+    pub data: &'a [u8],
+    pub info: Dictionary,
+}
+
+impl<'a> EmbeddedInfoPlist<'a> {
+    /// `CFBundleIdentifier`, if present
+    pub fn identifier(&self) -> Option<&str> {
+        self.info
+            .get("CFBundleIdentifier")
+            .and_then(Value::as_string)
+    }
+}
+
+/// The Info.plist embedded in each slice of an image; slices without one are
+/// left out
+pub fn embedded_info_plists<'a>(data: &'a [u8]) -> Result<Vec<EmbeddedInfoPlist<'a>>> {
+    let mut plists = vec![];
+    for slice in image_slices(data)? {
+        if image_cpu_type(slice).is_none() {
+            continue;
+        }
+        let commands = load_commands(slice)?;
+        if let Some(section) = section_data(slice, &commands, SEG_TEXT, SECT_INFO_PLIST)? {
+            plists.push(EmbeddedInfoPlist {
+                slice,
+                data: section,
+                info: parse_dictionary(section)?,
+            });
+        }
+    }
+    Ok(plists)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use byteorder::{ByteOrder, LittleEndian};
+    use consts::CSSLOT_INFOSLOT;
+    use mach_object::LC_SEGMENT_64;
+    use plist::Value;
+    use sections::{embedded_info_plists, entitlements, EntitlementsSource};
+    use signer::tests::fake_macho;
+    use signer::{sign_macho, SigningSettings};
+    use std::path::Path;
+    use verify::VerificationReport;
+
+    /// `fake_macho` with a `__TEXT` segment holding section `sectname` at
+    /// 0x800, filled with `contents`
+    pub fn with_text_section(sectname: &str, contents: &[u8]) -> Vec<u8> {
+        let mut macho = fake_macho(0x2000);
+        LittleEndian::write_u32(&mut macho[16..], 3);
+        LittleEndian::write_u32(&mut macho[20..], 88 + 152);
+        LittleEndian::write_u32(&mut macho[120..], LC_SEGMENT_64);
+        LittleEndian::write_u32(&mut macho[124..], 152);
+        macho[128..134].copy_from_slice(b"__TEXT");
+        LittleEndian::write_u64(&mut macho[168..], 0x1000);
+        LittleEndian::write_u32(&mut macho[184..], 1);
+        macho[192..192 + sectname.len()].copy_from_slice(sectname.as_bytes());
+        macho[208..214].copy_from_slice(b"__TEXT");
+        LittleEndian::write_u64(&mut macho[232..], contents.len() as u64);
+        LittleEndian::write_u32(&mut macho[240..], 0x800);
+        macho[0x800..0x800 + contents.len()].copy_from_slice(contents);
+        macho
+    }
+
+    #[test]
+    fn test_simulator_entitlements() {
+        let macho = with_text_section(
+            "__entitlements",
+            br#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0"><dict><key>application-identifier</key><string>ABCDE12345.com.example</string></dict></plist>"#,
+        );
+        let (source, dict) = entitlements(&macho, &[]).unwrap().unwrap();
+        assert_eq!(source, EntitlementsSource::Simulator);
+        assert_eq!(
+            dict.get("application-identifier"),
+            Some(&Value::String("ABCDE12345.com.example".to_string()))
+        );
+        assert!(entitlements(&fake_macho(0x2000), &[]).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_embedded_info_plist() {
+        let info_plist = br#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0"><dict><key>CFBundleIdentifier</key><string>com.example.tool</string></dict></plist>"#;
+        let mut macho = with_text_section("__info_plist", info_plist);
+        let mut settings = SigningSettings {
+            identifier: "tool".to_string(),
+            ..Default::default()
+        };
+        settings
+            .set_special_slot(CSSLOT_INFOSLOT, info_plist)
+            .unwrap();
+        sign_macho(&settings, &mut macho).unwrap();
+        assert_eq!(embedded_info_plists(&macho).unwrap().len(), 1);
+
+        let mut report = VerificationReport::new(Path::new("tool"));
+        report.check_embedded_info_plist(&macho).unwrap();
+        assert!(report.is_valid(), "{:?}", report);
+        assert_eq!(
+            report.bundle_identifier,
+            Some("com.example.tool".to_string())
+        );
+
+        macho[0x800 + info_plist.len() - 30] = b'X';
+        let mut report = VerificationReport::new(Path::new("tool"));
+        report.check_embedded_info_plist(&macho).unwrap();
+        assert!(!report.is_valid());
+    }
+}
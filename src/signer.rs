@@ -0,0 +1,916 @@
+#![allow(dead_code)]
+#![allow(non_snake_case)]
+
+use byteorder::{ByteOrder, LittleEndian, NetworkEndian, WriteBytesExt};
+use hex;
+use plist::Value;
+use ring::digest::{digest, SHA1};
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+use std::sync::Arc;
+
+use errors::{CDMachError::*, Result};
+
+use bundle::Bundle;
+use cms::SigningIdentity;
+use codedir::{
+    find_link_edit, hash_digest, hash_size, supportsExecSegment, Blob, CDVersion, CodeDirectory,
+    CodeSignature, LinkEditLayout,
+};
+use consts::{
+    CS_ADHOC, CS_EXECSEG_MAIN_BINARY, CS_HASHTYPE_SHA256, CS_LINKER_SIGNED, CS_PAGE_SIZE,
+    CS_PAGE_SIZE_16K, CSMAGIC_BLOBWRAPPER, CSMAGIC_CODEDIRECTORY,
+    CSMAGIC_EMBEDDED_DER_ENTITLEMENTS, CSMAGIC_EMBEDDED_ENTITLEMENTS, CSMAGIC_EMBEDDED_SIGNATURE,
+    CSSLOT_ALTERNATE_CODEDIRECTORIES, CSSLOT_ALTERNATE_CODEDIRECTORY_LIMIT,
+    CSSLOT_ALTERNATE_CODEDIRECTORY_MAX, CSSLOT_CMS_SIGNATURE, CSSLOT_CODEDIRECTORY,
+    CSSLOT_DER_ENTITLEMENTS, CSSLOT_ENTITLEMENTS, CSSLOT_INFOSLOT, CSSLOT_REQUIREMENTS,
+    CSSLOT_RESOURCEDIR, kSecDesignatedRequirementType,
+};
+use der::{
+    encode, encode_constructed, encode_set, OID_APPLE_CDHASHES, TAG_OCTET_STRING, TAG_OID,
+    TAG_SEQUENCE,
+};
+use requirements::{designated_requirement, encode_requirements, Expr, Match, LEAF_CERT};
+use resources::CodeResources;
+use mach_object::MH_EXECUTE;
+
+/// Highest special slot we know how to fill
+const MAX_SPECIAL_SLOT: u32 = CSSLOT_DER_ENTITLEMENTS;
+
+/// Alignment of the signature inside `__LINKEDIT`
+pub const SIGNATURE_ALIGNMENT: usize = 16;
+
+/// VM granularity used when growing `__LINKEDIT`
+const SEGMENT_ALIGNMENT: u64 = 0x4000;
+
+/// Everything needed to produce a CodeDirectory (and the blobs it seals)
+/// besides the code itself.
+#[derive(Debug, Clone)]
+pub struct SigningSettings {
+    /// Identifier string
+    pub identifier: String,
+    /// Team Identifier
+    pub team_id: Option<String>,
+    /// CodeDirectory flags; `CS_ADHOC` is added when signing without an identity
+    pub flags: u32,
+    /// CodeDirectory version
+    pub version: u32,
+    /// platform identifier; zero if not platform binary
+    pub platform: u8,
+    /// Runtime version (hardened runtime), only written for version >= 0x20500
+    pub runtime: u32,
+    /// offset of executable segment
+    pub exec_seg_base: u64,
+    /// limit of executable segment
+    pub exec_seg_limit: u64,
+    /// exec segment flags
+    pub exec_seg_flags: u64,
+    /// type of hash (`CS_HASHTYPE_*`) of the primary CodeDirectory
+    pub hash_type: u8,
+    /// hash types of the CodeDirectories emitted in the
+    /// `CSSLOT_ALTERNATE_CODEDIRECTORIES` slots, e.g. SHA-256 next to a SHA-1
+    /// primary for binaries that must load on old releases
+    pub alternate_hash_types: Vec<u8>,
+    /// log2(page size in bytes), 0 => infinite
+    pub page_size: u8,
+    /// Raw requirements blob, including its header. When `None`, ad-hoc
+    /// signatures get an empty set and signatures with an identity a
+    /// designated requirement naming the identifier and the signer.
+    pub requirements: Option<Vec<u8>>,
+    /// XML entitlements plist
+    pub entitlements: Option<Vec<u8>>,
+    /// DER encoded entitlements
+    pub der_entitlements: Option<Vec<u8>>,
+    /// Digests of special slots whose content lives outside of the binary
+    /// (Info.plist, CodeResources, ...), keyed by hash type and slot number
+    pub special_slot_digests: BTreeMap<(u8, u32), Vec<u8>>,
+    /// Certificate and key for a CMS signature; ad-hoc when `None`
+    pub identity: Option<Arc<SigningIdentity>>,
+}
+
+impl Default for SigningSettings {
+    fn default() -> Self {
+        SigningSettings {
+            identifier: String::new(),
+            team_id: None,
+            flags: 0,
+            version: CodeDirectory::currentVersion,
+            platform: 0,
+            runtime: 0,
+            exec_seg_base: 0,
+            exec_seg_limit: 0,
+            exec_seg_flags: 0,
+            hash_type: CS_HASHTYPE_SHA256 as u8,
+            alternate_hash_types: vec![],
+            page_size: 12,
+            requirements: None,
+            entitlements: None,
+            der_entitlements: None,
+            special_slot_digests: BTreeMap::new(),
+            identity: None,
+        }
+    }
+}
+
+impl SigningSettings {
+    /// Take over the metadata of an existing signature, the way
+    /// `codesign --preserve-metadata` does: only the page hashes and the
+    /// special slots sealing blobs we emit ourselves get recomputed.
+    pub fn from_code_signature(cs: &CodeSignature) -> Result<SigningSettings> {
+        let blobs = match cs.blobs {
+            Some(ref blobs) => blobs,
+            None => return Err(NoCodeDirectory.into()),
+        };
+
+        let mut settings = None;
+        let mut alternate_hash_types = vec![];
+        let mut special_slot_digests = BTreeMap::new();
+        for blob in blobs {
+            if let Blob::CodeDirectory {
+                ref index,
+                ref code_directory,
+                ref identifier,
+                ref team_id,
+                ref cd_hashes,
+                ..
+            } = blob
+            {
+                let cd = code_directory;
+                if let Ok(hashes) = cd_hashes {
+                    for &(slot, ref hash) in hashes.iter().filter(|&&(slot, _)| slot < 0) {
+                        let slot = -slot as u32;
+                        if is_sealed_blob_slot(slot) {
+                            continue;
+                        }
+                        let digest = hex::decode(hash)?;
+                        if digest.iter().any(|&b| b != 0) {
+                            special_slot_digests.insert((cd.hashType, slot), digest);
+                        }
+                    }
+                }
+                if index.typ >= CSSLOT_ALTERNATE_CODEDIRECTORIES
+                    && index.typ < CSSLOT_ALTERNATE_CODEDIRECTORY_LIMIT
+                {
+                    alternate_hash_types.push(cd.hashType);
+                    continue;
+                }
+                if index.typ != CSSLOT_CODEDIRECTORY {
+                    continue;
+                }
+                let identifier = match identifier {
+                    Ok(identifier) => identifier.clone(),
+                    Err(_) => return Err(NoIdentifier.into()),
+                };
+                settings = Some(SigningSettings {
+                    identifier,
+                    team_id: match cd.teamIDOffset {
+                        0 => None,
+                        _ => team_id.as_ref().ok().cloned(),
+                    },
+                    flags: cd.flags,
+                    version: cd.version,
+                    platform: cd.platform,
+                    runtime: cd.runtime,
+                    exec_seg_base: cd.execSegBase,
+                    exec_seg_limit: cd.execSegLimit,
+                    exec_seg_flags: cd.execSegFlags,
+                    hash_type: cd.hashType,
+                    page_size: cd.pageSize,
+                    ..Default::default()
+                });
+            }
+        }
+        let mut settings = match settings {
+            Some(settings) => settings,
+            None => return Err(NoCodeDirectory.into()),
+        };
+        settings.alternate_hash_types = alternate_hash_types;
+        settings.special_slot_digests = special_slot_digests;
+
+        for blob in blobs {
+            match blob {
+                Blob::Requirements { ref data, .. } => settings.requirements = Some(data.clone()),
+                Blob::Entitlements {
+                    ref entitlements, ..
+                } => settings.entitlements = Some(entitlements.clone()),
+                Blob::DerEntitlements {
+                    ref der_entitlements,
+                    ..
+                } => settings.der_entitlements = Some(der_entitlements.clone()),
+                _ => {}
+            }
+        }
+        Ok(settings)
+    }
+
+    /// Settings for the signature ld64 emits for `macho`: a lone SHA-256
+    /// CodeDirectory flagged `adhoc,linker-signed`, with no requirements,
+    /// entitlements or CMS blob. `sign_macho` then produces the same bytes
+    /// as `ld -adhoc_codesign`.
+    pub fn linker_signed(identifier: &str, macho: &[u8]) -> Result<SigningSettings> {
+        let layout = find_link_edit(macho)?;
+        let (exec_seg_base, exec_seg_limit) = layout.text_segment.unwrap_or((0, 0));
+        Ok(SigningSettings {
+            identifier: identifier.to_string(),
+            flags: CS_ADHOC | CS_LINKER_SIGNED,
+            version: supportsExecSegment,
+            exec_seg_base,
+            exec_seg_limit,
+            exec_seg_flags: if layout.filetype == MH_EXECUTE {
+                CS_EXECSEG_MAIN_BINARY
+            } else {
+                0
+            },
+            ..Default::default()
+        })
+    }
+
+    pub fn is_linker_signed(&self) -> bool {
+        self.flags & CS_LINKER_SIGNED != 0
+    }
+
+    /// Size in bytes of the signature produced for `code_limit` bytes of code
+    pub fn signature_size(&self, code_limit: usize) -> Result<usize> {
+        let blobs = self.sealed_blobs();
+        let mut cds_len = 0;
+        for &hash_type in self.hash_types()?.iter() {
+            cds_len += self.layout(hash_type, code_limit, &blobs)?.length as usize;
+        }
+        let blobs_len: usize = blobs.iter().map(|&(_, ref blob)| blob.len()).sum();
+        // a CMS wrapper, except for linker signatures
+        let cms = if self.is_linker_signed() { 0 } else { 1 };
+        let cms_len = match self.identity {
+            // the CMS size does not depend on the CodeDirectories' contents
+            Some(ref identity) if cms == 1 => {
+                let cds: Vec<(u8, Vec<u8>)> = self
+                    .hash_types()?
+                    .into_iter()
+                    .map(|hash_type| (hash_type, vec![]))
+                    .collect();
+                sign_code_directories(identity, &cds)?.len()
+            }
+            _ => 0,
+        };
+        let count = 1 + self.alternate_hash_types.len() + blobs.len() + cms;
+        Ok(12 + 8 * count + cds_len + blobs_len + 8 * cms + cms_len)
+    }
+
+    /// Primary hash type followed by the alternate ones
+    pub fn hash_types(&self) -> Result<Vec<u8>> {
+        if self.alternate_hash_types.len() > CSSLOT_ALTERNATE_CODEDIRECTORY_MAX as usize
+            || (self.is_linker_signed() && !self.alternate_hash_types.is_empty())
+        {
+            return Err(TooManyCodeDirectories(self.alternate_hash_types.len() + 1).into());
+        }
+        let mut hash_types = vec![self.hash_type];
+        hash_types.extend_from_slice(&self.alternate_hash_types);
+        Ok(hash_types)
+    }
+
+    /// Seal `data` (Info.plist, CodeResources...) in special slot `slot` of
+    /// every CodeDirectory
+    pub fn set_special_slot(&mut self, slot: u32, data: &[u8]) -> Result<()> {
+        for hash_type in self.hash_types()? {
+            let digest = hash_digest(hash_type as u32, data)?;
+            self.special_slot_digests.insert((hash_type, slot), digest);
+        }
+        Ok(())
+    }
+
+    /// The requirement set sealed when none is given: empty for ad-hoc
+    /// signatures, else a designated requirement naming the identifier and
+    /// the signer, by team when it has one and by leaf certificate otherwise
+    fn default_requirements(&self) -> Vec<u8> {
+        let identity = match self.identity {
+            Some(ref identity) => identity,
+            None => return encode_requirements(&[]),
+        };
+        let identifier = Expr::Identifier(self.identifier.clone());
+        let designated = match self.team_id {
+            Some(ref team_id) => identifier
+                .and(Expr::AppleGenericAnchor)
+                .and(Expr::CertField(
+                    LEAF_CERT,
+                    "subject.OU".to_string(),
+                    Match::Equal(team_id.as_bytes().to_vec()),
+                )),
+            None => identifier.and(Expr::AnchorHash(
+                LEAF_CERT,
+                digest(&SHA1, &identity.certificate.der).as_ref().to_vec(),
+            )),
+        };
+        encode_requirements(&[(kSecDesignatedRequirementType, designated)])
+    }
+
+    /// Blobs sealed by special slots, in slot order
+    fn sealed_blobs(&self) -> Vec<(u32, Vec<u8>)> {
+        let mut blobs = vec![];
+        if self.is_linker_signed() {
+            return blobs;
+        }
+        blobs.push((
+            CSSLOT_REQUIREMENTS,
+            match self.requirements {
+                Some(ref data) => data.clone(),
+                None => self.default_requirements(),
+            },
+        ));
+        if let Some(ref entitlements) = self.entitlements {
+            blobs.push((
+                CSSLOT_ENTITLEMENTS,
+                wrap_blob(CSMAGIC_EMBEDDED_ENTITLEMENTS, entitlements),
+            ));
+        }
+        if let Some(ref der) = self.der_entitlements {
+            blobs.push((
+                CSSLOT_DER_ENTITLEMENTS,
+                wrap_blob(CSMAGIC_EMBEDDED_DER_ENTITLEMENTS, der),
+            ));
+        }
+        blobs
+    }
+
+    /// Work out the header of the `hash_type` CodeDirectory for `code_limit`
+    /// bytes of code
+    fn layout(
+        &self,
+        hash_type: u8,
+        code_limit: usize,
+        sealed: &[(u32, Vec<u8>)],
+    ) -> Result<CodeDirectory> {
+        let hashSize = hash_size(hash_type as u32)?;
+        let nSpecialSlots = (1..=MAX_SPECIAL_SLOT)
+            .filter(|&slot| {
+                sealed.iter().any(|&(s, _)| s == slot)
+                    || self.special_slot_digests.contains_key(&(hash_type, slot))
+            })
+            .max()
+            .unwrap_or(0);
+        let nCodeSlots = match self.page_size {
+            0 if code_limit == 0 => 0,
+            0 => 1,
+            n => (code_limit + (1 << n) - 1) >> n,
+        };
+
+        let identOffset = CodeDirectory::header_size(self.version);
+        let mut end = identOffset + self.identifier.len() as u32 + 1;
+        let teamIDOffset = match self.team_id {
+            Some(ref team_id) if self.version >= ::codedir::supportsTeamID => {
+                let offset = end;
+                end += team_id.len() as u32 + 1;
+                offset
+            }
+            _ => 0,
+        };
+        let hashOffset = end + nSpecialSlots * hashSize as u32;
+
+        Ok(CodeDirectory {
+            magic: CSMAGIC_CODEDIRECTORY,
+            length: hashOffset + nCodeSlots as u32 * hashSize as u32,
+            version: self.version,
+            flags: self.flags,
+            hashOffset,
+            identOffset,
+            nSpecialSlots,
+            nCodeSlots: nCodeSlots as u32,
+            codeLimit: code_limit as u32,
+            hashSize,
+            hashType: hash_type,
+            platform: self.platform,
+            pageSize: self.page_size,
+            teamIDOffset,
+            execSegBase: self.exec_seg_base,
+            execSegLimit: self.exec_seg_limit,
+            execSegFlags: self.exec_seg_flags,
+            runtime: self.runtime,
+            ..Default::default()
+        })
+    }
+
+    /// Build the `hash_type` CodeDirectory blob for `code`, given the blobs it seals
+    fn build_code_directory(
+        &self,
+        hash_type: u8,
+        code_limit: usize,
+        page_hashes: &[Vec<u8>],
+        sealed: &[(u32, Vec<u8>)],
+    ) -> Result<Vec<u8>> {
+        let mut cd = self.layout(hash_type, code_limit, sealed)?;
+        if page_hashes.len() != cd.nCodeSlots as usize {
+            return Err(PageHashCountMismatch(page_hashes.len(), cd.nCodeSlots).into());
+        }
+        match self.identity {
+            Some(_) => cd.flags &= !CS_ADHOC,
+            None => cd.flags |= CS_ADHOC,
+        }
+
+        let mut buf = Vec::with_capacity(cd.length as usize);
+        cd.write_header::<NetworkEndian, _>(&mut buf)?;
+        buf.extend_from_slice(self.identifier.as_bytes());
+        buf.push(0);
+        if cd.teamIDOffset != 0 {
+            if let Some(ref team_id) = self.team_id {
+                buf.extend_from_slice(team_id.as_bytes());
+                buf.push(0);
+            }
+        }
+        buf.resize(
+            (cd.hashOffset - cd.nSpecialSlots * cd.hashSize as u32) as usize,
+            0,
+        );
+
+        for slot in (1..=cd.nSpecialSlots).rev() {
+            let digest = match sealed.iter().find(|&&(s, _)| s == slot) {
+                Some(&(_, ref blob)) => hash_digest(cd.hashType as u32, blob)?,
+                None => match self.special_slot_digests.get(&(cd.hashType, slot)) {
+                    Some(digest) => digest.clone(),
+                    None => vec![0u8; cd.hashSize as usize],
+                },
+            };
+            buf.extend_from_slice(&digest);
+        }
+
+        for hash in page_hashes {
+            buf.extend_from_slice(hash);
+        }
+
+        if buf.len() != cd.length as usize {
+            return Err(BadBlobLength(cd.length).into());
+        }
+        Ok(buf)
+    }
+
+    /// Produce an embedded signature over `code`
+    pub fn build_signature(&self, code: &[u8]) -> Result<Vec<u8>> {
+        let page_size = match self.page_size {
+            0 => code.len().max(1),
+            n => 1 << n,
+        };
+        let mut page_hashes = vec![];
+        for hash_type in self.hash_types()? {
+            let hashes: Result<Vec<Vec<u8>>> = code
+                .chunks(page_size)
+                .map(|page| hash_digest(hash_type as u32, page))
+                .collect();
+            page_hashes.push(hashes?);
+        }
+        self.build_signature_from_page_hashes(code.len(), &page_hashes)
+    }
+
+    /// Produce an embedded signature from page hashes computed
+    /// elsewhere: one vector per hash type, primary first, then alternates.
+    pub fn build_signature_from_page_hashes(
+        &self,
+        code_limit: usize,
+        page_hashes: &[Vec<Vec<u8>>],
+    ) -> Result<Vec<u8>> {
+        let hash_types = self.hash_types()?;
+        if page_hashes.len() != hash_types.len() {
+            return Err(HashTypeCountMismatch(page_hashes.len(), hash_types.len()).into());
+        }
+        let sealed = self.sealed_blobs();
+        let mut blobs = vec![(
+            CSSLOT_CODEDIRECTORY,
+            self.build_code_directory(hash_types[0], code_limit, &page_hashes[0], &sealed)?,
+        )];
+        let mut alternates = vec![];
+        for (i, &hash_type) in hash_types[1..].iter().enumerate() {
+            alternates.push((
+                CSSLOT_ALTERNATE_CODEDIRECTORIES + i as u32,
+                self.build_code_directory(hash_type, code_limit, &page_hashes[i + 1], &sealed)?,
+            ));
+        }
+        let cms = match self.identity {
+            Some(ref identity) if !self.is_linker_signed() => {
+                let mut cds = vec![(hash_types[0], blobs[0].1.clone())];
+                cds.extend(
+                    hash_types[1..]
+                        .iter()
+                        .cloned()
+                        .zip(alternates.iter().map(|&(_, ref cd)| cd.clone())),
+                );
+                sign_code_directories(identity, &cds)?
+            }
+            _ => vec![],
+        };
+        blobs.extend(sealed);
+        blobs.extend(alternates);
+        if !self.is_linker_signed() {
+            blobs.push((CSSLOT_CMS_SIGNATURE, wrap_blob(CSMAGIC_BLOBWRAPPER, &cms)));
+        }
+        build_super_blob(&blobs)
+    }
+}
+
+/// CMS signature over the primary CodeDirectory, listing the (truncated)
+/// cdhash of every `(hash type, CodeDirectory)` in a signed cdhashes plist
+fn sign_code_directories(identity: &SigningIdentity, cds: &[(u8, Vec<u8>)]) -> Result<Vec<u8>> {
+    let mut cdhashes = vec![];
+    for &(hash_type, ref cd) in cds {
+        let mut cdhash = hash_digest(hash_type as u32, cd)?;
+        cdhash.truncate(20);
+        cdhashes.push(Value::Data(cdhash));
+    }
+    let mut plist = ::plist::Dictionary::new();
+    plist.insert("cdhashes".to_string(), Value::Array(cdhashes));
+    let mut xml = vec![];
+    Value::Dictionary(plist).to_writer_xml(&mut xml)?;
+
+    let attribute = encode_constructed(
+        TAG_SEQUENCE,
+        &[
+            encode(TAG_OID, OID_APPLE_CDHASHES),
+            encode_set(vec![encode(TAG_OCTET_STRING, &xml)]),
+        ],
+    );
+    identity.sign_detached(&cds[0].1, &[attribute])
+}
+
+/// log2 of a supported page size, for `SigningSettings::page_size`
+pub fn page_shift(page_size: u32) -> Result<u8> {
+    match page_size {
+        CS_PAGE_SIZE => Ok(12),
+        CS_PAGE_SIZE_16K => Ok(14),
+        _ => Err(UnsupportedPageSize(page_size).into()),
+    }
+}
+
+/// Slots sealing blobs that are part of the signature itself
+fn is_sealed_blob_slot(slot: u32) -> bool {
+    match slot {
+        CSSLOT_REQUIREMENTS | CSSLOT_ENTITLEMENTS | CSSLOT_DER_ENTITLEMENTS => true,
+        _ => false,
+    }
+}
+
+/// Prefix `payload` with a blob header
+pub fn wrap_blob(magic: u32, payload: &[u8]) -> Vec<u8> {
+    let mut blob = Vec::with_capacity(payload.len() + 8);
+    blob.write_u32::<NetworkEndian>(magic).unwrap();
+    blob.write_u32::<NetworkEndian>(payload.len() as u32 + 8)
+        .unwrap();
+    blob.extend_from_slice(payload);
+    blob
+}
+
+/// Assemble an embedded signature SuperBlob from `(slot, blob)` pairs
+fn build_super_blob(blobs: &[(u32, Vec<u8>)]) -> Result<Vec<u8>> {
+    let header_len = 12 + 8 * blobs.len();
+    let length = header_len + blobs.iter().map(|&(_, ref blob)| blob.len()).sum::<usize>();
+
+    let mut buf = Vec::with_capacity(length);
+    buf.write_u32::<NetworkEndian>(CSMAGIC_EMBEDDED_SIGNATURE)?;
+    buf.write_u32::<NetworkEndian>(length as u32)?;
+    buf.write_u32::<NetworkEndian>(blobs.len() as u32)?;
+    let mut offset = header_len;
+    for &(slot, ref blob) in blobs {
+        buf.write_u32::<NetworkEndian>(slot)?;
+        buf.write_u32::<NetworkEndian>(offset as u32)?;
+        offset += blob.len();
+    }
+    for &(_, ref blob) in blobs {
+        buf.extend_from_slice(blob);
+    }
+    Ok(buf)
+}
+
+/// Point `LC_CODE_SIGNATURE` and `__LINKEDIT` at a signature of `datasize` bytes
+fn resize_signature(macho: &mut [u8], layout: &LinkEditLayout, datasize: u32) -> Result<()> {
+    let end = layout.dataoff as u64 + datasize as u64;
+    let bad_linkedit =
+        || UnsupportedMachO("__LINKEDIT does not end with the signature".to_string());
+    match layout.linkedit_cmd {
+        Some((cmd, true)) => {
+            let fileoff = LittleEndian::read_u64(&macho[cmd + 40..]);
+            let vmsize = LittleEndian::read_u64(&macho[cmd + 32..]);
+            let filesize = match end.checked_sub(fileoff) {
+                Some(filesize) => filesize,
+                None => return Err(bad_linkedit().into()),
+            };
+            LittleEndian::write_u64(&mut macho[cmd + 48..], filesize);
+            if filesize > vmsize {
+                let vmsize = (filesize + SEGMENT_ALIGNMENT - 1) & !(SEGMENT_ALIGNMENT - 1);
+                LittleEndian::write_u64(&mut macho[cmd + 32..], vmsize);
+            }
+        }
+        Some((cmd, false)) => {
+            let fileoff = LittleEndian::read_u32(&macho[cmd + 32..]) as u64;
+            let vmsize = LittleEndian::read_u32(&macho[cmd + 28..]) as u64;
+            let filesize = match end.checked_sub(fileoff) {
+                Some(filesize) if filesize <= u64::from(u32::MAX) - SEGMENT_ALIGNMENT => filesize,
+                _ => return Err(bad_linkedit().into()),
+            };
+            LittleEndian::write_u32(&mut macho[cmd + 36..], filesize as u32);
+            if filesize > vmsize {
+                let vmsize = (filesize + SEGMENT_ALIGNMENT - 1) & !(SEGMENT_ALIGNMENT - 1);
+                LittleEndian::write_u32(&mut macho[cmd + 28..], vmsize as u32);
+            }
+        }
+        None => {}
+    }
+    LittleEndian::write_u32(&mut macho[layout.code_signature_cmd + 12..], datasize);
+    Ok(())
+}
+
+/// Replace the signature of a thin Mach-O image in place with the signature
+/// described by `settings` (ad-hoc unless it carries an identity).
+///
+/// The image must already carry an `LC_CODE_SIGNATURE` whose data ends the
+/// file; the load commands are updated when the new signature's size differs.
+pub fn sign_macho(settings: &SigningSettings, macho: &mut Vec<u8>) -> Result<()> {
+    let layout = find_link_edit(macho)?;
+    if layout.dataoff as usize + layout.datasize as usize != macho.len() {
+        return Err(UnsupportedMachO("code signature does not end the file".to_string()).into());
+    }
+
+    let size = settings.signature_size(layout.dataoff as usize)?;
+    // ld64 ends the file with the signature, unpadded
+    let datasize = if settings.is_linker_signed() {
+        size
+    } else {
+        (size + SIGNATURE_ALIGNMENT - 1) & !(SIGNATURE_ALIGNMENT - 1)
+    };
+    resize_signature(macho, &layout, datasize as u32)?;
+
+    macho.truncate(layout.dataoff as usize);
+    let signature = settings.build_signature(macho)?;
+    if signature.len() != size {
+        return Err(BadBlobLength(signature.len() as u32).into());
+    }
+    macho.extend_from_slice(&signature);
+    macho.resize(layout.dataoff as usize + datasize, 0);
+    Ok(())
+}
+
+/// Re-sign a patched thin Mach-O image, keeping the identifier, team ID,
+/// flags, runtime version, entitlements and requirements of `cs`.
+///
+/// `cs` must have been parsed from `macho` itself (offsets relative to the
+/// start of the image). The result is signed by `identity`, or ad hoc without
+/// one; code signed with a certificate cannot be re-signed ad hoc. Ad-hoc code
+/// signed with an identity gets the default designated requirement in place
+/// of a requirement set without one.
+pub fn resign_preserving_metadata(
+    cs: &CodeSignature,
+    identity: Option<Arc<SigningIdentity>>,
+    macho: &mut Vec<u8>,
+) -> Result<()> {
+    if cs.cms().is_some() && identity.is_none() {
+        return Err(NotAdHoc.into());
+    }
+    let mut settings = SigningSettings {
+        identity,
+        ..SigningSettings::from_code_signature(cs)?
+    };
+    if settings.identity.is_some() {
+        if let Some(requirements) = settings.requirements.take() {
+            if designated_requirement(&requirements)?.is_some() {
+                settings.requirements = Some(requirements);
+            }
+        }
+    }
+    sign_macho(&settings, macho)
+}
+
+/// Sign the main executable of `bundle`: generate and write its
+/// CodeResources, then seal it and Info.plist in their special slots.
+///
+/// Nested code must be signed beforehand and the executable must be a thin
+/// image (see `sign_macho`). An empty identifier defaults to
+/// `CFBundleIdentifier`.
+pub fn sign_bundle(bundle: &Bundle, settings: &SigningSettings) -> Result<CodeResources> {
+    let resources = CodeResources::generate(bundle)?;
+    resources.write(bundle)?;
+
+    let mut settings = settings.clone();
+    if settings.identifier.is_empty() {
+        settings.identifier = match bundle.identifier() {
+            Some(identifier) => identifier.to_string(),
+            None => return Err(MissingInfoKey("CFBundleIdentifier".to_string()).into()),
+        };
+    }
+    settings.set_special_slot(CSSLOT_INFOSLOT, &bundle.info_plist)?;
+    settings.set_special_slot(CSSLOT_RESOURCEDIR, &resources.raw)?;
+
+    let mut macho = bundle.read_executable()?;
+    sign_macho(&settings, &mut macho)?;
+    File::create(&bundle.executable)?.write_all(&macho)?;
+    Ok(resources)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use byteorder::{ByteOrder, LittleEndian};
+    use cms::tests::identity;
+    use cms::SignedData;
+    use codedir::{find_link_edit, Blob, CodeSignature};
+    use consts::{
+        CS_HASHTYPE_SHA1, CS_HASHTYPE_SHA256, CS_HASHTYPE_SHA384, CS_PAGE_SIZE_16K,
+        CSSLOT_ALTERNATE_CODEDIRECTORIES, CSSLOT_CODEDIRECTORY,
+    };
+    use mach_object::{LC_CODE_SIGNATURE, LC_SEGMENT_64, MH_MAGIC_64};
+    use requirements::{designated_requirement, encode_requirements};
+    use signer::{page_shift, resign_preserving_metadata, sign_macho, SigningSettings};
+    use std::sync::Arc;
+    use std::io::Cursor;
+
+    /// A minimal 64 bit image: header, `__LINKEDIT` and `LC_CODE_SIGNATURE`
+    pub fn fake_macho(code_len: usize) -> Vec<u8> {
+        let mut macho = vec![0u8; code_len + 16];
+        LittleEndian::write_u32(&mut macho[0..], MH_MAGIC_64);
+        LittleEndian::write_u32(&mut macho[16..], 2);
+        LittleEndian::write_u32(&mut macho[20..], 72 + 16);
+
+        LittleEndian::write_u32(&mut macho[32..], LC_SEGMENT_64);
+        LittleEndian::write_u32(&mut macho[36..], 72);
+        macho[40..50].copy_from_slice(b"__LINKEDIT");
+        LittleEndian::write_u64(&mut macho[64..], 0x4000);
+        LittleEndian::write_u64(&mut macho[72..], 0x1000);
+        LittleEndian::write_u64(&mut macho[80..], code_len as u64 + 16 - 0x1000);
+
+        LittleEndian::write_u32(&mut macho[104..], LC_CODE_SIGNATURE);
+        LittleEndian::write_u32(&mut macho[108..], 16);
+        LittleEndian::write_u32(&mut macho[112..], code_len as u32);
+        LittleEndian::write_u32(&mut macho[116..], 16);
+        macho
+    }
+
+    pub fn parse(macho: &[u8]) -> CodeSignature {
+        let off = LittleEndian::read_u32(&macho[112..]);
+        let size = LittleEndian::read_u32(&macho[116..]);
+        let mut cur = Cursor::new(macho);
+        cur.set_position(off as u64);
+        CodeSignature::parse(None, off, size, &mut cur)
+            .unwrap()
+            .unwrap()
+    }
+
+    pub fn assert_hashes_match(cs: &CodeSignature) {
+        for blob in cs.blobs.as_ref().unwrap() {
+            if let Blob::CodeDirectory {
+                ref cd_hashes,
+                ref computed_cd_hashes,
+                ..
+            } = blob
+            {
+                let code: Vec<_> = cd_hashes
+                    .as_ref()
+                    .unwrap()
+                    .iter()
+                    .filter(|&&(i, _)| i >= 0)
+                    .cloned()
+                    .collect();
+                assert_eq!(&code, computed_cd_hashes.as_ref().unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_resign_preserving_metadata() {
+        let mut macho = fake_macho(0x2345);
+        let settings = SigningSettings {
+            identifier: "com.example.tool".to_string(),
+            team_id: Some("ABCDE12345".to_string()),
+            entitlements: Some(b"<plist/>".to_vec()),
+            ..Default::default()
+        };
+        sign_macho(&settings, &mut macho).unwrap();
+        let cs = parse(&macho);
+        assert_hashes_match(&cs);
+
+        macho[0x1234] = 0xff;
+        resign_preserving_metadata(&cs, None, &mut macho).unwrap();
+        let resigned = parse(&macho);
+        assert_hashes_match(&resigned);
+
+        let preserved = SigningSettings::from_code_signature(&resigned).unwrap();
+        assert_eq!(preserved.identifier, "com.example.tool");
+        assert_eq!(preserved.team_id, Some("ABCDE12345".to_string()));
+        assert_eq!(preserved.entitlements, Some(b"<plist/>".to_vec()));
+        // ad-hoc signatures default to an empty requirement set
+        assert_eq!(preserved.requirements, Some(encode_requirements(&[])));
+
+        // signing ad-hoc code with an identity adds a designated requirement
+        let identity = Arc::new(identity());
+        let mut signed = macho.clone();
+        resign_preserving_metadata(&resigned, Some(identity.clone()), &mut signed).unwrap();
+        let requirements = parse(&signed).requirements().unwrap().to_vec();
+        assert_eq!(
+            designated_requirement(&requirements)
+                .unwrap()
+                .unwrap()
+                .to_string(),
+            "identifier \"com.example.tool\" and anchor apple generic and \
+             certificate leaf[subject.OU] = ABCDE12345"
+        );
+
+        // the signer is kept, never downgraded to ad hoc
+        let mut macho = fake_macho(0x2345);
+        let settings = SigningSettings {
+            identifier: "com.example.tool".to_string(),
+            identity: Some(identity.clone()),
+            ..Default::default()
+        };
+        sign_macho(&settings, &mut macho).unwrap();
+        let cs = parse(&macho);
+        macho[0x1234] = 0xff;
+        assert!(resign_preserving_metadata(&cs, None, &mut macho.clone()).is_err());
+        resign_preserving_metadata(&cs, Some(identity.clone()), &mut macho).unwrap();
+        let resigned = parse(&macho);
+        assert_hashes_match(&resigned);
+        let cms = SignedData::parse(resigned.cms().unwrap()).unwrap();
+        assert_eq!(cms.leaf_certificate(), Some(&identity.certificate));
+        assert_eq!(resigned.requirements(), cs.requirements());
+    }
+
+    #[test]
+    fn test_linkedit_after_signature() {
+        let mut macho = fake_macho(0x2000);
+        LittleEndian::write_u64(&mut macho[72..], 0x3000);
+        let original = macho.clone();
+        assert!(sign_macho(&SigningSettings::default(), &mut macho).is_err());
+        assert_eq!(macho, original);
+    }
+
+    #[test]
+    fn test_linker_signed() {
+        // appkit_launcher_arm64 of menuinst 2.4.2 (BSD-3-Clause), an arm64
+        // executable signed by ld64-857.1
+        let linked = include_bytes!("../testdata/ld64_linker_signed").to_vec();
+        let mut macho = linked.clone();
+        let dataoff = find_link_edit(&macho).unwrap().dataoff as usize;
+        for byte in &mut macho[dataoff..] {
+            *byte = 0;
+        }
+        let settings = SigningSettings::linker_signed("appkit-launcher", &macho).unwrap();
+        sign_macho(&settings, &mut macho).unwrap();
+        assert_eq!(macho.len(), linked.len());
+        assert!(macho == linked, "signature differs from ld64's");
+
+        // regardless of the signature it replaces
+        let mut macho = fake_macho(0x3000);
+        let settings = SigningSettings::linker_signed("a.out", &macho).unwrap();
+        sign_macho(&settings, &mut macho).unwrap();
+        let cs = parse(&macho);
+        assert_hashes_match(&cs);
+        assert_eq!(cs.super_blob.as_ref().unwrap().count, 1);
+        match cs.blobs.as_ref().unwrap()[0] {
+            Blob::CodeDirectory {
+                ref code_directory,
+                ref identifier,
+                ..
+            } => {
+                assert!(code_directory.is_linker_signed());
+                assert_eq!(code_directory.flags_str(), "adhoc,linker-signed");
+                assert_eq!(code_directory.nSpecialSlots, 0);
+                assert_eq!(identifier.as_ref().unwrap(), "a.out");
+            }
+            _ => panic!("expected a code directory"),
+        }
+    }
+
+    #[test]
+    fn test_dual_hash_types() {
+        let mut macho = fake_macho(0x9000);
+        let settings = SigningSettings {
+            identifier: "com.example.legacy".to_string(),
+            hash_type: CS_HASHTYPE_SHA1 as u8,
+            alternate_hash_types: vec![CS_HASHTYPE_SHA256 as u8, CS_HASHTYPE_SHA384 as u8],
+            page_size: page_shift(CS_PAGE_SIZE_16K).unwrap(),
+            ..Default::default()
+        };
+        sign_macho(&settings, &mut macho).unwrap();
+        let cs = parse(&macho);
+        assert_hashes_match(&cs);
+
+        let cds: Vec<_> = cs
+            .blobs
+            .as_ref()
+            .unwrap()
+            .iter()
+            .filter_map(|blob| match blob {
+                Blob::CodeDirectory {
+                    index,
+                    code_directory,
+                    ..
+                } => Some((index.typ, code_directory.hashType as u32)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            cds,
+            vec![
+                (CSSLOT_CODEDIRECTORY, CS_HASHTYPE_SHA1),
+                (CSSLOT_ALTERNATE_CODEDIRECTORIES, CS_HASHTYPE_SHA256),
+                (CSSLOT_ALTERNATE_CODEDIRECTORIES + 1, CS_HASHTYPE_SHA384),
+            ]
+        );
+        let preserved = SigningSettings::from_code_signature(&cs).unwrap();
+        assert_eq!(
+            preserved.alternate_hash_types,
+            settings.alternate_hash_types
+        );
+    }
+}
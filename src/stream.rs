@@ -0,0 +1,138 @@
+#![allow(dead_code)]
+
+use std::io::{self, Write};
+
+use errors::{CDMachError::*, Result};
+
+use codedir::hash_digest;
+use signer::{SigningSettings, SIGNATURE_ALIGNMENT};
+
+/// Signs a Mach-O image while it is being written.
+///
+/// Everything written through the signer up to the signature offset is
+/// forwarded to the inner writer and hashed page by page, so the output never
+/// has to be read back. The caller lays out `__LINKEDIT` with
+/// `estimate_signature_size` bytes reserved for `LC_CODE_SIGNATURE`, writes the
+/// image up to `dataoff`, then calls `finish` to append the SuperBlob.
+pub struct StreamingSigner<W: Write> {
+    inner: W,
+    settings: SigningSettings,
+    hash_types: Vec<u8>,
+    page_size: usize,
+    /// bytes of the current, incomplete page
+    page: Vec<u8>,
+    /// hashes of the completed pages, one vector per hash type
+    page_hashes: Vec<Vec<Vec<u8>>>,
+    /// number of bytes written so far
+    written: usize,
+}
+
+impl<W: Write> StreamingSigner<W> {
+    pub fn new(inner: W, settings: SigningSettings) -> Result<StreamingSigner<W>> {
+        if settings.page_size == 0 {
+            return Err(UnsupportedPageSize(0).into());
+        }
+        let hash_types = settings.hash_types()?;
+        let page_size = 1 << settings.page_size;
+        Ok(StreamingSigner {
+            inner,
+            page_hashes: vec![vec![]; hash_types.len()],
+            hash_types,
+            page_size,
+            page: Vec::with_capacity(page_size),
+            settings,
+            written: 0,
+        })
+    }
+
+    /// Number of bytes to reserve for the signature (`LC_CODE_SIGNATURE`
+    /// datasize) of an image whose signature starts at `code_limit`
+    pub fn estimate_signature_size(settings: &SigningSettings, code_limit: usize) -> Result<usize> {
+        let size = settings.signature_size(code_limit)?;
+        Ok((size + SIGNATURE_ALIGNMENT - 1) & !(SIGNATURE_ALIGNMENT - 1))
+    }
+
+    /// Number of bytes written so far
+    pub fn position(&self) -> usize {
+        self.written
+    }
+
+    fn hash_page(&mut self) -> Result<()> {
+        for (hash_type, hashes) in self.hash_types.iter().zip(self.page_hashes.iter_mut()) {
+            hashes.push(hash_digest(*hash_type as u32, &self.page)?);
+        }
+        self.page.clear();
+        Ok(())
+    }
+
+    /// Write the signature (padded to `estimate_signature_size`) after the
+    /// code, returning the inner writer and the SuperBlob
+    pub fn finish(mut self) -> Result<(W, Vec<u8>)> {
+        if !self.page.is_empty() {
+            self.hash_page()?;
+        }
+        let signature = self
+            .settings
+            .build_signature_from_page_hashes(self.written, &self.page_hashes)?;
+        let reserved = Self::estimate_signature_size(&self.settings, self.written)?;
+        self.inner.write_all(&signature)?;
+        self.inner
+            .write_all(&vec![0u8; reserved - signature.len()])?;
+        self.inner.flush()?;
+        Ok((self.inner, signature))
+    }
+}
+
+impl<W: Write> Write for StreamingSigner<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        let mut rest = &buf[..n];
+        while !rest.is_empty() {
+            let take = (self.page_size - self.page.len()).min(rest.len());
+            self.page.extend_from_slice(&rest[..take]);
+            rest = &rest[take..];
+            if self.page.len() == self.page_size {
+                self.hash_page()
+                    .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+            }
+        }
+        self.written += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use consts::{CS_HASHTYPE_SHA1, CS_HASHTYPE_SHA256};
+    use signer::SigningSettings;
+    use std::io::Write;
+    use stream::StreamingSigner;
+
+    #[test]
+    fn test_streaming_matches_build_signature() {
+        let code: Vec<u8> = (0..0x3456u32).map(|i| (i * 7) as u8).collect();
+        let settings = SigningSettings {
+            identifier: "com.example.linked".to_string(),
+            hash_type: CS_HASHTYPE_SHA1 as u8,
+            alternate_hash_types: vec![CS_HASHTYPE_SHA256 as u8],
+            ..Default::default()
+        };
+
+        let mut signer = StreamingSigner::new(vec![], settings.clone()).unwrap();
+        for chunk in code.chunks(1000) {
+            signer.write_all(chunk).unwrap();
+        }
+        let (out, signature) = signer.finish().unwrap();
+
+        assert_eq!(signature, settings.build_signature(&code).unwrap());
+        assert_eq!(&out[..code.len()], &code[..]);
+        assert_eq!(
+            out.len() - code.len(),
+            StreamingSigner::<Vec<u8>>::estimate_signature_size(&settings, code.len()).unwrap()
+        );
+    }
+}
@@ -0,0 +1,207 @@
+#![allow(dead_code)]
+
+use flate2::read::GzDecoder;
+use tar::Archive;
+use xz2::read::XzDecoder;
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use errors::Result;
+
+use archive::{is_archive, verify_members};
+use codedir::CodeSignature;
+use rules::is_macho_magic;
+use verify::{VerificationReport, VerificationStatus};
+
+/// Magic of gzip streams
+pub const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+/// Magic of xz streams
+pub const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0];
+/// Bytes read from each entry to tell whether it is code
+const SNIFF_SIZE: usize = 8;
+
+/// Scan the tarball at `path`, plain, gzip or xz compressed
+pub fn scan_tarball(path: &Path) -> Result<Vec<VerificationReport>> {
+    scan_tar_stream(File::open(path)?)
+}
+
+/// Walk the entries of a (compressed) tar stream and verify each Mach-O
+/// file, and each Mach-O member of static libraries, without unpacking to
+/// disk; the compression is told by its magic. Reports are named after the
+/// entries' paths.
+pub fn scan_tar_stream<R: Read>(reader: R) -> Result<Vec<VerificationReport>> {
+    let mut reader = BufReader::new(reader);
+    let magic = reader.fill_buf()?.to_vec();
+    if magic.starts_with(GZIP_MAGIC) {
+        scan_entries(GzDecoder::new(reader))
+    } else if magic.starts_with(XZ_MAGIC) {
+        scan_entries(XzDecoder::new(reader))
+    } else {
+        scan_entries(reader)
+    }
+}
+
+fn scan_entries<R: Read>(reader: R) -> Result<Vec<VerificationReport>> {
+    let mut reports = vec![];
+    let mut archive = Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = PathBuf::from(entry.path()?.into_owned());
+        let mut data = vec![];
+        (&mut entry)
+            .take(SNIFF_SIZE as u64)
+            .read_to_end(&mut data)?;
+        if !is_macho_magic(&data) && !is_archive(&data) {
+            // the rest of the entry is skipped
+            continue;
+        }
+        entry.read_to_end(&mut data)?;
+        let verified = if is_archive(&data) {
+            verify_members(&path, &data)
+        } else {
+            verify_macho(&path, &data).map(|report| vec![report])
+        };
+        // a malformed entry fails its own verification, not the scan's
+        match verified {
+            Ok(verified) => reports.extend(verified),
+            Err(err) => {
+                let mut report = VerificationReport::new(&path);
+                report.problem(err.to_string());
+                reports.push(report);
+            }
+        }
+    }
+    Ok(reports)
+}
+
+fn verify_macho(path: &Path, data: &[u8]) -> Result<VerificationReport> {
+    let mut report = VerificationReport::new(path);
+    report.check_signatures(&CodeSignature::parse_image(None, data)?)?;
+    Ok(report)
+}
+
+/// One line per report: status, cdhash, identifier and path
+pub fn entry_table(reports: &[VerificationReport]) -> String {
+    let mut table = format!(
+        "{:<8} {:<40} {:<32} {}\n",
+        "STATUS", "CDHASH", "IDENTIFIER", "PATH"
+    );
+    for report in reports {
+        let status = match report.status {
+            VerificationStatus::Valid => "valid",
+            VerificationStatus::Unsigned => "unsigned",
+            VerificationStatus::Invalid(_) => "invalid",
+        };
+        table.push_str(&format!(
+            "{:<8} {:<40} {:<32} {}\n",
+            status,
+            report.cdhash.as_ref().map(|s| s.as_str()).unwrap_or("-"),
+            report
+                .identifier
+                .as_ref()
+                .map(|s| s.as_str())
+                .unwrap_or("-"),
+            report.path.display()
+        ));
+        if let VerificationStatus::Invalid(ref problems) = report.status {
+            for problem in problems {
+                table.push_str(&format!("{:<8} {}\n", "", problem));
+            }
+        }
+    }
+    table
+}
+
+#[cfg(test)]
+pub mod tests {
+    use archive::AR_MAGIC;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use signer::tests::fake_macho;
+    use signer::{sign_macho, SigningSettings};
+    use std::path::Path;
+    use tar::{Builder, Header};
+    use tarball::{entry_table, scan_tar_stream};
+    use verify::VerificationStatus;
+
+    #[test]
+    fn test_scan_tar_gz() {
+        let mut macho = fake_macho(0x2000);
+        let settings = SigningSettings {
+            identifier: "tool".to_string(),
+            ..Default::default()
+        };
+        sign_macho(&settings, &mut macho).unwrap();
+        let mut tampered = macho.clone();
+        tampered[0x1800] ^= 1;
+
+        let mut builder = Builder::new(GzEncoder::new(vec![], Compression::default()));
+        for &(path, data) in &[
+            ("pkg/bin/tool", &macho[..]),
+            ("pkg/README", &b"not code"[..]),
+            ("pkg/bin/tampered", &tampered[..]),
+        ] {
+            let mut header = Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o755);
+            header.set_cksum();
+            builder.append_data(&mut header, path, data).unwrap();
+        }
+        let tarball = builder.into_inner().unwrap().finish().unwrap();
+
+        let reports = scan_tar_stream(&tarball[..]).unwrap();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].status, VerificationStatus::Valid);
+        assert_eq!(reports[0].identifier, Some("tool".to_string()));
+        assert!(!reports[1].is_valid());
+        let table = entry_table(&reports);
+        assert_eq!(table.lines().count(), 4);
+        assert!(table.contains("pkg/bin/tampered"));
+    }
+
+    #[test]
+    fn test_scan_malformed_entries() {
+        let mut macho = fake_macho(0x2000);
+        sign_macho(&SigningSettings::default(), &mut macho).unwrap();
+        let mut bad_archive = AR_MAGIC.to_vec();
+        // a member header without its terminating magic
+        bad_archive.extend_from_slice(&[b' '; 60]);
+
+        let mut builder = Builder::new(vec![]);
+        for &(path, data) in &[
+            ("pkg/bin/truncated", &macho[..40]),
+            ("pkg/lib/libbad.a", &bad_archive[..]),
+            ("pkg/bin/tool", &macho[..]),
+        ] {
+            let mut header = Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o755);
+            header.set_cksum();
+            builder.append_data(&mut header, path, data).unwrap();
+        }
+        let tarball = builder.into_inner().unwrap();
+
+        let reports = scan_tar_stream(&tarball[..]).unwrap();
+        let paths: Vec<_> = reports.iter().map(|r| r.path.clone()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                Path::new("pkg/bin/truncated"),
+                Path::new("pkg/lib/libbad.a"),
+                Path::new("pkg/bin/tool"),
+            ]
+        );
+        for report in &reports[..2] {
+            match report.status {
+                VerificationStatus::Invalid(ref problems) => assert_eq!(problems.len(), 1),
+                _ => panic!("{:?}", report),
+            }
+        }
+        assert_eq!(reports[2].status, VerificationStatus::Valid);
+    }
+}
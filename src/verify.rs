@@ -0,0 +1,665 @@
+#![allow(dead_code)]
+
+use hex;
+use plist::Dictionary;
+
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use errors::Result;
+
+use bundle::Bundle;
+use cms::SignedData;
+use codedir::{
+    image_file_type, image_slices, Blob, CodeSignature, DetachedSignature, DETACHED_SIGNATURE_PATH,
+};
+use consts::{
+    CSMAGIC_EMBEDDED_DER_ENTITLEMENTS, CSMAGIC_EMBEDDED_ENTITLEMENTS, CSSLOT_DER_ENTITLEMENTS,
+    CSSLOT_ENTITLEMENTS, CSSLOT_INFOSLOT, CSSLOT_REQUIREMENTS, CSSLOT_RESOURCEDIR,
+};
+use der::OID_APPLE_DEVELOPER_ID_KEXT;
+use dmg::{verify_dmg_data, KolyTrailer};
+use dyld_cache::{verify_dyld_cache_data, DyldCacheHeader};
+use fileset::{is_fileset, verify_fileset};
+use mach_object::MH_KEXT_BUNDLE;
+use profile::{ProvisioningProfile, EMBEDDED_PROFILE_PATH};
+use resources::{CodeResources, ResourceDiff, ResourceSeal};
+use rules::is_macho;
+use sections::{embedded_info_plists, entitlements, EntitlementsSource};
+use signer::wrap_blob;
+use xar::{verify_xar_data, Xar};
+
+/// Directories of a bundle's contents that hold nested code: those of the
+/// default `nested` resource rule, then the iOS ones
+pub const NESTED_CODE_DIRS: [&str; 14] = [
+    "Frameworks",
+    "SharedFrameworks",
+    "PlugIns",
+    "Plug-ins",
+    "XPCServices",
+    "Helpers",
+    "MacOS",
+    "Library/Automator",
+    "Library/Extensions",
+    "Library/Spotlight",
+    "Library/LoginItems",
+    "Extensions",
+    "Watch",
+    "AppClips",
+];
+
+/// Verdict on a single piece of code
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerificationStatus {
+    Valid,
+    /// no embedded signature
+    Unsigned,
+    /// signed, with the problems found
+    Invalid(Vec<String>),
+}
+
+/// Verification result of a bundle or Mach-O file and of the code nested
+/// inside it
+#[derive(Debug, Clone)]
+pub struct VerificationReport {
+    pub path: PathBuf,
+    /// identifier of the primary CodeDirectory, or the signer of a xar archive
+    pub identifier: Option<String>,
+    /// `CFBundleIdentifier` of the bundle's Info.plist, or of the one
+    /// embedded in a command-line tool
+    pub bundle_identifier: Option<String>,
+    /// cdhash of the primary CodeDirectory, truncated to 20 bytes
+    pub cdhash: Option<String>,
+    /// flags of the primary CodeDirectory, e.g. `adhoc,linker-signed`
+    pub flags: Option<String>,
+    /// entitlements of the main executable and where they were found
+    pub entitlements: Option<(EntitlementsSource, Dictionary)>,
+    pub status: VerificationStatus,
+    pub children: Vec<VerificationReport>,
+}
+
+impl VerificationReport {
+    pub fn new(path: &Path) -> VerificationReport {
+        VerificationReport {
+            path: path.to_path_buf(),
+            identifier: None,
+            bundle_identifier: None,
+            cdhash: None,
+            flags: None,
+            entitlements: None,
+            status: VerificationStatus::Valid,
+            children: vec![],
+        }
+    }
+
+    pub fn problem(&mut self, problem: String) {
+        match self.status {
+            VerificationStatus::Invalid(ref mut problems) => problems.push(problem),
+            _ => self.status = VerificationStatus::Invalid(vec![problem]),
+        }
+    }
+
+    /// Whether this item and everything nested in it verified
+    pub fn is_valid(&self) -> bool {
+        self.status == VerificationStatus::Valid && self.children.iter().all(|c| c.is_valid())
+    }
+
+    /// Check every slice's signature: page hashes and the blobs sealed by
+    /// special slots
+    pub fn check_signatures(&mut self, signatures: &[CodeSignature]) -> Result<()> {
+        if signatures.is_empty() {
+            self.status = VerificationStatus::Unsigned;
+            return Ok(());
+        }
+        for cs in signatures {
+            for blob in cs.blobs.iter().flat_map(|blobs| blobs.iter()) {
+                let (slot, data) = match blob {
+                    Blob::CodeDirectory {
+                        cd_hashes: Ok(ref hashes),
+                        computed_cd_hashes: Ok(ref computed),
+                        ref hash_type,
+                        ..
+                    } => {
+                        let code: Vec<_> = hashes.iter().filter(|&&(i, _)| i >= 0).collect();
+                        if code.len() != computed.len()
+                            || code.iter().zip(computed.iter()).any(|(a, b)| *a != b)
+                        {
+                            self.problem(format!(
+                                "{} page hashes do not match the code",
+                                hash_type.as_ref().map(|t| t.as_str()).unwrap_or("unknown")
+                            ));
+                        }
+                        continue;
+                    }
+                    Blob::CodeDirectory { .. } => {
+                        self.problem("unreadable CodeDirectory".to_string());
+                        continue;
+                    }
+                    Blob::Requirements { ref data, .. } => (CSSLOT_REQUIREMENTS, data.clone()),
+                    Blob::Entitlements {
+                        ref entitlements, ..
+                    } => (
+                        CSSLOT_ENTITLEMENTS,
+                        wrap_blob(CSMAGIC_EMBEDDED_ENTITLEMENTS, entitlements),
+                    ),
+                    Blob::DerEntitlements {
+                        ref der_entitlements,
+                        ..
+                    } => (
+                        CSSLOT_DER_ENTITLEMENTS,
+                        wrap_blob(CSMAGIC_EMBEDDED_DER_ENTITLEMENTS, der_entitlements),
+                    ),
+                    _ => continue,
+                };
+                if cs.verify_special_slot(slot, &data)? == Some(false) {
+                    self.problem(format!("blob in special slot {} was modified", slot));
+                }
+            }
+            if let Some(cms) = cs.cms() {
+                let verified = match cs.code_directory_data() {
+                    Some(cd) => {
+                        SignedData::parse(cms).and_then(|signed| signed.verify_detached(cd))
+                    }
+                    None => Ok(false),
+                };
+                match verified {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        self.problem("CMS signature does not match the CodeDirectory".to_string())
+                    }
+                    Err(err) => self.problem(format!("CMS signature not verified: {}", err)),
+                }
+            }
+        }
+
+        let primary = &signatures[0];
+        match primary.code_directory() {
+            Some((cd, cd_hash)) => {
+                self.cdhash = Some(cd_hash.chars().take(40).collect());
+                self.flags = Some(cd.flags_str());
+            }
+            None => self.problem("no CodeDirectory".to_string()),
+        }
+        self.identifier = primary
+            .identity()
+            .map(|(identifier, _)| identifier.to_string());
+        Ok(())
+    }
+
+    /// Check what a bundle's signatures seal besides the code: Info.plist and
+    /// the resource envelope
+    pub fn check_bundle_seals(
+        &mut self,
+        signatures: &[CodeSignature],
+        info_plist: &[u8],
+        resources: Option<&CodeResources>,
+    ) -> Result<()> {
+        for cs in signatures {
+            if cs.verify_special_slot(CSSLOT_INFOSLOT, info_plist)? == Some(false) {
+                self.problem("Info.plist does not match the signature".to_string());
+            }
+            match resources {
+                Some(resources) => match resources.verify_envelope(cs)? {
+                    Some(true) => {}
+                    Some(false) => {
+                        self.problem("CodeResources does not match the signature".to_string())
+                    }
+                    None => self.problem("signature does not seal CodeResources".to_string()),
+                },
+                None => {
+                    if cs.verify_special_slot(CSSLOT_RESOURCEDIR, &[])?.is_some() {
+                        self.problem("sealed CodeResources is missing".to_string());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the Info.plist embedded in `__TEXT,__info_plist` of each slice
+    /// against the slice's own signature
+    pub fn check_embedded_info_plist(&mut self, data: &[u8]) -> Result<()> {
+        for info_plist in embedded_info_plists(data)? {
+            if self.bundle_identifier.is_none() {
+                self.bundle_identifier = info_plist.identifier().map(str::to_string);
+            }
+            for cs in CodeSignature::parse_image(None, info_plist.slice)? {
+                if cs.verify_special_slot(CSSLOT_INFOSLOT, info_plist.data)? == Some(false) {
+                    self.problem("embedded Info.plist does not match the signature".to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Record the differences between a resource envelope and the bundle
+    pub fn add_diff(&mut self, diff: &ResourceDiff) {
+        for path in &diff.added {
+            self.problem(format!("resource added: {}", path));
+        }
+        for path in &diff.removed {
+            self.problem(format!("resource removed: {}", path));
+        }
+        for path in &diff.modified {
+            self.problem(format!("resource modified: {}", path));
+        }
+    }
+
+    /// Cross-check the signatures with the bundle's provisioning profile
+    pub fn check_profile(&mut self, profile: &[u8], signatures: &[CodeSignature]) -> Result<()> {
+        let profile = ProvisioningProfile::parse(profile)?;
+        if profile.is_expired() {
+            self.problem("provisioning profile expired".to_string());
+        }
+        if !profile.signature_verifies {
+            self.problem("provisioning profile signature does not verify".to_string());
+        }
+        for cs in signatures {
+            let check = profile.check(cs)?;
+            for key in &check.ungranted_entitlements {
+                self.problem(format!("entitlement not granted by the profile: {}", key));
+            }
+            for cert in &check.unlisted_certificates {
+                self.problem(format!("certificate not listed in the profile: {}", cert));
+            }
+        }
+        Ok(())
+    }
+
+    /// Report the slices, by CPU type, a detached signature has no signature
+    /// for
+    pub fn check_uncovered_slices(&mut self, cpu_types: &[Option<u32>]) {
+        for cpu_type in cpu_types {
+            match *cpu_type {
+                Some(cpu_type) => self.problem(format!(
+                    "no detached signature for CPU type 0x{:x}",
+                    cpu_type
+                )),
+                None => self.problem("no detached signature for the code".to_string()),
+            }
+        }
+    }
+
+    /// Check the expectations on a kernel extension: an `MH_KEXT_BUNDLE`
+    /// executable in `Contents/MacOS`, declared `OSBundleLibraries` and a
+    /// leaf certificate with the Developer ID kext marker
+    pub fn check_kext(
+        &mut self,
+        bundle: &Bundle,
+        executable: &[u8],
+        signatures: &[CodeSignature],
+    ) -> Result<()> {
+        if bundle.executable.parent() != Some(&bundle.contents.join("MacOS")) {
+            self.problem("kext executable is not in Contents/MacOS".to_string());
+        }
+        if image_slices(executable)?
+            .iter()
+            .any(|slice| image_file_type(slice) != Some(MH_KEXT_BUNDLE))
+        {
+            self.problem("kext executable is not an MH_KEXT_BUNDLE".to_string());
+        }
+        if bundle.info.get("OSBundleLibraries").is_none() {
+            self.problem("kext Info.plist has no OSBundleLibraries".to_string());
+        }
+        for cs in signatures {
+            let leaf = match cs.cms() {
+                Some(cms) => SignedData::parse(cms)?.leaf_certificate().cloned(),
+                None => None,
+            };
+            match leaf {
+                Some(ref leaf) if leaf.has_extension(OID_APPLE_DEVELOPER_ID_KEXT) => {}
+                Some(ref leaf) => self.problem(format!(
+                    "{} is not a Developer ID kext certificate",
+                    leaf.common_name().unwrap_or_default()
+                )),
+                None => self.problem("kext is not signed with a certificate".to_string()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Compare the cdhash of this (nested) code with the seal the enclosing
+    /// bundle's CodeResources holds for it, if any
+    pub fn check_nested_seal(&mut self, seal: Option<&ResourceSeal>) {
+        if let Some(sealed) = seal.and_then(|seal| seal.cdhash.as_ref()) {
+            if self.cdhash != Some(hex::encode(sealed)) {
+                self.problem("cdhash does not match the enclosing bundle's seal".to_string());
+            }
+        }
+    }
+}
+
+/// Verify a bundle or a Mach-O file, and everything nested in it
+pub fn verify(path: &Path) -> Result<VerificationReport> {
+    if Bundle::is_bundle(path) {
+        verify_bundle(&Bundle::open(path)?)
+    } else {
+        verify_file(path)
+    }
+}
+
+/// Verify the signature of every slice of a Mach-O file, of each entry of a
+/// kernel collection, or of a disk image, xar archive or dyld shared cache
+pub fn verify_file(path: &Path) -> Result<VerificationReport> {
+    let mut data = vec![];
+    File::open(path)?.read_to_end(&mut data)?;
+    if KolyTrailer::find(&data).is_ok() {
+        return verify_dmg_data(path, &data);
+    }
+    if Xar::is_xar(&data) {
+        return verify_xar_data(path, &data, &[]);
+    }
+    if DyldCacheHeader::is_dyld_cache(&data) {
+        return verify_dyld_cache_data(path, &data);
+    }
+    if is_fileset(&data) {
+        return verify_fileset(path, &data);
+    }
+    let mut report = VerificationReport::new(path);
+    let signatures = CodeSignature::parse_image(None, &data)?;
+    report.check_signatures(&signatures)?;
+    report.entitlements = entitlements(&data, &signatures)?;
+    report.check_embedded_info_plist(&data)?;
+    Ok(report)
+}
+
+/// Verify each slice of a file against the matching architecture of a
+/// detached signature
+pub fn verify_detached(path: &Path, signature: &Path) -> Result<VerificationReport> {
+    let mut data = vec![];
+    File::open(path)?.read_to_end(&mut data)?;
+    let mut report = VerificationReport::new(path);
+    let detached = DetachedSignature::open(signature)?;
+    report.check_signatures(&detached.parse_image(None, &data)?)?;
+    report.check_uncovered_slices(&detached.uncovered_slices(&data)?);
+    Ok(report)
+}
+
+/// Verify a bundle: its executable, Info.plist and resources, then each piece
+/// of nested code against its own signature and the cdhash the bundle's
+/// CodeResources sealed for it.
+///
+/// An executable without an embedded signature is checked against the
+/// bundle's detached signature, if it has one.
+pub fn verify_bundle(bundle: &Bundle) -> Result<VerificationReport> {
+    let mut report = VerificationReport::new(&bundle.path);
+    let executable = bundle.read_executable()?;
+    let mut signatures = CodeSignature::parse_image(None, &executable)?;
+    let mut uncovered = vec![];
+    let detached = bundle.contents.join(DETACHED_SIGNATURE_PATH);
+    if signatures.is_empty() && detached.is_file() {
+        let detached = DetachedSignature::open(detached)?;
+        signatures = detached.parse_image(None, &executable)?;
+        uncovered = detached.uncovered_slices(&executable)?;
+    }
+    report.check_signatures(&signatures)?;
+    report.check_uncovered_slices(&uncovered);
+    report.entitlements = entitlements(&executable, &signatures)?;
+    report.bundle_identifier = bundle.identifier().map(str::to_string);
+    if report.status == VerificationStatus::Unsigned {
+        return Ok(report);
+    }
+
+    let resources = CodeResources::open(bundle).ok();
+    report.check_bundle_seals(&signatures, &bundle.info_plist, resources.as_ref())?;
+    if bundle.is_kext() {
+        report.check_kext(bundle, &executable, &signatures)?;
+    }
+    let profile = bundle.contents.join(EMBEDDED_PROFILE_PATH);
+    if profile.is_file() {
+        let mut data = vec![];
+        File::open(profile)?.read_to_end(&mut data)?;
+        report.check_profile(&data, &signatures)?;
+    }
+
+    let mut nested: BTreeSet<String> = find_nested_code(bundle)?.into_iter().collect();
+    if let Some(ref resources) = resources {
+        report.add_diff(&resources.diff(bundle)?);
+        nested.extend(resources.nested_code());
+    }
+
+    for path in nested {
+        let on_disk = bundle.contents.join(&path);
+        if fs::symlink_metadata(&on_disk).is_err() {
+            // reported as a removed resource
+            continue;
+        }
+        // code that cannot be read fails its own verification, not the bundle's
+        let mut child = match verify(&on_disk) {
+            Ok(child) => child,
+            Err(err) => {
+                let mut child = VerificationReport::new(&on_disk);
+                child.problem(err.to_string());
+                child
+            }
+        };
+        child.check_nested_seal(
+            resources
+                .as_ref()
+                .and_then(|resources| resources.seals().get(&path)),
+        );
+        report.children.push(child);
+    }
+    Ok(report)
+}
+
+/// Nested bundles and Mach-O files in the standard locations of `bundle`,
+/// relative to its contents; the main executable is left out
+pub fn find_nested_code(bundle: &Bundle) -> Result<Vec<String>> {
+    let executable = bundle.relative_executable();
+    let mut nested = vec![];
+    for dir in NESTED_CODE_DIRS.iter() {
+        let path = bundle.contents.join(dir);
+        if !path.is_dir() {
+            continue;
+        }
+        let mut entries = vec![];
+        for entry in fs::read_dir(&path)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let relative = format!("{}/{}", dir, name);
+            let file_type = entry.file_type()?;
+            let is_code = if file_type.is_dir() {
+                name.contains('.') && Bundle::is_bundle(entry.path())
+            } else {
+                file_type.is_file() && is_macho(&entry.path())?
+            };
+            if is_code && executable.as_ref() != Some(&relative) {
+                entries.push(relative);
+            }
+        }
+        entries.sort();
+        nested.extend(entries);
+    }
+    Ok(nested)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use bundle::tests::info_plist;
+    use bundle::Bundle;
+    use cms::tests::identity;
+    use cms::{SignedData, SigningIdentity};
+    use byteorder::{ByteOrder, LittleEndian};
+    use codedir::tests::detached_signature;
+    use codedir::CodeSignature;
+    use deep::{sign_deep, DeepSigningConfig};
+    use mach_object::MH_KEXT_BUNDLE;
+    use signer::tests::fake_macho;
+    use signer::{sign_macho, SigningSettings};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+    use verify::{verify, verify_bundle, verify_detached, VerificationReport, VerificationStatus};
+
+    /// A deep signed macOS app in `dir` with two resources and a helper tool
+    pub fn signed_app(dir: &Path) -> PathBuf {
+        let app = dir.join("Foo.app");
+        fs::create_dir_all(app.join("Contents/MacOS")).unwrap();
+        fs::create_dir_all(app.join("Contents/Resources")).unwrap();
+        fs::write(
+            app.join("Contents/Info.plist"),
+            info_plist("Foo", "com.example.foo"),
+        )
+        .unwrap();
+        fs::write(app.join("Contents/MacOS/Foo"), fake_macho(0x2000)).unwrap();
+        fs::write(app.join("Contents/MacOS/helper"), fake_macho(0x1000)).unwrap();
+        fs::write(app.join("Contents/Resources/a.txt"), b"a").unwrap();
+        fs::write(app.join("Contents/Resources/b.txt"), b"b").unwrap();
+        sign_deep(&app, &DeepSigningConfig::default()).unwrap();
+        app
+    }
+
+    fn problems(report: &VerificationReport) -> Vec<String> {
+        match report.status {
+            VerificationStatus::Invalid(ref problems) => problems.clone(),
+            _ => vec![],
+        }
+    }
+
+    #[test]
+    fn test_verify_bundle_resources() {
+        let dir = TempDir::new().unwrap();
+        let app = signed_app(dir.path());
+        let report = verify_bundle(&Bundle::open(&app).unwrap()).unwrap();
+        assert!(report.is_valid(), "{:?}", report);
+        assert_eq!(report.children.len(), 1);
+
+        fs::write(app.join("Contents/Resources/a.txt"), b"changed").unwrap();
+        fs::remove_file(app.join("Contents/Resources/b.txt")).unwrap();
+        fs::write(app.join("Contents/Resources/c.txt"), b"c").unwrap();
+        let report = verify_bundle(&Bundle::open(&app).unwrap()).unwrap();
+        assert_eq!(
+            problems(&report),
+            vec![
+                "resource added: Resources/c.txt",
+                "resource removed: Resources/b.txt",
+                "resource modified: Resources/a.txt",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_verify_bundle_nested() {
+        let dir = TempDir::new().unwrap();
+        let app = signed_app(dir.path());
+        let helper = app.join("Contents/MacOS/helper");
+
+        // validly signed on its own, but not what the app sealed
+        let mut macho = fake_macho(0x1000);
+        let settings = SigningSettings {
+            identifier: "other".to_string(),
+            ..Default::default()
+        };
+        sign_macho(&settings, &mut macho).unwrap();
+        fs::write(&helper, &macho).unwrap();
+        let report = verify_bundle(&Bundle::open(&app).unwrap()).unwrap();
+        assert_eq!(report.status, VerificationStatus::Valid);
+        assert_eq!(
+            problems(&report.children[0]),
+            vec!["cdhash does not match the enclosing bundle's seal"]
+        );
+
+        // unreadable nested code is reported, not fatal
+        fs::write(&helper, &macho[..64]).unwrap();
+        let report = verify_bundle(&Bundle::open(&app).unwrap()).unwrap();
+        assert_eq!(report.children[0].path, helper);
+        assert!(report.children[0].cdhash.is_none());
+        assert!(!report.children[0].is_valid());
+    }
+
+    #[test]
+    fn test_verify_cms() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("tool");
+        let identity = identity();
+        let mut macho = fake_macho(0x1000);
+        let settings = SigningSettings {
+            identifier: "com.example.tool".to_string(),
+            identity: Some(Arc::new(identity.clone())),
+            ..Default::default()
+        };
+        sign_macho(&settings, &mut macho).unwrap();
+        fs::write(&path, &macho).unwrap();
+        assert_eq!(verify(&path).unwrap().status, VerificationStatus::Valid);
+
+        // the signer's signature of other attributes
+        let signature = |cms: &[u8]| SignedData::parse(cms).unwrap().signers[0].signature.clone();
+        let signatures = CodeSignature::parse_image(None, &macho).unwrap();
+        let genuine = signature(signatures[0].cms().unwrap());
+        let forged = signature(&identity.sign_detached(b"CodeDirectory", &[]).unwrap());
+        let at = macho
+            .windows(genuine.len())
+            .position(|w| w == &genuine[..])
+            .unwrap();
+        macho[at..at + genuine.len()].copy_from_slice(&forged);
+        fs::write(&path, &macho).unwrap();
+        assert_eq!(
+            problems(&verify(&path).unwrap()),
+            vec!["CMS signature does not match the CodeDirectory"]
+        );
+    }
+
+    #[test]
+    fn test_verify_kext() {
+        let dir = TempDir::new().unwrap();
+        let kext = dir.path().join("Foo.kext");
+        fs::create_dir_all(kext.join("Contents/MacOS")).unwrap();
+        fs::write(
+            kext.join("Contents/Info.plist"),
+            info_plist("Foo", "com.example.foo")
+                .replace("</dict>", "<key>OSBundleLibraries</key><dict/></dict>"),
+        )
+        .unwrap();
+        let sign = |identity: Option<SigningIdentity>| {
+            let mut macho = fake_macho(0x1000);
+            macho[12] = MH_KEXT_BUNDLE as u8;
+            fs::write(kext.join("Contents/MacOS/Foo"), &macho).unwrap();
+            let config = DeepSigningConfig {
+                settings: SigningSettings {
+                    identity: identity.map(Arc::new),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            sign_deep(&kext, &config).unwrap();
+            problems(&verify_bundle(&Bundle::open(&kext).unwrap()).unwrap())
+        };
+
+        let kext_identity = SigningIdentity::from_der(
+            include_bytes!("../testdata/developer_id.p8"),
+            include_bytes!("../testdata/developer_id_kext.der"),
+            &[],
+        )
+        .unwrap();
+        assert_eq!(sign(Some(kext_identity)), Vec::<String>::new());
+        assert_eq!(
+            sign(Some(identity())),
+            vec!["Developer ID Application: Test (ABCDE12345) is not a Developer ID kext certificate"]
+        );
+        assert_eq!(sign(None), vec!["kext is not signed with a certificate"]);
+    }
+
+    #[test]
+    fn test_verify_detached_uncovered_slice() {
+        let dir = TempDir::new().unwrap();
+        let (mut macho, detached) = detached_signature(0x0100000c);
+        fs::write(dir.path().join("tool.sig"), detached).unwrap();
+        fs::write(dir.path().join("tool"), &macho).unwrap();
+        let report =
+            verify_detached(&dir.path().join("tool"), &dir.path().join("tool.sig")).unwrap();
+        assert!(report.is_valid(), "{:?}", report);
+
+        LittleEndian::write_u32(&mut macho[4..], 0x01000007);
+        fs::write(dir.path().join("tool"), &macho).unwrap();
+        let report =
+            verify_detached(&dir.path().join("tool"), &dir.path().join("tool.sig")).unwrap();
+        assert_eq!(
+            problems(&report),
+            vec!["no detached signature for CPU type 0x1000007".to_string()]
+        );
+    }
+}
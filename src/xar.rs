@@ -0,0 +1,427 @@
+#![allow(dead_code)]
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use byteorder::{ByteOrder, NetworkEndian};
+use flate2::read::ZlibDecoder;
+use regex::Regex;
+use ring::digest::{digest, SHA1, SHA256, SHA512};
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use errors::{CDMachError::*, Result};
+
+use cms::Certificate;
+use ring::signature::{
+    RSAParameters, RSA_PKCS1_2048_8192_SHA1, RSA_PKCS1_2048_8192_SHA256, RSA_PKCS1_2048_8192_SHA512,
+};
+use verify::{VerificationReport, VerificationStatus};
+
+/// Magic of a xar archive, e.g. a flat installer package
+pub const XAR_MAGIC: &[u8] = b"xar!";
+/// Size of the fixed part of the header
+const XAR_HEADER_SIZE: usize = 28;
+/// Header checksum algorithm naming its hash after the fixed header
+pub const XAR_CKSUM_OTHER: u32 = 3;
+
+/// A region of the heap, which follows the compressed TOC
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeapRange {
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// The TOC signature: an RSA signature of the TOC checksum and the signer's
+/// certificate chain, leaf first
+#[derive(Debug, Clone)]
+pub struct XarSignature {
+    /// `RSA` for installer packages
+    pub style: String,
+    pub range: HeapRange,
+    pub certificates: Vec<Certificate>,
+}
+
+/// A xar archive: its header and table of contents
+#[derive(Debug, Clone)]
+pub struct Xar {
+    pub version: u16,
+    /// checksum algorithm of the header; the TOC names it too
+    pub checksum_algorithm: u32,
+    /// zlib compressed TOC, as checksummed and signed
+    pub toc_compressed: Vec<u8>,
+    /// TOC XML
+    pub toc: String,
+    /// offset of the heap in the archive
+    pub heap_offset: u64,
+    /// TOC checksum style, e.g. `sha1`, and where the checksum is
+    pub checksum: Option<(String, HeapRange)>,
+    pub signature: Option<XarSignature>,
+}
+
+impl Xar {
+    pub fn is_xar(data: &[u8]) -> bool {
+        data.starts_with(XAR_MAGIC)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Xar> {
+        if !Xar::is_xar(data) || data.len() < XAR_HEADER_SIZE {
+            return Err(BadXar("no xar magic".to_string()).into());
+        }
+        let header_size = NetworkEndian::read_u16(&data[4..]) as usize;
+        let toc_length = NetworkEndian::read_u64(&data[8..]);
+        let toc_end = match (header_size as u64).checked_add(toc_length) {
+            Some(toc_end) if header_size >= XAR_HEADER_SIZE && toc_end <= data.len() as u64 => {
+                toc_end
+            }
+            _ => return Err(BadXar("TOC past the end".to_string()).into()),
+        };
+        let toc_compressed = data[header_size..toc_end as usize].to_vec();
+        let mut toc = String::new();
+        ZlibDecoder::new(&toc_compressed[..]).read_to_string(&mut toc)?;
+
+        let checksum = match element(&toc, "checksum")? {
+            Some((style, body)) => Some((style, heap_range(&body)?)),
+            None => None,
+        };
+        let signature = match element(&toc, "signature")? {
+            Some((style, body)) => {
+                let mut certificates = vec![];
+                let re = Regex::new(r"(?s)<X509Certificate>(.*?)</X509Certificate>")?;
+                for cert in re.captures_iter(&body) {
+                    let encoded: String = cert[1].split_whitespace().collect();
+                    let der = match STANDARD.decode(&encoded) {
+                        Ok(der) => der,
+                        Err(_) => return Err(BadXar("bad certificate".to_string()).into()),
+                    };
+                    certificates.push(Certificate::parse(&der)?);
+                }
+                Some(XarSignature {
+                    style,
+                    range: heap_range(&body)?,
+                    certificates,
+                })
+            }
+            None => None,
+        };
+
+        Ok(Xar {
+            version: NetworkEndian::read_u16(&data[6..]),
+            checksum_algorithm: NetworkEndian::read_u32(&data[24..]),
+            toc_compressed,
+            toc,
+            heap_offset: toc_end,
+            checksum,
+            signature,
+        })
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Xar> {
+        let mut data = vec![];
+        File::open(path)?.read_to_end(&mut data)?;
+        Xar::parse(&data)
+    }
+
+    /// The bytes of a heap range of the archive `data`
+    pub fn heap<'a>(&self, data: &'a [u8], range: &HeapRange) -> Result<&'a [u8]> {
+        let start = self.heap_offset.checked_add(range.offset);
+        match start.and_then(|start| start.checked_add(range.size).map(|end| (start, end))) {
+            Some((start, end)) if end <= data.len() as u64 => {
+                Ok(&data[start as usize..end as usize])
+            }
+            _ => Err(BadXar(format!("heap range {:?} past the end", range)).into()),
+        }
+    }
+}
+
+/// Style attribute and body of the TOC's first `<name style="...">` element
+fn element(toc: &str, name: &str) -> Result<Option<(String, String)>> {
+    let re = Regex::new(&format!(
+        r#"(?s)<{0}\s+style="([^"]*)"\s*>(.*?)</{0}>"#,
+        name
+    ))?;
+    Ok(re
+        .captures(toc)
+        .map(|c| (c[1].to_string(), c[2].to_string())))
+}
+
+/// The `<offset>` and `<size>` of an element body
+fn heap_range(body: &str) -> Result<HeapRange> {
+    let value = |name: &str| -> Result<u64> {
+        let re = Regex::new(&format!(r"<{0}>\s*(\d+)\s*</{0}>", name))?;
+        match re.captures(body).map(|c| c[1].parse()) {
+            Some(Ok(value)) => Ok(value),
+            _ => Err(BadXar(format!("no {} in the TOC", name)).into()),
+        }
+    };
+    Ok(HeapRange {
+        offset: value("offset")?,
+        size: value("size")?,
+    })
+}
+
+/// The RSA verification algorithm matching a TOC checksum style: the
+/// checksum is signed as a digest of that type
+fn signature_algorithm(style: &str) -> Option<&'static RSAParameters> {
+    match style {
+        "sha1" => Some(&RSA_PKCS1_2048_8192_SHA1),
+        "sha256" => Some(&RSA_PKCS1_2048_8192_SHA256),
+        "sha512" => Some(&RSA_PKCS1_2048_8192_SHA512),
+        _ => None,
+    }
+}
+
+/// Verify the signature of the xar archive (e.g. installer package) at
+/// `path`. No root is trusted, so a signed archive is reported as not
+/// anchored.
+pub fn verify_xar(path: &Path) -> Result<VerificationReport> {
+    let mut data = vec![];
+    File::open(path)?.read_to_end(&mut data)?;
+    verify_xar_data(path, &data, &[])
+}
+
+/// Verify a xar archive: the TOC checksum, its RSA signature by the leaf
+/// certificate and the certificate chain, which must end in one of
+/// `anchors` or in a certificate issued by one of them.
+pub fn verify_xar_data(
+    path: &Path,
+    data: &[u8],
+    anchors: &[Certificate],
+) -> Result<VerificationReport> {
+    let xar = Xar::parse(data)?;
+    let mut report = VerificationReport::new(path);
+
+    let style = match xar.checksum {
+        Some((ref style, ref range)) => {
+            let checksum = xar.heap(data, range)?;
+            let computed = match style.as_str() {
+                "sha1" => Some(digest(&SHA1, &xar.toc_compressed)),
+                "sha256" => Some(digest(&SHA256, &xar.toc_compressed)),
+                "sha512" => Some(digest(&SHA512, &xar.toc_compressed)),
+                _ => None,
+            };
+            match computed {
+                Some(ref computed) if computed.as_ref() == checksum => {}
+                Some(_) => report.problem("TOC checksum does not match".to_string()),
+                None => report.problem(format!("unsupported TOC checksum {}", style)),
+            }
+            style.clone()
+        }
+        None => {
+            report.problem("TOC has no checksum".to_string());
+            return Ok(report);
+        }
+    };
+
+    let signature = match xar.signature {
+        Some(ref signature) => signature,
+        None => {
+            if report.status == VerificationStatus::Valid {
+                report.status = VerificationStatus::Unsigned;
+            }
+            return Ok(report);
+        }
+    };
+    let leaf = match signature.certificates.first() {
+        Some(leaf) => leaf,
+        None => {
+            report.problem("signature has no certificate".to_string());
+            return Ok(report);
+        }
+    };
+    report.identifier = leaf.common_name();
+    match (signature.style.as_str(), signature_algorithm(&style)) {
+        ("RSA", Some(algorithm)) => {
+            let value = xar.heap(data, &signature.range)?;
+            match leaf.verify(algorithm, &xar.toc_compressed, value) {
+                Ok(true) => {}
+                Ok(false) => report.problem("TOC signature does not verify".to_string()),
+                Err(_) => report.problem(format!(
+                    "{}: unsupported key algorithm",
+                    leaf.common_name().unwrap_or_default()
+                )),
+            }
+        }
+        (style, _) => report.problem(format!("unsupported {} signature", style)),
+    }
+    for pair in signature.certificates.windows(2) {
+        let subject = pair[0].common_name().unwrap_or_default();
+        let issuer = pair[1].common_name().unwrap_or_default();
+        match pair[0].is_issued_by(&pair[1]) {
+            Ok(true) => {}
+            Ok(false) => report.problem(format!("{} is not issued by {}", subject, issuer)),
+            Err(_) => report.problem(format!(
+                "{} is not checked against {}: unsupported key algorithm",
+                subject, issuer
+            )),
+        }
+    }
+    let last = signature.certificates.last().unwrap_or(leaf);
+    let anchored = anchors
+        .iter()
+        .any(|anchor| anchor == last || last.is_issued_by(anchor).unwrap_or(false));
+    if !anchored {
+        report.problem(format!(
+            "{} is not anchored to a trusted root",
+            last.common_name().unwrap_or_default()
+        ));
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use byteorder::{ByteOrder, NetworkEndian};
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use cms::tests::identity;
+    use ring::digest::{digest, Algorithm, SHA1, SHA256};
+    use ring::rand::SystemRandom;
+    use ring::signature::{RSAKeyPair, RSASigningState, RSA_PKCS1_SHA256};
+    use std::io::Write;
+    use std::path::Path;
+    use std::sync::Arc;
+    use untrusted::Input;
+    use verify::VerificationStatus;
+    use xar::{verify_xar_data, Xar, XAR_CKSUM_OTHER, XAR_MAGIC};
+
+    #[test]
+    fn test_toc_checksum() {
+        let toc = r#"<?xml version="1.0" encoding="UTF-8"?>
+<xar><toc><checksum style="sha1"><offset>0</offset><size>20</size></checksum>
+<file id="1"><name>Distribution</name></file></toc></xar>"#;
+        let mut data = xar(toc, &[]);
+
+        let parsed = Xar::parse(&data).unwrap();
+        assert_eq!(parsed.toc, toc);
+        assert!(parsed.signature.is_none());
+        let report = verify_xar_data(Path::new("a.pkg"), &data, &[]).unwrap();
+        assert_eq!(report.status, VerificationStatus::Unsigned);
+
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        let report = verify_xar_data(Path::new("a.pkg"), &data, &[]).unwrap();
+        assert!(!report.is_valid());
+    }
+
+    /// A xar archive with a SHA-1 TOC checksum, followed in the heap by
+    /// `heap`
+    fn xar(toc: &str, heap: &[u8]) -> Vec<u8> {
+        xar_with_checksum(toc, heap, &SHA1)
+    }
+
+    /// A xar archive with a TOC checksum of type `algorithm`, followed in
+    /// the heap by `heap`
+    fn xar_with_checksum(toc: &str, heap: &[u8], algorithm: &'static Algorithm) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+        encoder.write_all(toc.as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut data = XAR_MAGIC.to_vec();
+        data.extend_from_slice(&[0, 28, 0, 1]);
+        let mut lengths = [0; 20];
+        NetworkEndian::write_u64(&mut lengths, compressed.len() as u64);
+        NetworkEndian::write_u64(&mut lengths[8..], toc.len() as u64);
+        let checksum_algorithm = if algorithm == &SHA1 {
+            1
+        } else {
+            XAR_CKSUM_OTHER
+        };
+        NetworkEndian::write_u32(&mut lengths[16..], checksum_algorithm);
+        data.extend_from_slice(&lengths);
+        data.extend_from_slice(&compressed);
+        data.extend_from_slice(digest(algorithm, &compressed).as_ref());
+        data.extend_from_slice(heap);
+        data
+    }
+
+    #[test]
+    fn test_unsupported_key_algorithm() {
+        let cert = STANDARD.encode(&include_bytes!("../testdata/ecdsa.der")[..]);
+        let toc = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<xar><toc><checksum style="sha1"><offset>0</offset><size>20</size></checksum>
+<signature style="RSA"><offset>20</offset><size>64</size><KeyInfo><X509Data>
+<X509Certificate>{0}</X509Certificate><X509Certificate>{0}</X509Certificate>
+</X509Data></KeyInfo></signature></toc></xar>"#,
+            cert
+        );
+        let report = verify_xar_data(Path::new("a.pkg"), &xar(&toc, &[0; 64]), &[]).unwrap();
+        let name = "Developer ID Installer: Test (ABCDE12345)";
+        assert_eq!(
+            report.status,
+            VerificationStatus::Invalid(vec![
+                format!("{}: unsupported key algorithm", name),
+                format!(
+                    "{} is not checked against {}: unsupported key algorithm",
+                    name, name
+                ),
+                format!("{} is not anchored to a trusted root", name),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_rsa_signature() {
+        let identity = identity();
+        let toc = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<xar><toc><checksum style="sha256"><offset>0</offset><size>32</size></checksum>
+<signature style="RSA"><offset>32</offset><size>256</size><KeyInfo><X509Data>
+<X509Certificate>{}</X509Certificate></X509Data></KeyInfo></signature></toc></xar>"#,
+            STANDARD.encode(&identity.certificate.der)
+        );
+        let mut data = xar_with_checksum(&toc, &[0; 256], &SHA256);
+        let xar = Xar::parse(&data).unwrap();
+        let key =
+            RSAKeyPair::from_pkcs8(Input::from(include_bytes!("../testdata/developer_id.p8")))
+                .unwrap();
+        let mut state = RSASigningState::new(Arc::new(key)).unwrap();
+        let signature_offset = xar.heap_offset as usize + 32;
+        state
+            .sign(
+                &RSA_PKCS1_SHA256,
+                &SystemRandom::new(),
+                &xar.toc_compressed,
+                &mut data[signature_offset..],
+            )
+            .unwrap();
+
+        let anchors = [identity.certificate.clone()];
+        let report = verify_xar_data(Path::new("a.pkg"), &data, &anchors).unwrap();
+        assert_eq!(report.status, VerificationStatus::Valid);
+        assert_eq!(report.identifier, identity.certificate.common_name());
+
+        let name = identity.certificate.common_name().unwrap();
+        let report = verify_xar_data(Path::new("a.pkg"), &data, &[]).unwrap();
+        assert_eq!(
+            report.status,
+            VerificationStatus::Invalid(vec![format!(
+                "{} is not anchored to a trusted root",
+                name
+            )])
+        );
+
+        data[signature_offset] ^= 1;
+        let report = verify_xar_data(Path::new("a.pkg"), &data, &anchors).unwrap();
+        assert_eq!(
+            report.status,
+            VerificationStatus::Invalid(vec!["TOC signature does not verify".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_heap_range_overflow() {
+        let toc = r#"<?xml version="1.0" encoding="UTF-8"?>
+<xar><toc><checksum style="sha1"><offset>18446744073709551615</offset><size>20</size></checksum>
+</toc></xar>"#;
+        assert!(verify_xar_data(Path::new("a.pkg"), &xar(toc, &[]), &[]).is_err());
+
+        let mut data = xar(toc, &[]);
+        NetworkEndian::write_u64(&mut data[8..], u64::MAX);
+        assert!(Xar::parse(&data).is_err());
+    }
+}